@@ -0,0 +1,229 @@
+use std::path::{Path, PathBuf};
+
+use crate::auth::{AuthUser, Role};
+use crate::config::{AccessRule, Config};
+use crate::error::AppError;
+use crate::security::normalize_relative;
+
+/// What an operation needs to do with a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// List or download.
+    Read,
+    /// Upload or create a directory.
+    Create,
+    /// Move, rename or delete.
+    Modify,
+}
+
+impl Access {
+    /// Role required when no `write` rule covers the path.
+    fn role(self) -> Role {
+        match self {
+            Access::Read => Role::ReadOnly,
+            Access::Create => Role::Uploader,
+            Access::Modify => Role::Admin,
+        }
+    }
+}
+
+/// Validates a user-provided path with one of the `security::validate_*`
+/// functions and checks the user may access it.
+///
+/// The rules are checked on the path as typed *before* touching the
+/// filesystem, so a hidden path answers 404 whether or not it exists, and
+/// again on the canonical result to catch symlinks into hidden trees.
+pub fn validate<F>(
+    config: &Config,
+    user: &AuthUser,
+    user_path: &str,
+    access: Access,
+    validate: F,
+) -> Result<PathBuf, AppError>
+where
+    F: FnOnce(&Path, &str) -> Result<PathBuf, AppError>,
+{
+    authorize(
+        &config.access_rules,
+        user,
+        &normalize_relative(user_path),
+        access,
+    )
+    .map_err(|e| hide(e, user_path))?;
+
+    let path = validate(&config.root_directory, user_path)?;
+
+    let rel = relative_to_root(config, &path)?;
+    authorize(&config.access_rules, user, &rel, access).map_err(|e| hide(e, user_path))?;
+
+    Ok(path)
+}
+
+/// Returns the root-relative form of a validated (canonical) path.
+pub fn relative_to_root(config: &Config, path: &Path) -> Result<PathBuf, AppError> {
+    let root = config.root_directory.canonicalize()?;
+    path.strip_prefix(&root)
+        .map(Path::to_path_buf)
+        .map_err(|_| AppError::Forbidden)
+}
+
+/// Returns true if `rel` (root-relative) is visible to the user.
+/// Used to filter directory listings.
+pub fn can_read(rules: &[AccessRule], user: &AuthUser, rel: &Path) -> bool {
+    user.role == Role::Admin
+        || matching(rules, rel).all(|rule| match &rule.read {
+            Some(allowed) => is_listed(allowed, user),
+            None => true,
+        })
+}
+
+/// Checks the rules for a root-relative path that need not exist, such as
+/// the original location of a trashed item.
+pub fn check(config: &Config, user: &AuthUser, rel: &Path, access: Access) -> Result<(), AppError> {
+    authorize(&config.access_rules, user, rel, access).map_err(|e| hide(e, &rel.to_string_lossy()))
+}
+
+fn authorize(
+    rules: &[AccessRule],
+    user: &AuthUser,
+    rel: &Path,
+    access: Access,
+) -> Result<(), AppError> {
+    if !can_read(rules, user, rel) {
+        return Err(AppError::NotFound(String::new()));
+    }
+
+    if access == Access::Read || user.role == Role::Admin {
+        return Ok(());
+    }
+
+    // The most specific `write` rule decides; otherwise the role does
+    let write_rule = matching(rules, rel)
+        .filter_map(|rule| rule.write.as_ref().map(|w| (rule, w)))
+        .max_by_key(|(rule, _)| rule_path(rule).components().count());
+
+    match write_rule {
+        Some((_, allowed)) if is_listed(allowed, user) => Ok(()),
+        Some(_) => {
+            tracing::warn!("User '{}' denied write to {}", user.username, rel.display());
+            Err(AppError::Forbidden)
+        }
+        None => user.require(access.role()),
+    }
+}
+
+fn matching<'a>(rules: &'a [AccessRule], rel: &'a Path) -> impl Iterator<Item = &'a AccessRule> {
+    rules
+        .iter()
+        .filter(move |rule| rel.starts_with(rule_path(rule)))
+}
+
+fn rule_path(rule: &AccessRule) -> PathBuf {
+    normalize_relative(&rule.path)
+}
+
+fn is_listed(allowed: &[String], user: &AuthUser) -> bool {
+    allowed
+        .iter()
+        .any(|p| *p == user.username || p == user.role.as_str())
+}
+
+/// Hidden paths report the same 404 as a missing file.
+fn hide(err: AppError, user_path: &str) -> AppError {
+    match err {
+        AppError::NotFound(_) => AppError::NotFound(format!("not found: {}", user_path)),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(path: &str, read: Option<&[&str]>, write: Option<&[&str]>) -> AccessRule {
+        let list = |l: &[&str]| l.iter().map(|s| s.to_string()).collect();
+        AccessRule {
+            path: path.to_string(),
+            read: read.map(list),
+            write: write.map(list),
+        }
+    }
+
+    fn user(name: &str, role: Role) -> AuthUser {
+        AuthUser {
+            username: name.to_string(),
+            role,
+        }
+    }
+
+    fn rules() -> Vec<AccessRule> {
+        vec![
+            rule("music", None, Some(&["dj"])),
+            rule("photos/private", Some(&["mom", "uploader"]), None),
+        ]
+    }
+
+    #[test]
+    fn test_hidden_paths() {
+        let rules = rules();
+        let guest = user("guest", Role::ReadOnly);
+        let mom = user("mom", Role::ReadOnly);
+
+        assert!(can_read(&rules, &guest, Path::new("photos")));
+        assert!(!can_read(&rules, &guest, Path::new("photos/private")));
+        assert!(!can_read(&rules, &guest, Path::new("photos/private/a.jpg")));
+        assert!(can_read(&rules, &mom, Path::new("photos/private/a.jpg")));
+
+        // Role names work as principals, admins see everything
+        assert!(can_read(
+            &rules,
+            &user("dad", Role::Uploader),
+            Path::new("photos/private")
+        ));
+        assert!(can_read(
+            &rules,
+            &user("root", Role::Admin),
+            Path::new("photos/private")
+        ));
+
+        // Prefix match is per component, not per character
+        assert!(can_read(&rules, &guest, Path::new("photos/private2")));
+
+        assert!(matches!(
+            authorize(
+                &rules,
+                &guest,
+                Path::new("photos/private/a.jpg"),
+                Access::Read
+            ),
+            Err(AppError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_write_rules() {
+        let rules = rules();
+        let dj = user("dj", Role::ReadOnly);
+        let uploader = user("alice", Role::Uploader);
+
+        // The rule grants dj full write access to music/ only
+        assert!(authorize(&rules, &dj, Path::new("music/new.mp3"), Access::Modify).is_ok());
+        assert!(authorize(&rules, &dj, Path::new("photos/a.jpg"), Access::Create).is_err());
+
+        // ...and keeps other non-admins out of it
+        assert!(matches!(
+            authorize(
+                &rules,
+                &uploader,
+                Path::new("music/new.mp3"),
+                Access::Create
+            ),
+            Err(AppError::Forbidden)
+        ));
+        assert!(authorize(&rules, &uploader, Path::new("photos/a.jpg"), Access::Create).is_ok());
+        assert!(authorize(&rules, &uploader, Path::new("photos/a.jpg"), Access::Modify).is_err());
+
+        let admin = user("root", Role::Admin);
+        assert!(authorize(&rules, &admin, Path::new("music/new.mp3"), Access::Modify).is_ok());
+    }
+}
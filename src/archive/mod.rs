@@ -0,0 +1,271 @@
+pub mod tar;
+pub mod zip;
+
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_compression::tokio::write::GzipEncoder;
+use async_compression::Level;
+use axum::body::Body;
+use futures::{stream, StreamExt};
+use serde::Deserialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+use crate::access;
+use crate::auth::AuthUser;
+use crate::config::AccessRule;
+use crate::jobs;
+use crate::security::has_internal_component;
+
+use self::tar::TarWriter;
+use self::zip::ZipWriter;
+
+/// Size of the in-memory pipe between the archive writer and the response.
+/// This is the only buffering, whatever the size of the archive.
+const PIPE_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    #[default]
+    Zip,
+    Tar,
+    #[serde(rename = "tar.gz", alias = "tgz")]
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::TarGz => "application/gzip",
+        }
+    }
+}
+
+/// A file or directory selected for the archive, stored under `name`.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub name: String,
+    /// Canonical path, already validated against the root jail.
+    pub path: PathBuf,
+}
+
+#[derive(Debug)]
+pub enum EntryKind {
+    Directory,
+    File { size: u64 },
+}
+
+/// One item to write, in pre-order (directories before their contents).
+#[derive(Debug)]
+pub struct Entry {
+    /// Path inside the archive, `/`-separated.
+    pub name: String,
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    pub modified: SystemTime,
+}
+
+/// Walks the selected sources lazily, one directory listing at a time.
+///
+/// Children are filtered through the access rules and internal server files
+/// are left out. Symlinks are only followed to files that resolve inside the
+/// root; symlinked directories are skipped to avoid cycles. Also drives
+/// `/api/copy`.
+pub struct Walker {
+    root: PathBuf,
+    rules: Vec<AccessRule>,
+    user: AuthUser,
+    pending: Vec<Entry>,
+}
+
+impl Walker {
+    pub async fn new(
+        root: PathBuf,
+        rules: Vec<AccessRule>,
+        user: AuthUser,
+        sources: Vec<Source>,
+    ) -> io::Result<Self> {
+        let mut walker = Self {
+            root,
+            rules,
+            user,
+            pending: Vec::new(),
+        };
+
+        for source in sources.into_iter().rev() {
+            let meta = tokio::fs::metadata(&source.path).await?;
+            walker.pending.push(Entry {
+                name: source.name,
+                kind: kind_of(&meta),
+                modified: meta.modified().unwrap_or(UNIX_EPOCH),
+                path: source.path,
+            });
+        }
+
+        Ok(walker)
+    }
+
+    pub async fn next(&mut self) -> io::Result<Option<Entry>> {
+        let Some(entry) = self.pending.pop() else {
+            return Ok(None);
+        };
+
+        if matches!(entry.kind, EntryKind::Directory) {
+            let mut children = self.read_children(&entry).await?;
+            // Pushed in reverse so they pop in name order
+            children.sort_by(|a, b| b.name.cmp(&a.name));
+            self.pending.extend(children);
+        }
+
+        Ok(Some(entry))
+    }
+
+    async fn read_children(&self, parent: &Entry) -> io::Result<Vec<Entry>> {
+        let mut children = Vec::new();
+        let mut dir = tokio::fs::read_dir(&parent.path).await?;
+
+        while let Some(child) = dir.next_entry().await? {
+            let path = child.path();
+            let file_type = child.file_type().await?;
+
+            let (path, meta) = if file_type.is_symlink() {
+                let Ok(target) = tokio::fs::canonicalize(&path).await else {
+                    continue;
+                };
+                let meta = tokio::fs::metadata(&target).await?;
+                if !target.starts_with(&self.root) || meta.is_dir() {
+                    continue;
+                }
+                (target, meta)
+            } else {
+                let meta = child.metadata().await?;
+                (path, meta)
+            };
+
+            let Ok(rel) = path.strip_prefix(&self.root) else {
+                continue;
+            };
+            if has_internal_component(rel) || !access::can_read(&self.rules, &self.user, rel) {
+                continue;
+            }
+
+            children.push(Entry {
+                name: format!("{}/{}", parent.name, child.file_name().to_string_lossy()),
+                kind: kind_of(&meta),
+                modified: meta.modified().unwrap_or(UNIX_EPOCH),
+                path,
+            });
+        }
+
+        Ok(children)
+    }
+}
+
+fn kind_of(meta: &std::fs::Metadata) -> EntryKind {
+    if meta.is_dir() {
+        EntryKind::Directory
+    } else {
+        EntryKind::File { size: meta.len() }
+    }
+}
+
+/// Streams an archive of everything the walker yields.
+///
+/// The archive is written by a background task into a small pipe that the
+/// response body drains, so memory use is bounded by `PIPE_SIZE`. A failure
+/// half-way aborts the response instead of ending it cleanly, so clients
+/// never mistake a truncated archive for a complete one.
+pub fn stream(format: ArchiveFormat, walker: Walker) -> Body {
+    let (reader, writer) = tokio::io::duplex(PIPE_SIZE);
+
+    let task = tokio::spawn(async move {
+        let result = write(format, writer, walker).await;
+        if let Err(ref e) = result {
+            tracing::warn!("Archive stream aborted: {}", e);
+        }
+        result
+    });
+
+    let outcome = stream::once(async move {
+        match task.await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(Err(e)),
+            Err(e) => Some(Err(io::Error::other(e))),
+        }
+    })
+    .filter_map(|item| async move { item });
+
+    Body::from_stream(ReaderStream::new(reader).chain(outcome))
+}
+
+/// Writes a complete archive of everything the walker yields to `out`.
+/// Also used by archive jobs, which write to a file.
+pub async fn write<W: AsyncWrite + Unpin>(
+    format: ArchiveFormat,
+    out: W,
+    walker: Walker,
+) -> io::Result<()> {
+    match format {
+        ArchiveFormat::Zip => write_zip(out, walker).await,
+        ArchiveFormat::Tar => write_tar(out, walker).await,
+        // Most media is already compressed, so favour speed over ratio
+        ArchiveFormat::TarGz => {
+            write_tar(GzipEncoder::with_quality(out, Level::Fastest), walker).await
+        }
+    }
+}
+
+async fn write_zip<W: AsyncWrite + Unpin>(out: W, mut walker: Walker) -> io::Result<()> {
+    let mut zip = ZipWriter::new(out);
+
+    while let Some(entry) = walker.next().await? {
+        jobs::check_cancelled()?;
+        match entry.kind {
+            EntryKind::Directory => zip.add_directory(&entry.name, entry.modified).await?,
+            EntryKind::File { size } => {
+                let file = tokio::fs::File::open(&entry.path).await?;
+                zip.add_file(&entry.name, entry.modified, size, file)
+                    .await?;
+                jobs::report_bytes(size);
+            }
+        }
+        jobs::report_item();
+    }
+
+    zip.finish().await?;
+    Ok(())
+}
+
+async fn write_tar<W: AsyncWrite + Unpin>(out: W, mut walker: Walker) -> io::Result<()> {
+    let mut tar = TarWriter::new(out);
+
+    while let Some(entry) = walker.next().await? {
+        jobs::check_cancelled()?;
+        match entry.kind {
+            EntryKind::Directory => tar.add_directory(&entry.name, entry.modified).await?,
+            EntryKind::File { size } => {
+                let file = tokio::fs::File::open(&entry.path).await?;
+                tar.add_file(&entry.name, entry.modified, size, file)
+                    .await?;
+                jobs::report_bytes(size);
+            }
+        }
+        jobs::report_item();
+    }
+
+    // Shutdown also writes the gzip trailer when compressing
+    tar.finish().await?.shutdown().await
+}
@@ -0,0 +1,270 @@
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const BLOCK: usize = 512;
+
+/// Largest size the 11-digit octal field can hold (8 GiB - 1).
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+
+/// Streaming tar writer (POSIX ustar with pax extended headers).
+///
+/// Paths longer than ustar allows or containing non-ASCII characters, and
+/// files of 8 GiB or more, get a pax header so nothing is truncated.
+pub struct TarWriter<W> {
+    out: W,
+}
+
+impl<W: AsyncWrite + Unpin> TarWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    pub async fn add_directory(&mut self, name: &str, mtime: SystemTime) -> io::Result<()> {
+        let name = if name.ends_with('/') {
+            name.to_string()
+        } else {
+            format!("{}/", name)
+        };
+        self.write_header(&name, b'5', 0o755, 0, mtime).await
+    }
+
+    /// Adds a file of exactly `size` bytes read from `reader`.
+    ///
+    /// The size goes in the header before the data, so a file that shrinks
+    /// while being archived is an error; extra bytes from a file that grew
+    /// are left out.
+    pub async fn add_file<R: AsyncRead + Unpin>(
+        &mut self,
+        name: &str,
+        mtime: SystemTime,
+        size: u64,
+        reader: R,
+    ) -> io::Result<()> {
+        self.write_header(name, b'0', 0o644, size, mtime).await?;
+
+        let copied = tokio::io::copy(&mut reader.take(size), &mut self.out).await?;
+        if copied != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} shrank while archiving", name),
+            ));
+        }
+
+        self.pad(size).await
+    }
+
+    /// Writes the end-of-archive marker and returns the underlying writer.
+    pub async fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(&[0u8; BLOCK * 2]).await?;
+        self.out.flush().await?;
+        Ok(self.out)
+    }
+
+    async fn write_header(
+        &mut self,
+        name: &str,
+        typeflag: u8,
+        mode: u32,
+        size: u64,
+        mtime: SystemTime,
+    ) -> io::Result<()> {
+        let mtime = mtime
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let split = split_ustar_name(name);
+
+        let mut pax = Vec::new();
+        if split.is_none() {
+            pax_record(&mut pax, "path", name);
+        }
+        if size > MAX_OCTAL_SIZE {
+            pax_record(&mut pax, "size", &size.to_string());
+        }
+
+        if !pax.is_empty() {
+            let pax_name = format!("PaxHeaders/{}", ascii_tail(name, 80));
+            let header = ustar_header(("", &pax_name), b'x', 0o644, pax.len() as u64, mtime);
+            self.out.write_all(&header).await?;
+            self.out.write_all(&pax).await?;
+            self.pad(pax.len() as u64).await?;
+        }
+
+        let fallback = ascii_tail(name, 99);
+        let (prefix, short) = split.unwrap_or(("", &fallback));
+        let header = ustar_header(
+            (prefix, short),
+            typeflag,
+            mode,
+            size.min(MAX_OCTAL_SIZE),
+            mtime,
+        );
+        self.out.write_all(&header).await
+    }
+
+    async fn pad(&mut self, len: u64) -> io::Result<()> {
+        let rem = (len % BLOCK as u64) as usize;
+        if rem != 0 {
+            self.out.write_all(&[0u8; BLOCK][..BLOCK - rem]).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits a name into ustar `prefix` (155) and `name` (100) fields at a `/`.
+/// Returns `None` if it doesn't fit or isn't plain ASCII.
+fn split_ustar_name(name: &str) -> Option<(&str, &str)> {
+    if !name.is_ascii() {
+        return None;
+    }
+    if name.len() <= 100 {
+        return Some(("", name));
+    }
+
+    name.match_indices('/')
+        .map(|(i, _)| (&name[..i], &name[i + 1..]))
+        .find(|(prefix, rest)| prefix.len() <= 155 && rest.len() <= 100 && !rest.is_empty())
+}
+
+/// ASCII-only tail of a name for header fields that are overridden by pax.
+fn ascii_tail(name: &str, max: usize) -> String {
+    let ascii: String = name
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    ascii[ascii.len().saturating_sub(max)..].to_string()
+}
+
+/// Appends a pax record: `"<len> <key>=<value>\n"`, where len counts itself.
+fn pax_record(buf: &mut Vec<u8>, key: &str, value: &str) {
+    let body = format!(" {}={}\n", key, value);
+    let mut len = body.len() + 1;
+    while len.to_string().len() + body.len() != len {
+        len = len.to_string().len() + body.len();
+    }
+    buf.extend_from_slice(format!("{}{}", len, body).as_bytes());
+}
+
+fn ustar_header(
+    (prefix, name): (&str, &str),
+    typeflag: u8,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+) -> [u8; BLOCK] {
+    let mut h = [0u8; BLOCK];
+    h[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut h[100..108], mode as u64);
+    octal(&mut h[108..116], 0); // uid
+    octal(&mut h[116..124], 0); // gid
+    octal(&mut h[124..136], size);
+    octal(&mut h[136..148], mtime.min(MAX_OCTAL_SIZE));
+    h[156] = typeflag;
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    h[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // Checksum is computed with its own field set to spaces
+    h[148..156].fill(b' ');
+    let sum: u32 = h.iter().map(|&b| b as u32).sum();
+    h[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+
+    h
+}
+
+/// Zero-padded octal number filling all but the last (NUL) byte of `field`.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let s = format!("{:0width$o}", value, width = digits);
+    field[..digits].copy_from_slice(&s.as_bytes()[s.len() - digits..]);
+    field[digits] = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn checksum_ok(h: &[u8]) -> bool {
+        let stored = u32::from_str_radix(std::str::from_utf8(&h[148..154]).unwrap(), 8).unwrap();
+        let sum: u32 = h
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    b' ' as u32
+                } else {
+                    b as u32
+                }
+            })
+            .sum();
+        stored == sum
+    }
+
+    #[tokio::test]
+    async fn test_tar_layout() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut tar = TarWriter::new(Vec::new());
+        tar.add_directory("album", mtime).await.unwrap();
+        tar.add_file("album/song.mp3", mtime, 5, &b"hello"[..])
+            .await
+            .unwrap();
+        let buf = tar.finish().await.unwrap();
+
+        // dir header, file header, one data block, two end blocks
+        assert_eq!(buf.len(), BLOCK * 5);
+        assert_eq!(&buf[..6], b"album/");
+        assert_eq!(buf[156], b'5');
+        assert!(checksum_ok(&buf[..BLOCK]));
+
+        let file = &buf[BLOCK..BLOCK * 2];
+        assert_eq!(&file[..14], b"album/song.mp3");
+        assert_eq!(&file[124..136], b"00000000005\0");
+        assert_eq!(
+            &file[136..148],
+            format!("{:011o}\0", 1_700_000_000).as_bytes()
+        );
+        assert!(checksum_ok(file));
+        assert_eq!(&buf[BLOCK * 2..BLOCK * 2 + 5], b"hello");
+        assert!(buf[BLOCK * 3..].iter().all(|&b| b == 0));
+    }
+
+    #[tokio::test]
+    async fn test_pax_for_long_and_unicode_names() {
+        let name = format!("{}/Canción.mp3", "a".repeat(200));
+        let mut tar = TarWriter::new(Vec::new());
+        tar.add_file(&name, UNIX_EPOCH, 0, &b""[..]).await.unwrap();
+        let buf = tar.finish().await.unwrap();
+
+        assert_eq!(buf[156], b'x');
+        let record = format!("path={}\n", name);
+        let data = std::str::from_utf8(&buf[BLOCK..BLOCK * 2]).unwrap();
+        assert!(data.trim_end_matches('\0').ends_with(&record));
+        assert_eq!(buf[BLOCK * 2 + 156], b'0');
+    }
+
+    #[test]
+    fn test_pax_record_length() {
+        let mut buf = Vec::new();
+        pax_record(&mut buf, "path", "x");
+        assert_eq!(buf, b"9 path=x\n");
+
+        // Length crossing a digit boundary counts its own extra digit
+        let mut buf = Vec::new();
+        pax_record(&mut buf, "path", &"y".repeat(91));
+        assert_eq!(buf.len(), 101);
+        assert!(buf.starts_with(b"101 path="));
+    }
+
+    #[test]
+    fn test_split_ustar_name() {
+        assert_eq!(split_ustar_name("a/b.mp3"), Some(("", "a/b.mp3")));
+        let long = format!("{}/{}", "d".repeat(120), "f".repeat(50));
+        assert_eq!(split_ustar_name(&long), Some((&long[..120], &long[121..])));
+        assert_eq!(split_ustar_name(&"x".repeat(101)), None);
+        assert_eq!(split_ustar_name("ñ.mp3"), None);
+    }
+}
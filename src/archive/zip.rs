@@ -0,0 +1,397 @@
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const LOCAL_HEADER_SIG: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIG: u32 = 0x08074b50;
+const CENTRAL_HEADER_SIG: u32 = 0x02014b50;
+const ZIP64_EOCD_SIG: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIG: u32 = 0x07064b50;
+const EOCD_SIG: u32 = 0x06054b50;
+
+/// Bit 3: sizes and CRC follow the data. Bit 11: names are UTF-8.
+const FLAGS: u16 = 0x0808;
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// Made by: Unix, spec 4.5
+const VERSION_MADE_BY: u16 = (3 << 8) | VERSION_ZIP64;
+
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+const TIMESTAMP_EXTRA_ID: u16 = 0x5455;
+
+const U32_MAX: u64 = 0xFFFF_FFFF;
+const U16_MAX: u64 = 0xFFFF;
+
+/// Streaming ZIP writer (store method, no compression).
+///
+/// Nothing is buffered except the central directory: each entry is written
+/// as soon as it is added, with its CRC and sizes in a trailing data
+/// descriptor. Entries of 4 GiB or more, offsets past 4 GiB and more than
+/// 65535 entries switch to ZIP64 records.
+pub struct ZipWriter<W> {
+    out: W,
+    offset: u64,
+    entries: Vec<CentralEntry>,
+}
+
+struct CentralEntry {
+    name: String,
+    mtime: SystemTime,
+    crc: u32,
+    size: u64,
+    offset: u64,
+    zip64: bool,
+    external_attrs: u32,
+}
+
+impl<W: AsyncWrite + Unpin> ZipWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            offset: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Adds a directory entry. A trailing `/` is appended if missing.
+    pub async fn add_directory(&mut self, name: &str, mtime: SystemTime) -> io::Result<()> {
+        let name = if name.ends_with('/') {
+            name.to_string()
+        } else {
+            format!("{}/", name)
+        };
+
+        let offset = self.offset;
+        self.write_local_header(&name, mtime, false).await?;
+        self.write_data_descriptor(0, 0, false).await?;
+
+        self.entries.push(CentralEntry {
+            name,
+            mtime,
+            crc: 0,
+            size: 0,
+            offset,
+            zip64: false,
+            external_attrs: (0o40755 << 16) | 0x10,
+        });
+        Ok(())
+    }
+
+    /// Adds a file, copying `reader` to the output.
+    /// `size_hint` (usually from metadata) decides whether ZIP64 is needed.
+    pub async fn add_file<R: AsyncRead + Unpin>(
+        &mut self,
+        name: &str,
+        mtime: SystemTime,
+        size_hint: u64,
+        mut reader: R,
+    ) -> io::Result<()> {
+        let zip64 = size_hint >= U32_MAX;
+        let offset = self.offset;
+        self.write_local_header(name, mtime, zip64).await?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut size: u64 = 0;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            self.write(&buf[..n]).await?;
+            size += n as u64;
+        }
+
+        if !zip64 && size >= U32_MAX {
+            return Err(io::Error::other(format!(
+                "{} grew past 4 GiB while archiving",
+                name
+            )));
+        }
+
+        let crc = hasher.finalize();
+        self.write_data_descriptor(crc, size, zip64).await?;
+
+        self.entries.push(CentralEntry {
+            name: name.to_string(),
+            mtime,
+            crc,
+            size,
+            offset,
+            zip64,
+            external_attrs: 0o100644 << 16,
+        });
+        Ok(())
+    }
+
+    /// Writes the central directory and returns the underlying writer.
+    pub async fn finish(mut self) -> io::Result<W> {
+        let cd_offset = self.offset;
+        let entries = std::mem::take(&mut self.entries);
+
+        for entry in &entries {
+            self.write_central_header(entry).await?;
+        }
+
+        let cd_size = self.offset - cd_offset;
+        let count = entries.len() as u64;
+
+        let needs_zip64 = count >= U16_MAX || cd_offset >= U32_MAX || cd_size >= U32_MAX;
+        if needs_zip64 {
+            let eocd64_offset = self.offset;
+
+            let mut rec = Vec::with_capacity(56 + 20);
+            put_u32(&mut rec, ZIP64_EOCD_SIG);
+            put_u64(&mut rec, 44); // size of remaining record
+            put_u16(&mut rec, VERSION_MADE_BY);
+            put_u16(&mut rec, VERSION_ZIP64);
+            put_u32(&mut rec, 0); // this disk
+            put_u32(&mut rec, 0); // disk with central directory
+            put_u64(&mut rec, count);
+            put_u64(&mut rec, count);
+            put_u64(&mut rec, cd_size);
+            put_u64(&mut rec, cd_offset);
+
+            put_u32(&mut rec, ZIP64_LOCATOR_SIG);
+            put_u32(&mut rec, 0);
+            put_u64(&mut rec, eocd64_offset);
+            put_u32(&mut rec, 1); // total disks
+            self.write(&rec).await?;
+        }
+
+        let mut rec = Vec::with_capacity(22);
+        put_u32(&mut rec, EOCD_SIG);
+        put_u16(&mut rec, 0);
+        put_u16(&mut rec, 0);
+        put_u16(&mut rec, count.min(U16_MAX) as u16);
+        put_u16(&mut rec, count.min(U16_MAX) as u16);
+        put_u32(&mut rec, cd_size.min(U32_MAX) as u32);
+        put_u32(&mut rec, cd_offset.min(U32_MAX) as u32);
+        put_u16(&mut rec, 0); // comment length
+        self.write(&rec).await?;
+
+        self.out.flush().await?;
+        Ok(self.out)
+    }
+
+    async fn write_local_header(
+        &mut self,
+        name: &str,
+        mtime: SystemTime,
+        zip64: bool,
+    ) -> io::Result<()> {
+        let (time, date) = dos_datetime(mtime);
+
+        let mut extra = Vec::new();
+        if zip64 {
+            // Sizes live in the data descriptor; the field signals its width
+            put_u16(&mut extra, ZIP64_EXTRA_ID);
+            put_u16(&mut extra, 16);
+            put_u64(&mut extra, 0);
+            put_u64(&mut extra, 0);
+        }
+        put_timestamp_extra(&mut extra, mtime);
+
+        let version = if zip64 {
+            VERSION_ZIP64
+        } else {
+            VERSION_DEFAULT
+        };
+
+        let mut rec = Vec::with_capacity(30 + name.len() + extra.len());
+        put_u32(&mut rec, LOCAL_HEADER_SIG);
+        put_u16(&mut rec, version);
+        put_u16(&mut rec, FLAGS);
+        put_u16(&mut rec, 0); // stored
+        put_u16(&mut rec, time);
+        put_u16(&mut rec, date);
+        put_u32(&mut rec, 0); // crc (in descriptor)
+        let size_field = if zip64 { U32_MAX as u32 } else { 0 };
+        put_u32(&mut rec, size_field);
+        put_u32(&mut rec, size_field);
+        put_u16(&mut rec, name.len() as u16);
+        put_u16(&mut rec, extra.len() as u16);
+        rec.extend_from_slice(name.as_bytes());
+        rec.extend_from_slice(&extra);
+
+        self.write(&rec).await
+    }
+
+    async fn write_data_descriptor(&mut self, crc: u32, size: u64, zip64: bool) -> io::Result<()> {
+        let mut rec = Vec::with_capacity(24);
+        put_u32(&mut rec, DATA_DESCRIPTOR_SIG);
+        put_u32(&mut rec, crc);
+        if zip64 {
+            put_u64(&mut rec, size);
+            put_u64(&mut rec, size);
+        } else {
+            put_u32(&mut rec, size as u32);
+            put_u32(&mut rec, size as u32);
+        }
+        self.write(&rec).await
+    }
+
+    async fn write_central_header(&mut self, entry: &CentralEntry) -> io::Result<()> {
+        let (time, date) = dos_datetime(entry.mtime);
+
+        let large_size = entry.zip64 || entry.size >= U32_MAX;
+        let large_offset = entry.offset >= U32_MAX;
+
+        let mut extra = Vec::new();
+        if large_size || large_offset {
+            let mut fields = Vec::new();
+            if large_size {
+                put_u64(&mut fields, entry.size); // uncompressed
+                put_u64(&mut fields, entry.size); // compressed
+            }
+            if large_offset {
+                put_u64(&mut fields, entry.offset);
+            }
+            put_u16(&mut extra, ZIP64_EXTRA_ID);
+            put_u16(&mut extra, fields.len() as u16);
+            extra.extend_from_slice(&fields);
+        }
+        put_timestamp_extra(&mut extra, entry.mtime);
+
+        let version = if large_size || large_offset {
+            VERSION_ZIP64
+        } else {
+            VERSION_DEFAULT
+        };
+        let size_field = if large_size {
+            U32_MAX as u32
+        } else {
+            entry.size as u32
+        };
+
+        let mut rec = Vec::with_capacity(46 + entry.name.len() + extra.len());
+        put_u32(&mut rec, CENTRAL_HEADER_SIG);
+        put_u16(&mut rec, VERSION_MADE_BY);
+        put_u16(&mut rec, version);
+        put_u16(&mut rec, FLAGS);
+        put_u16(&mut rec, 0); // stored
+        put_u16(&mut rec, time);
+        put_u16(&mut rec, date);
+        put_u32(&mut rec, entry.crc);
+        put_u32(&mut rec, size_field);
+        put_u32(&mut rec, size_field);
+        put_u16(&mut rec, entry.name.len() as u16);
+        put_u16(&mut rec, extra.len() as u16);
+        put_u16(&mut rec, 0); // comment length
+        put_u16(&mut rec, 0); // disk number
+        put_u16(&mut rec, 0); // internal attributes
+        put_u32(&mut rec, entry.external_attrs);
+        put_u32(&mut rec, entry.offset.min(U32_MAX) as u32);
+        rec.extend_from_slice(entry.name.as_bytes());
+        rec.extend_from_slice(&extra);
+
+        self.write(&rec).await
+    }
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data).await?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// Extended timestamp (0x5455) with the exact Unix mtime; DOS times only
+/// have two-second resolution and no time zone.
+fn put_timestamp_extra(buf: &mut Vec<u8>, mtime: SystemTime) {
+    let secs = mtime
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+        .min(u32::MAX as u64) as u32;
+    put_u16(buf, TIMESTAMP_EXTRA_ID);
+    put_u16(buf, 5);
+    buf.push(1); // mtime present
+    put_u32(buf, secs);
+}
+
+/// MS-DOS time and date fields (UTC). DOS dates start in 1980.
+fn dos_datetime(mtime: SystemTime) -> (u16, u16) {
+    let dt: DateTime<Utc> = mtime.into();
+    if dt.year() < 1980 {
+        return (0, (1 << 5) | 1); // 1980-01-01 00:00
+    }
+    let time = ((dt.hour() as u16) << 11) | ((dt.minute() as u16) << 5) | (dt.second() as u16 / 2);
+    let date =
+        (((dt.year() - 1980).min(127) as u16) << 9) | ((dt.month() as u16) << 5) | dt.day() as u16;
+    (time, date)
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn u16_at(buf: &[u8], pos: usize) -> u16 {
+        u16::from_le_bytes([buf[pos], buf[pos + 1]])
+    }
+
+    fn u32_at(buf: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_zip_layout() {
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut zip = ZipWriter::new(Vec::new());
+        zip.add_directory("album", mtime).await.unwrap();
+        zip.add_file("album/song.mp3", mtime, 5, &b"hello"[..])
+            .await
+            .unwrap();
+        let buf = zip.finish().await.unwrap();
+
+        // End of central directory: 2 entries, central directory right after the data
+        let eocd = buf.len() - 22;
+        assert_eq!(u32_at(&buf, eocd), EOCD_SIG);
+        assert_eq!(u16_at(&buf, eocd + 10), 2);
+        let cd_size = u32_at(&buf, eocd + 12) as usize;
+        let cd_offset = u32_at(&buf, eocd + 16) as usize;
+        assert_eq!(cd_offset + cd_size, eocd);
+
+        // Second central entry points at the file's local header and has its CRC
+        let first_len =
+            46 + u16_at(&buf, cd_offset + 28) as usize + u16_at(&buf, cd_offset + 30) as usize;
+        let second = cd_offset + first_len;
+        assert_eq!(u32_at(&buf, second), CENTRAL_HEADER_SIG);
+        assert_eq!(u32_at(&buf, second + 16), crc32fast::hash(b"hello"));
+        assert_eq!(u32_at(&buf, second + 24), 5);
+        let name_len = u16_at(&buf, second + 28) as usize;
+        assert_eq!(&buf[second + 46..second + 46 + name_len], b"album/song.mp3");
+
+        let local = u32_at(&buf, second + 42) as usize;
+        assert_eq!(u32_at(&buf, local), LOCAL_HEADER_SIG);
+        let data =
+            local + 30 + u16_at(&buf, local + 26) as usize + u16_at(&buf, local + 28) as usize;
+        assert_eq!(&buf[data..data + 5], b"hello");
+        assert_eq!(u32_at(&buf, data + 5), DATA_DESCRIPTOR_SIG);
+    }
+
+    #[test]
+    fn test_dos_datetime() {
+        // 2023-11-14 22:13:20 UTC
+        let (time, date) = dos_datetime(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        assert_eq!(time, (22 << 11) | (13 << 5) | 10);
+        assert_eq!(date, (43 << 9) | (11 << 5) | 14);
+
+        assert_eq!(dos_datetime(UNIX_EPOCH), (0, 33));
+    }
+}
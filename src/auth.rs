@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use rand::RngCore;
+use serde::Deserialize;
+
+use crate::config::AuthConfig;
+use crate::dav;
+use crate::error::AppError;
+use crate::util;
+use crate::AppState;
+
+pub const SESSION_COOKIE: &str = "monkeyarch_session";
+
+/// How long a successfully verified Basic Auth header is trusted before the
+/// password hash is checked again. Argon2 is slow on a Pi Zero, and browsers
+/// resend the header with every request.
+const BASIC_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+const BASIC_CACHE_MAX: usize = 64;
+
+/// What a user may do. Each role includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// List directories and download files.
+    #[serde(alias = "readonly", alias = "read_only")]
+    ReadOnly,
+    /// Also upload files and create directories.
+    Uploader,
+    /// Also move, rename and delete.
+    Admin,
+}
+
+impl Role {
+    /// Name as written in config.toml.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::ReadOnly => "read-only",
+            Role::Uploader => "uploader",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// The user a request was authenticated as.
+/// Inserted into request extensions by [`require_auth`].
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub username: String,
+    pub role: Role,
+}
+
+impl AuthUser {
+    /// With auth disabled everyone gets full access, as before auth existed.
+    fn anonymous() -> Self {
+        Self {
+            username: "anonymous".to_string(),
+            role: Role::Admin,
+        }
+    }
+
+    /// Fails with `Forbidden` unless the user has at least `role`.
+    pub fn require(&self, role: Role) -> Result<(), AppError> {
+        if self.role >= role {
+            Ok(())
+        } else {
+            tracing::warn!(
+                "User '{}' ({:?}) denied operation requiring {:?}",
+                self.username,
+                self.role,
+                role
+            );
+            Err(AppError::Forbidden)
+        }
+    }
+}
+
+struct Entry {
+    username: String,
+    expires: Instant,
+}
+
+/// In-memory login sessions and verified Basic Auth credentials.
+/// Everything is lost on restart, which simply forces a new login.
+#[derive(Default)]
+pub struct SessionStore {
+    sessions: Mutex<HashMap<String, Entry>>,
+    basic: Mutex<HashMap<String, Entry>>,
+}
+
+impl SessionStore {
+    /// Creates a session and returns its token.
+    pub fn create(&self, username: &str, ttl: Duration) -> String {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = util::to_hex(&bytes);
+
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        sessions.retain(|_, e| e.expires > now);
+        sessions.insert(
+            token.clone(),
+            Entry {
+                username: username.to_string(),
+                expires: now + ttl,
+            },
+        );
+        token
+    }
+
+    /// Returns the username for a live session token.
+    pub fn lookup(&self, token: &str) -> Option<String> {
+        lookup_entry(&self.sessions, token)
+    }
+
+    pub fn remove(&self, token: &str) {
+        self.sessions.lock().unwrap().remove(token);
+    }
+
+    fn lookup_basic(&self, header: &str) -> Option<String> {
+        lookup_entry(&self.basic, header)
+    }
+
+    fn remember_basic(&self, header: &str, username: &str) {
+        let mut basic = self.basic.lock().unwrap();
+        if basic.len() >= BASIC_CACHE_MAX {
+            basic.clear();
+        }
+        basic.insert(
+            header.to_string(),
+            Entry {
+                username: username.to_string(),
+                expires: Instant::now() + BASIC_CACHE_TTL,
+            },
+        );
+    }
+}
+
+fn lookup_entry(map: &Mutex<HashMap<String, Entry>>, key: &str) -> Option<String> {
+    let mut map = map.lock().unwrap();
+    match map.get(key) {
+        Some(e) if e.expires > Instant::now() => Some(e.username.clone()),
+        Some(_) => {
+            map.remove(key);
+            None
+        }
+        None => None,
+    }
+}
+
+/// Middleware guarding the `/api` routes.
+///
+/// Accepts either a session cookie (see `handlers::session`) or HTTP Basic
+/// credentials. When auth is disabled every request runs as `anonymous`.
+pub async fn require_auth(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let auth = &state.config.auth;
+
+    if !auth.enabled {
+        req.extensions_mut().insert(AuthUser::anonymous());
+        return next.run(req).await;
+    }
+
+    match authenticate(&state, req.headers()).await {
+        Some(user) => {
+            req.extensions_mut().insert(user);
+            next.run(req).await
+        }
+        None => unauthorized(auth, req.uri().path()),
+    }
+}
+
+async fn authenticate(state: &AppState, headers: &HeaderMap) -> Option<AuthUser> {
+    let auth = &state.config.auth;
+
+    if let Some(username) = session_cookie(headers).and_then(|t| state.sessions.lookup(t)) {
+        return find_user(auth, &username);
+    }
+
+    let header = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+
+    if let Some(username) = state.sessions.lookup_basic(header) {
+        return find_user(auth, &username);
+    }
+
+    let (username, password) = parse_basic(header)?;
+    let user = check_credentials(auth, &username, password).await?;
+    state.sessions.remember_basic(header, &user.username);
+    Some(user)
+}
+
+fn find_user(auth: &AuthConfig, username: &str) -> Option<AuthUser> {
+    auth.users
+        .iter()
+        .find(|u| u.username == username)
+        .map(|u| AuthUser {
+            username: u.username.clone(),
+            role: u.role,
+        })
+}
+
+/// Verifies a username/password pair against the configured users.
+/// Hashing runs on the blocking pool so it does not stall the runtime.
+pub async fn check_credentials(
+    auth: &AuthConfig,
+    username: &str,
+    password: String,
+) -> Option<AuthUser> {
+    let user = auth.users.iter().find(|u| u.username == username)?;
+    let hash = user.password_hash.clone();
+
+    let ok = tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+        .await
+        .ok()?;
+
+    if ok {
+        find_user(auth, username)
+    } else {
+        tracing::warn!("Failed login for user '{}'", username);
+        None
+    }
+}
+
+fn unauthorized(auth: &AuthConfig, path: &str) -> Response {
+    let mut response =
+        AppError::Unauthorized("authentication required".to_string()).into_response();
+
+    // Without the session login page, let the browser show its own prompt.
+    // WebDAV clients only know Basic, so they always get the challenge.
+    if !auth.session_login || dav::relative_path(path).is_some() {
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"monkeyarch\", charset=\"UTF-8\""),
+        );
+    }
+
+    response
+}
+
+/// Extracts the session token from the `Cookie` header(s).
+pub fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value)
+        .filter(|value| !value.is_empty())
+}
+
+/// Parses an `Authorization: Basic ...` header into username and password.
+fn parse_basic(header: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = header.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Returns true if the hash is in a format [`verify_password`] understands.
+pub fn is_supported_hash(hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok()
+    } else {
+        hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$")
+    }
+}
+
+/// Checks a password against an argon2 or bcrypt hash.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        match PasswordHash::new(hash) {
+            Ok(parsed) => Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+            Err(_) => false,
+        }
+    } else if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else {
+        false
+    }
+}
+
+/// Produces an argon2id hash suitable for `password_hash` in config.toml.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut rand::rngs::OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_basic() {
+        // "pi:rasp:berry" - password may contain colons
+        let header = format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode("pi:rasp:berry")
+        );
+        assert_eq!(
+            parse_basic(&header),
+            Some(("pi".to_string(), "rasp:berry".to_string()))
+        );
+        assert_eq!(parse_basic("Bearer abc"), None);
+        assert_eq!(parse_basic("Basic !!!"), None);
+    }
+
+    #[test]
+    fn test_session_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_static("theme=dark; monkeyarch_session=abc123"),
+        );
+        assert_eq!(session_cookie(&headers), Some("abc123"));
+
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_static("monkeyarch_session="),
+        );
+        assert_eq!(session_cookie(&headers), None);
+    }
+
+    #[test]
+    fn test_verify_password() {
+        let argon = hash_password("secret").unwrap();
+        assert!(is_supported_hash(&argon));
+        assert!(verify_password("secret", &argon));
+        assert!(!verify_password("wrong", &argon));
+
+        let bcrypt = bcrypt::hash("secret", 4).unwrap();
+        assert!(is_supported_hash(&bcrypt));
+        assert!(verify_password("secret", &bcrypt));
+        assert!(!verify_password("wrong", &bcrypt));
+
+        assert!(!is_supported_hash("secret"));
+        assert!(!verify_password("secret", "secret"));
+    }
+
+    #[test]
+    fn test_role_order() {
+        let user = |role| AuthUser {
+            username: "kid".to_string(),
+            role,
+        };
+
+        assert!(user(Role::ReadOnly).require(Role::ReadOnly).is_ok());
+        assert!(user(Role::ReadOnly).require(Role::Uploader).is_err());
+        assert!(user(Role::Uploader).require(Role::Uploader).is_ok());
+        assert!(user(Role::Uploader).require(Role::Admin).is_err());
+        assert!(user(Role::Admin).require(Role::Uploader).is_ok());
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let store = SessionStore::default();
+        let live = store.create("pi", Duration::from_secs(60));
+        let dead = store.create("pi", Duration::ZERO);
+
+        assert_eq!(store.lookup(&live).as_deref(), Some("pi"));
+        assert_eq!(store.lookup(&dead), None);
+
+        store.remove(&live);
+        assert_eq!(store.lookup(&live), None);
+    }
+}
@@ -0,0 +1,226 @@
+//! Upload checksums: digests sent by the client, verified while streaming.
+//!
+//! Accepted per file: a `Content-MD5` part header (RFC 1864), a `Digest`
+//! part header (RFC 3230, `md5` and `sha-256`), or a hex `sha256` form field.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::error::AppError;
+use crate::util::to_hex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha256 => "sha256",
+        }
+    }
+
+    fn len(self) -> usize {
+        match self {
+            Algorithm::Md5 => 16,
+            Algorithm::Sha256 => 32,
+        }
+    }
+}
+
+/// A digest the client says the file has.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expected {
+    pub algorithm: Algorithm,
+    pub digest: Vec<u8>,
+}
+
+impl Expected {
+    fn new(algorithm: Algorithm, digest: Option<Vec<u8>>, source: &str) -> Result<Self, AppError> {
+        match digest {
+            Some(digest) if digest.len() == algorithm.len() => Ok(Self { algorithm, digest }),
+            _ => Err(AppError::BadRequest(format!(
+                "invalid {} {}",
+                algorithm.name(),
+                source
+            ))),
+        }
+    }
+
+    /// `Content-MD5: <base64>`
+    pub fn content_md5(value: &str) -> Result<Self, AppError> {
+        let digest = STANDARD.decode(value.trim()).ok();
+        Self::new(Algorithm::Md5, digest, "Content-MD5")
+    }
+
+    /// `sha256=<hex>` form field.
+    pub fn sha256_hex(value: &str) -> Result<Self, AppError> {
+        Self::new(Algorithm::Sha256, decode_hex(value.trim()), "form field")
+    }
+
+    /// `Digest: sha-256=<base64>, md5=<base64>`. Unknown algorithms are
+    /// ignored, as RFC 3230 asks.
+    pub fn digest_header(value: &str) -> Result<Vec<Self>, AppError> {
+        let mut expected = Vec::new();
+
+        for item in value.split(',') {
+            let Some((name, encoded)) = item.trim().split_once('=') else {
+                continue;
+            };
+            let algorithm = match name.trim().to_ascii_lowercase().as_str() {
+                "md5" => Algorithm::Md5,
+                "sha-256" => Algorithm::Sha256,
+                _ => continue,
+            };
+            let digest = STANDARD.decode(encoded.trim()).ok();
+            expected.push(Self::new(algorithm, digest, "Digest")?);
+        }
+
+        Ok(expected)
+    }
+}
+
+/// Computed digests, lowercase hex.
+#[derive(Debug, Clone, Default)]
+pub struct Digests {
+    pub sha256: String,
+    /// Only computed when the client sent an MD5.
+    pub md5: Option<String>,
+}
+
+/// Hashes a file as it streams. SHA-256 is always computed so it can be
+/// returned to the client; MD5 only when there is one to check.
+pub struct Checksum {
+    sha256: Sha256,
+    md5: Option<Md5>,
+    expected: Vec<Expected>,
+}
+
+impl Checksum {
+    pub fn new(expected: Vec<Expected>) -> Self {
+        let md5 = expected
+            .iter()
+            .any(|e| e.algorithm == Algorithm::Md5)
+            .then(Md5::new);
+
+        Self {
+            sha256: Sha256::new(),
+            md5,
+            expected,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.sha256.update(data);
+        if let Some(md5) = &mut self.md5 {
+            md5.update(data);
+        }
+    }
+
+    /// Compares against every expected digest.
+    pub fn finish(self) -> Result<Digests, AppError> {
+        let sha256 = self.sha256.finalize().to_vec();
+        let md5 = self.md5.map(|md5| md5.finalize().to_vec());
+
+        for expected in &self.expected {
+            let actual = match expected.algorithm {
+                Algorithm::Sha256 => &sha256,
+                Algorithm::Md5 => md5.as_ref().expect("md5 is computed when expected"),
+            };
+            if *actual != expected.digest {
+                return Err(AppError::BadRequest(format!(
+                    "{} mismatch: expected {}, got {}",
+                    expected.algorithm.name(),
+                    to_hex(&expected.digest),
+                    to_hex(actual)
+                )));
+            }
+        }
+
+        Ok(Digests {
+            sha256: to_hex(&sha256),
+            md5: md5.as_deref().map(to_hex),
+        })
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    let pairs = s.as_bytes().chunks_exact(2);
+    if !pairs.remainder().is_empty() || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    pairs
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    const HELLO_MD5_B64: &str = "XUFAKrxLKna5cZ2REBfFkg==";
+
+    fn hash(data: &[u8], expected: Vec<Expected>) -> Result<Digests, AppError> {
+        let mut checksum = Checksum::new(expected);
+        for chunk in data.chunks(2) {
+            checksum.update(chunk);
+        }
+        checksum.finish()
+    }
+
+    #[test]
+    fn test_no_expectation_still_hashes() {
+        let digests = hash(b"hello", Vec::new()).unwrap();
+        assert_eq!(digests.sha256, HELLO_SHA256);
+        assert_eq!(digests.md5, None);
+    }
+
+    #[test]
+    fn test_matching_digests() {
+        let expected = vec![
+            Expected::sha256_hex(&HELLO_SHA256.to_uppercase()).unwrap(),
+            Expected::content_md5(HELLO_MD5_B64).unwrap(),
+        ];
+        let digests = hash(b"hello", expected).unwrap();
+        assert_eq!(
+            digests.md5.as_deref(),
+            Some("5d41402abc4b2a76b9719d911017c592")
+        );
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let expected = vec![Expected::content_md5(HELLO_MD5_B64).unwrap()];
+        assert!(matches!(
+            hash(b"hellO", expected),
+            Err(AppError::BadRequest(msg)) if msg.starts_with("md5 mismatch")
+        ));
+    }
+
+    #[test]
+    fn test_digest_header() {
+        let sha_b64 = STANDARD.encode(decode_hex(HELLO_SHA256).unwrap());
+        let value = format!("SHA-256={}, unixsum=30, md5={}", sha_b64, HELLO_MD5_B64);
+        let expected = Expected::digest_header(&value).unwrap();
+        assert_eq!(expected.len(), 2);
+        assert!(hash(b"hello", expected).is_ok());
+
+        assert!(Expected::digest_header("sha-256=AAAA").is_err());
+        assert!(Expected::digest_header("crc32c=abc").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        assert!(Expected::sha256_hex("abc").is_err());
+        assert!(Expected::sha256_hex(&"zz".repeat(32)).is_err());
+        assert!(Expected::content_md5("not base64!").is_err());
+    }
+}
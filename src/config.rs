@@ -1,6 +1,10 @@
+use ipnet::IpNet;
 use serde::Deserialize;
+use std::net::IpAddr;
 use std::path::PathBuf;
 
+use crate::auth::Role;
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub root_directory: PathBuf,
@@ -9,6 +13,195 @@ pub struct Config {
     pub port: u16,
     pub max_upload_size: u64,
     pub enable_delete: bool,
+    pub auth: AuthConfig,
+    /// Networks allowed to connect. Empty = everyone.
+    pub allowed_networks: Vec<IpNet>,
+    /// Reverse proxies whose `X-Forwarded-For` header is trusted.
+    pub trusted_proxies: Vec<IpNet>,
+    pub access_rules: Vec<AccessRule>,
+    pub upload_policy: UploadPolicy,
+    pub quotas: Vec<Quota>,
+    pub trash: TrashConfig,
+    pub jobs: JobsConfig,
+    pub events: EventsConfig,
+    pub tus: TusConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthConfig {
+    /// Require credentials for every `/api` route.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Offer a cookie session login page instead of the browser's Basic Auth prompt.
+    #[serde(default)]
+    pub session_login: bool,
+
+    /// Session lifetime in seconds.
+    #[serde(default = "default_session_ttl")]
+    pub session_ttl: u64,
+
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            session_login: false,
+            session_ttl: default_session_ttl(),
+            users: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserConfig {
+    pub username: String,
+    /// Argon2 (`$argon2id$...`) or bcrypt (`$2b$...`) password hash.
+    pub password_hash: String,
+    #[serde(default = "default_role")]
+    pub role: Role,
+}
+
+/// Restricts who may see or modify a subtree. See `access.rs`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccessRule {
+    /// Root-relative directory or file the rule applies to (and below).
+    pub path: String,
+    /// Users or role names that may see the path. Everyone else gets 404.
+    #[serde(default)]
+    pub read: Option<Vec<String>>,
+    /// Users or role names that may upload, create, move and delete here.
+    #[serde(default)]
+    pub write: Option<Vec<String>>,
+}
+
+/// Which files may be uploaded. See `mime_policy.rs`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadPolicy {
+    /// Allowed MIME types; `type/*` matches a whole family.
+    #[serde(default = "default_mime_types")]
+    pub mime_types: Vec<String>,
+    /// Allowed file extensions, without the dot. Empty = any.
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// Check the first bytes of each file against its claimed type.
+    #[serde(default = "default_sniff")]
+    pub sniff: bool,
+    #[serde(default)]
+    pub overrides: Vec<PolicyOverride>,
+}
+
+impl Default for UploadPolicy {
+    fn default() -> Self {
+        Self {
+            mime_types: default_mime_types(),
+            extensions: Vec::new(),
+            sniff: default_sniff(),
+            overrides: Vec::new(),
+        }
+    }
+}
+
+/// Replaces the allow-lists for uploads into a subtree.
+/// The most specific matching override wins.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PolicyOverride {
+    /// Root-relative directory the override applies to (and below).
+    pub path: String,
+    #[serde(default)]
+    pub mime_types: Option<Vec<String>>,
+    #[serde(default)]
+    pub extensions: Option<Vec<String>>,
+}
+
+/// Caps the total size of a subtree. See `storage.rs`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Quota {
+    /// Root-relative directory; empty = the whole root.
+    #[serde(default)]
+    pub path: String,
+    /// Maximum total size in bytes.
+    pub max_size: u64,
+}
+
+/// Recycle bin for deleted files. See `trash.rs`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TrashConfig {
+    /// Move deleted items to the trash instead of removing them.
+    #[serde(default = "default_trash_enabled")]
+    pub enabled: bool,
+    /// Days before trashed items are removed for good. 0 = keep forever.
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u64,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_trash_enabled(),
+            retention_days: default_retention_days(),
+        }
+    }
+}
+
+/// Background jobs. See `jobs.rs`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct JobsConfig {
+    /// Jobs running at once; the rest wait in line.
+    #[serde(default = "default_job_workers")]
+    pub workers: usize,
+    /// Seconds a finished job (and its archive) is kept.
+    #[serde(default = "default_job_result_ttl")]
+    pub result_ttl: u64,
+    /// Jobs one user may have queued or running at once.
+    #[serde(default = "default_jobs_per_user")]
+    pub max_per_user: usize,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            workers: default_job_workers(),
+            result_ttl: default_job_result_ttl(),
+            max_per_user: default_jobs_per_user(),
+        }
+    }
+}
+
+/// Live change notifications. See `events.rs`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventsConfig {
+    /// Watch the root with inotify and serve `/api/events`.
+    #[serde(default = "default_events_enabled")]
+    pub enabled: bool,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_events_enabled(),
+        }
+    }
+}
+
+/// Resumable uploads. See `tus.rs`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TusConfig {
+    /// Seconds without progress before an unfinished upload is discarded.
+    /// 0 = keep until the client terminates it.
+    #[serde(default = "default_tus_expire_after")]
+    pub expire_after: u64,
+}
+
+impl Default for TusConfig {
+    fn default() -> Self {
+        Self {
+            expire_after: default_tus_expire_after(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -30,6 +223,36 @@ struct RawConfig {
 
     #[serde(default = "default_enable_delete")]
     enable_delete: bool,
+
+    #[serde(default)]
+    auth: AuthConfig,
+
+    #[serde(default)]
+    allowed_networks: Vec<String>,
+
+    #[serde(default)]
+    trusted_proxies: Vec<String>,
+
+    #[serde(default)]
+    access_rules: Vec<AccessRule>,
+
+    #[serde(default)]
+    upload_policy: UploadPolicy,
+
+    #[serde(default)]
+    quotas: Vec<Quota>,
+
+    #[serde(default)]
+    trash: TrashConfig,
+
+    #[serde(default)]
+    jobs: JobsConfig,
+
+    #[serde(default)]
+    events: EventsConfig,
+
+    #[serde(default)]
+    tus: TusConfig,
 }
 
 fn default_root() -> String {
@@ -52,6 +275,50 @@ fn default_enable_delete() -> bool {
     true
 }
 
+fn default_role() -> Role {
+    Role::Admin
+}
+
+fn default_session_ttl() -> u64 {
+    7 * 24 * 60 * 60 // 1 week
+}
+
+fn default_mime_types() -> Vec<String> {
+    vec!["audio/mpeg".into(), "image/*".into()]
+}
+
+fn default_sniff() -> bool {
+    true
+}
+
+fn default_trash_enabled() -> bool {
+    true
+}
+
+fn default_retention_days() -> u64 {
+    30
+}
+
+fn default_job_workers() -> usize {
+    1
+}
+
+fn default_job_result_ttl() -> u64 {
+    60 * 60 // 1 hour
+}
+
+fn default_jobs_per_user() -> usize {
+    4
+}
+
+fn default_events_enabled() -> bool {
+    true
+}
+
+fn default_tus_expire_after() -> u64 {
+    24 * 60 * 60 // 1 day
+}
+
 impl Config {
     pub fn load() -> Result<Self, config::ConfigError> {
         let cfg = config::Config::builder()
@@ -73,6 +340,33 @@ impl Config {
             port: raw.port,
             max_upload_size: raw.max_upload_size,
             enable_delete: raw.enable_delete,
+            auth: raw.auth,
+            allowed_networks: parse_networks("allowed_networks", &raw.allowed_networks)?,
+            trusted_proxies: parse_networks("trusted_proxies", &raw.trusted_proxies)?,
+            access_rules: raw.access_rules,
+            upload_policy: raw.upload_policy,
+            quotas: raw.quotas,
+            trash: raw.trash,
+            jobs: raw.jobs,
+            events: raw.events,
+            tus: raw.tus,
         })
     }
 }
+
+/// Parses CIDR entries ("192.168.1.0/24", "fd00::/8"). A bare address is
+/// treated as a single host.
+fn parse_networks(key: &str, entries: &[String]) -> Result<Vec<IpNet>, config::ConfigError> {
+    entries
+        .iter()
+        .map(|entry| {
+            let entry = entry.trim();
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| {
+                    config::ConfigError::Message(format!("{}: invalid network '{}'", key, entry))
+                })
+        })
+        .collect()
+}
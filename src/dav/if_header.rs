@@ -0,0 +1,199 @@
+//! The WebDAV `If` header (RFC 4918, section 10.4).
+//!
+//! ```text
+//! If: (<opaquelocktoken:a-b-c> ["etag"]) (Not <DAV:no-lock>)
+//! If: </dav/file.mp3> (<opaquelocktoken:a-b-c>)
+//! ```
+//!
+//! The header holds lists of conditions, optionally tagged with the
+//! resource they apply to. It is true when all conditions of any one list
+//! hold. Lock tokens named anywhere in it count as submitted.
+
+use crate::error::AppError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    /// A lock token (or any other state token, which never matches).
+    Token(String),
+    ETag(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct List {
+    /// Tagged resource; `None` for the request URI.
+    pub resource: Option<String>,
+    /// Conditions, each with whether it is negated.
+    pub conditions: Vec<(bool, Condition)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IfHeader {
+    pub lists: Vec<List>,
+}
+
+impl IfHeader {
+    pub fn parse(value: &str) -> Result<Self, AppError> {
+        let invalid = || AppError::BadRequest(format!("invalid If header: {}", value));
+        let mut rest = value.trim_start();
+        let mut resource = None;
+        let mut lists = Vec::new();
+
+        while let Some(c) = rest.chars().next() {
+            match c {
+                '<' => {
+                    let (url, after) = delimited(rest, '>').ok_or_else(invalid)?;
+                    resource = Some(url.to_string());
+                    rest = after;
+                }
+                '(' => {
+                    let (inner, after) = delimited(rest, ')').ok_or_else(invalid)?;
+                    let conditions = parse_conditions(inner).ok_or_else(invalid)?;
+                    lists.push(List {
+                        resource: resource.clone(),
+                        conditions,
+                    });
+                    rest = after;
+                }
+                _ => return Err(invalid()),
+            }
+            rest = rest.trim_start();
+        }
+
+        if lists.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self { lists })
+    }
+
+    /// Lock tokens named in the header.
+    pub fn tokens(&self) -> Vec<String> {
+        self.lists
+            .iter()
+            .flat_map(|list| &list.conditions)
+            .filter_map(|(_, condition)| match condition {
+                Condition::Token(token) => Some(token.clone()),
+                Condition::ETag(_) => None,
+            })
+            .collect()
+    }
+
+    /// Evaluates the header; `holds` answers whether a condition is true
+    /// for a resource (`None` being the request URI).
+    pub fn evaluate<F>(&self, mut holds: F) -> bool
+    where
+        F: FnMut(Option<&str>, &Condition) -> bool,
+    {
+        self.lists.iter().any(|list| {
+            list.conditions
+                .iter()
+                .all(|(not, condition)| holds(list.resource.as_deref(), condition) != *not)
+        })
+    }
+}
+
+/// Splits `<...>rest` (or `(...)`, `[...]`) into the inside and the rest.
+fn delimited(s: &str, close: char) -> Option<(&str, &str)> {
+    let end = s.find(close)?;
+    Some((&s[1..end], &s[end + 1..]))
+}
+
+fn parse_conditions(mut rest: &str) -> Option<Vec<(bool, Condition)>> {
+    let mut conditions = Vec::new();
+    rest = rest.trim_start();
+
+    while !rest.is_empty() {
+        let mut not = false;
+        if rest.len() >= 3 && rest[..3].eq_ignore_ascii_case("not") {
+            not = true;
+            rest = rest[3..].trim_start();
+        }
+
+        let condition = match rest.chars().next()? {
+            '<' => {
+                let (token, after) = delimited(rest, '>')?;
+                rest = after;
+                Condition::Token(token.to_string())
+            }
+            '[' => {
+                let (etag, after) = delimited(rest, ']')?;
+                rest = after;
+                Condition::ETag(etag.to_string())
+            }
+            _ => return None,
+        };
+        conditions.push((not, condition));
+        rest = rest.trim_start();
+    }
+
+    (!conditions.is_empty()).then_some(conditions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "opaquelocktoken:e71d4fae-5dec-22d6-fea5-00a0c91e6be4";
+
+    #[test]
+    fn test_parse() {
+        let header =
+            IfHeader::parse(&format!("(<{}> [\"abc\"]) (Not <DAV:no-lock>)", TOKEN)).unwrap();
+        assert_eq!(
+            header.lists,
+            vec![
+                List {
+                    resource: None,
+                    conditions: vec![
+                        (false, Condition::Token(TOKEN.into())),
+                        (false, Condition::ETag("\"abc\"".into())),
+                    ],
+                },
+                List {
+                    resource: None,
+                    conditions: vec![(true, Condition::Token("DAV:no-lock".into()))],
+                },
+            ]
+        );
+        assert_eq!(
+            header.tokens(),
+            vec![TOKEN.to_string(), "DAV:no-lock".into()]
+        );
+    }
+
+    #[test]
+    fn test_parse_tagged() {
+        let header =
+            IfHeader::parse(&format!("</dav/a.mp3> (<{}>) </dav/b> ([W/\"x\"])", TOKEN)).unwrap();
+        assert_eq!(header.lists[0].resource.as_deref(), Some("/dav/a.mp3"));
+        assert_eq!(header.lists[1].resource.as_deref(), Some("/dav/b"));
+        assert_eq!(
+            header.lists[1].conditions,
+            vec![(false, Condition::ETag("W/\"x\"".into()))]
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for value in ["", "()", "(<a>", "<a>", "(foo)", "(Not)", "x (<a>)"] {
+            assert!(IfHeader::parse(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let locked = |_: Option<&str>, c: &Condition| *c == Condition::Token(TOKEN.into());
+
+        let header = IfHeader::parse(&format!("(<{}>)", TOKEN)).unwrap();
+        assert!(header.evaluate(locked));
+        assert!(!IfHeader::parse("(<DAV:no-lock>)").unwrap().evaluate(locked));
+        assert!(IfHeader::parse("(Not <DAV:no-lock>)")
+            .unwrap()
+            .evaluate(locked));
+
+        // Any list will do, but every condition of it must hold
+        let header = IfHeader::parse(&format!("(<DAV:no-lock>) (<{}>)", TOKEN)).unwrap();
+        assert!(header.evaluate(locked));
+        let header = IfHeader::parse(&format!("(<{}> <DAV:no-lock>)", TOKEN)).unwrap();
+        assert!(!header.evaluate(locked));
+    }
+}
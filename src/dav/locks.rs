@@ -0,0 +1,356 @@
+//! WebDAV write locks (class 2).
+//!
+//! Locks live in memory and are lost on restart, which clients handle like
+//! an expired lock. Paths are root-relative; a lock on a folder with depth
+//! infinity covers everything below it.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::AppError;
+use crate::util;
+
+tokio::task_local! {
+    /// Lock tokens submitted by the WebDAV request being handled.
+    static SUBMITTED: Vec<String>;
+}
+
+/// Runs `f` with `tokens` submitted, for the shared handlers it calls.
+pub async fn with_tokens<F: Future>(tokens: Vec<String>, f: F) -> F::Output {
+    SUBMITTED.scope(tokens, f).await
+}
+
+/// Longest lock handed out, also for `Timeout: Infinite`.
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Used when the client doesn't ask for a timeout.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct Lock {
+    pub token: String,
+    pub path: PathBuf,
+    pub shared: bool,
+    /// Depth infinity.
+    pub deep: bool,
+    pub owner: Option<String>,
+    pub username: String,
+    pub timeout: Duration,
+    expires: Instant,
+}
+
+impl Lock {
+    /// True if the lock applies to `rel`.
+    pub fn covers(&self, rel: &Path) -> bool {
+        rel == self.path || (self.deep && rel.starts_with(&self.path))
+    }
+
+    /// True if the lock applies to `rel` or, with `deep`, anything below it.
+    fn overlaps(&self, rel: &Path, deep: bool) -> bool {
+        self.covers(rel) || (deep && self.path.starts_with(rel))
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.expires.saturating_duration_since(Instant::now())
+    }
+}
+
+#[derive(Default)]
+pub struct LockStore {
+    locks: Mutex<HashMap<String, Lock>>,
+}
+
+impl LockStore {
+    /// Takes a new lock, unless it conflicts with an existing one: an
+    /// exclusive lock with any lock, a shared lock with an exclusive one.
+    pub fn lock(
+        &self,
+        rel: &Path,
+        shared: bool,
+        deep: bool,
+        owner: Option<String>,
+        username: &str,
+        timeout: Duration,
+    ) -> Result<Lock, AppError> {
+        let mut locks = self.active();
+        let conflict = locks
+            .values()
+            .any(|lock| lock.overlaps(rel, deep) && !(shared && lock.shared));
+        if conflict {
+            return Err(AppError::Locked(format!("'{}' is locked", rel.display())));
+        }
+
+        let lock = Lock {
+            token: new_token(),
+            path: rel.to_path_buf(),
+            shared,
+            deep,
+            owner,
+            username: username.to_string(),
+            timeout,
+            expires: Instant::now() + timeout,
+        };
+        locks.insert(lock.token.clone(), lock.clone());
+        Ok(lock)
+    }
+
+    /// Extends a lock on (or covering) `rel` that the user holds.
+    pub fn refresh(
+        &self,
+        rel: &Path,
+        tokens: &[String],
+        username: &str,
+        timeout: Duration,
+    ) -> Result<Lock, AppError> {
+        let mut locks = self.active();
+        let token = tokens
+            .iter()
+            .find(|token| {
+                locks
+                    .get(*token)
+                    .is_some_and(|lock| lock.covers(rel) && lock.username == username)
+            })
+            .ok_or_else(|| {
+                AppError::PreconditionFailed("no matching lock to refresh".to_string())
+            })?;
+        let lock = locks.get_mut(token).unwrap();
+
+        lock.timeout = timeout;
+        lock.expires = Instant::now() + timeout;
+        Ok(lock.clone())
+    }
+
+    /// Releases a lock covering `rel`.
+    pub fn unlock(&self, rel: &Path, token: &str, username: &str) -> Result<(), AppError> {
+        let mut locks = self.active();
+        match locks.get(token) {
+            Some(lock) if lock.covers(rel) && lock.username == username => {
+                locks.remove(token);
+                Ok(())
+            }
+            Some(lock) if lock.covers(rel) => Err(AppError::Forbidden),
+            _ => Err(AppError::Conflict(
+                "lock token does not match the resource".to_string(),
+            )),
+        }
+    }
+
+    /// Locks applying to `rel`.
+    pub fn covering(&self, rel: &Path) -> Vec<Lock> {
+        self.active()
+            .values()
+            .filter(|lock| lock.covers(rel))
+            .cloned()
+            .collect()
+    }
+
+    /// Checks that `rel` (with `deep`, also everything below it) may be
+    /// changed by a user who submitted `tokens`: every lock in the way
+    /// must be one of them and belong to the user. For shared locks on the
+    /// same path, one of them is enough.
+    pub fn check(
+        &self,
+        rel: &Path,
+        deep: bool,
+        tokens: &[String],
+        username: &str,
+    ) -> Result<(), AppError> {
+        let locks = self.active();
+        let held = |lock: &Lock| tokens.contains(&lock.token) && lock.username == username;
+
+        let blocked = locks
+            .values()
+            .filter(|lock| lock.overlaps(rel, deep))
+            .any(|lock| {
+                if lock.shared {
+                    !locks
+                        .values()
+                        .any(|other| other.shared && other.path == lock.path && held(other))
+                } else {
+                    !held(lock)
+                }
+            });
+
+        if blocked {
+            return Err(AppError::Locked(format!("'{}' is locked", rel.display())));
+        }
+        Ok(())
+    }
+
+    /// `check` for the write paths shared with the API, batches and tus.
+    /// Only WebDAV requests submit tokens; any other write is blocked by
+    /// every lock in its way.
+    pub fn guard(&self, rel: &Path, deep: bool, username: &str) -> Result<(), AppError> {
+        SUBMITTED
+            .try_with(|tokens| self.check(rel, deep, tokens, username))
+            .unwrap_or_else(|_| self.check(rel, deep, &[], username))
+    }
+
+    /// Guards adding or removing `rel`, which also changes its parent folder.
+    pub fn guard_entry(&self, rel: &Path, username: &str) -> Result<(), AppError> {
+        self.guard(rel, true, username)?;
+        self.guard(rel.parent().unwrap_or(Path::new("")), false, username)
+    }
+
+    /// Drops the locks on `rel` and below, after it was deleted or moved.
+    pub fn remove_tree(&self, rel: &Path) {
+        self.active().retain(|_, lock| !lock.path.starts_with(rel));
+    }
+
+    /// The store, with expired locks dropped.
+    fn active(&self) -> std::sync::MutexGuard<'_, HashMap<String, Lock>> {
+        let mut locks = self.locks.lock().unwrap();
+        let now = Instant::now();
+        locks.retain(|_, lock| lock.expires > now);
+        locks
+    }
+}
+
+/// Parses a `Timeout` header ("Second-600", "Infinite", or a list of them).
+pub fn parse_timeout(value: Option<&str>) -> Duration {
+    value
+        .into_iter()
+        .flat_map(|v| v.split(','))
+        .find_map(|t| {
+            let t = t.trim();
+            if t.eq_ignore_ascii_case("infinite") {
+                Some(MAX_TIMEOUT)
+            } else {
+                t.strip_prefix("Second-")
+                    .and_then(|s| s.parse().ok())
+                    .map(Duration::from_secs)
+            }
+        })
+        .unwrap_or(DEFAULT_TIMEOUT)
+        .min(MAX_TIMEOUT)
+}
+
+/// A lock token in the `opaquelocktoken` URI scheme.
+fn new_token() -> String {
+    let id = util::random_id();
+    format!(
+        "opaquelocktoken:{}-{}-{}-{}-{}",
+        &id[..8],
+        &id[8..12],
+        &id[12..16],
+        &id[16..20],
+        &id[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn lock(store: &LockStore, path: &str, shared: bool, deep: bool) -> Result<Lock, AppError> {
+        store.lock(Path::new(path), shared, deep, None, "pi", HOUR)
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let store = LockStore::default();
+        let dir = lock(&store, "music", false, true).unwrap();
+        assert!(dir.token.starts_with("opaquelocktoken:"));
+
+        // Covered by the depth-infinity lock
+        assert!(lock(&store, "music/a.mp3", true, false).is_err());
+        assert!(lock(&store, "photos", false, false).is_ok());
+        // A deep lock on the root would include music/
+        assert!(lock(&store, "", false, true).is_err());
+
+        let store = LockStore::default();
+        lock(&store, "a.mp3", true, false).unwrap();
+        assert!(lock(&store, "a.mp3", true, false).is_ok());
+        assert!(lock(&store, "a.mp3", false, false).is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let store = LockStore::default();
+        let file = lock(&store, "music/a.mp3", false, false).unwrap();
+        let token = vec![file.token.clone()];
+
+        assert!(store
+            .check(Path::new("music/a.mp3"), false, &[], "pi")
+            .is_err());
+        assert!(store
+            .check(Path::new("music/a.mp3"), false, &token, "pi")
+            .is_ok());
+        // Someone else's token doesn't count
+        assert!(store
+            .check(Path::new("music/a.mp3"), false, &token, "dj")
+            .is_err());
+        // Deleting the folder would take the locked file with it
+        assert!(store.check(Path::new("music"), true, &[], "pi").is_err());
+        assert!(store.check(Path::new("music"), false, &[], "pi").is_ok());
+        assert!(store
+            .check(Path::new("music/b.mp3"), false, &[], "pi")
+            .is_ok());
+
+        store.remove_tree(Path::new("music"));
+        assert!(store
+            .check(Path::new("music/a.mp3"), false, &[], "pi")
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_guard() {
+        let store = LockStore::default();
+        let dir = lock(&store, "music", false, false).unwrap();
+
+        // Without a WebDAV request, nothing submits the token
+        assert!(store.guard(Path::new("music"), false, "pi").is_err());
+        assert!(store.guard_entry(Path::new("music/a.mp3"), "pi").is_err());
+        assert!(store.guard_entry(Path::new("photos/a.mp3"), "pi").is_ok());
+
+        with_tokens(vec![dir.token.clone()], async {
+            assert!(store.guard_entry(Path::new("music/a.mp3"), "pi").is_ok());
+            assert!(store.guard_entry(Path::new("music/a.mp3"), "dj").is_err());
+        })
+        .await;
+    }
+
+    #[test]
+    fn test_refresh_and_unlock() {
+        let store = LockStore::default();
+        let dir = lock(&store, "music", false, true).unwrap();
+        let tokens = vec![dir.token.clone()];
+
+        let refreshed = store
+            .refresh(Path::new("music/a.mp3"), &tokens, "pi", MAX_TIMEOUT)
+            .unwrap();
+        assert_eq!(refreshed.timeout, MAX_TIMEOUT);
+        assert!(store
+            .refresh(Path::new("photos"), &tokens, "pi", HOUR)
+            .is_err());
+
+        assert!(store.unlock(Path::new("photos"), &dir.token, "pi").is_err());
+        assert!(store.unlock(Path::new("music"), &dir.token, "dj").is_err());
+        store.unlock(Path::new("music"), &dir.token, "pi").unwrap();
+        assert!(store.covering(Path::new("music")).is_empty());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let store = LockStore::default();
+        store
+            .lock(Path::new("a.mp3"), false, false, None, "pi", Duration::ZERO)
+            .unwrap();
+        assert!(store.covering(Path::new("a.mp3")).is_empty());
+        assert!(lock(&store, "a.mp3", false, false).is_ok());
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout(None), DEFAULT_TIMEOUT);
+        assert_eq!(parse_timeout(Some("Second-600")), Duration::from_secs(600));
+        assert_eq!(parse_timeout(Some("Infinite, Second-60")), MAX_TIMEOUT);
+        assert_eq!(parse_timeout(Some("Second-99999999")), MAX_TIMEOUT);
+        assert_eq!(parse_timeout(Some("bogus")), DEFAULT_TIMEOUT);
+    }
+}
@@ -0,0 +1,152 @@
+//! WebDAV (RFC 4918, class 1 and 2) under `/dav/`, so the media folder can
+//! be mounted as a network drive. The protocol parts live here; the
+//! handler in `handlers/dav.rs` maps the methods onto the same checks and
+//! operations as the JSON API.
+
+pub mod if_header;
+pub mod locks;
+pub mod xml;
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+use crate::error::AppError;
+
+/// Where the WebDAV tree is mounted.
+pub const PREFIX: &str = "/dav";
+
+/// Characters escaped in a path segment of an href.
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b']')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Depth {
+    Zero,
+    One,
+    Infinity,
+}
+
+impl Depth {
+    /// Parses a `Depth` header, falling back to `default` when missing.
+    pub fn parse(value: Option<&str>, default: Depth) -> Result<Self, AppError> {
+        match value.map(str::trim) {
+            None => Ok(default),
+            Some("0") => Ok(Depth::Zero),
+            Some("1") => Ok(Depth::One),
+            Some(v) if v.eq_ignore_ascii_case("infinity") => Ok(Depth::Infinity),
+            Some(v) => Err(AppError::BadRequest(format!("invalid Depth: {}", v))),
+        }
+    }
+}
+
+/// Turns a request path below `PREFIX` into a root-relative path, e.g.
+/// `/dav/My%20Music/` into `My Music`. `None` if it isn't below `PREFIX`.
+pub fn relative_path(uri_path: &str) -> Option<String> {
+    let rest = uri_path.strip_prefix(PREFIX)?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+
+    let decoded = percent_decode_str(rest).decode_utf8().ok()?;
+    Some(decoded.trim_matches('/').to_string())
+}
+
+/// Parses a `Destination` header, which may be an absolute URL.
+/// Destinations outside `PREFIX` (or on another host) are refused.
+pub fn destination(value: &str, host: Option<&str>) -> Result<String, AppError> {
+    let path = match value.split_once("://") {
+        Some((_, rest)) => {
+            let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            if host.is_some_and(|host| !authority.eq_ignore_ascii_case(host)) {
+                return Err(AppError::BadRequest(
+                    "destination is on another server".to_string(),
+                ));
+            }
+            path
+        }
+        None => value,
+    };
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+
+    relative_path(path)
+        .ok_or_else(|| AppError::BadRequest(format!("destination must be below {}/", PREFIX)))
+}
+
+/// The href of a root-relative path; folders end in `/`.
+pub fn href(rel: &str, is_dir: bool) -> String {
+    let mut href = String::from(PREFIX);
+    for segment in rel.split('/').filter(|s| !s.is_empty()) {
+        href.push('/');
+        href.extend(utf8_percent_encode(segment, SEGMENT));
+    }
+    if is_dir || href == PREFIX {
+        href.push('/');
+    }
+    href
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(relative_path("/dav").as_deref(), Some(""));
+        assert_eq!(relative_path("/dav/").as_deref(), Some(""));
+        assert_eq!(
+            relative_path("/dav/My%20Music/a%23b.mp3").as_deref(),
+            Some("My Music/a#b.mp3")
+        );
+        assert_eq!(relative_path("/dav/photos/").as_deref(), Some("photos"));
+        assert_eq!(relative_path("/davx/a"), None);
+        assert_eq!(relative_path("/api/list"), None);
+    }
+
+    #[test]
+    fn test_destination() {
+        let host = Some("pi.local:8000");
+        assert_eq!(
+            destination("http://pi.local:8000/dav/a%20b.mp3", host).unwrap(),
+            "a b.mp3"
+        );
+        assert_eq!(destination("/dav/dir/", host).unwrap(), "dir");
+        assert!(destination("http://other:8000/dav/a", host).is_err());
+        assert!(destination("http://pi.local:8000/api/a", host).is_err());
+    }
+
+    #[test]
+    fn test_href() {
+        assert_eq!(href("", true), "/dav/");
+        assert_eq!(href("My Music", true), "/dav/My%20Music/");
+        assert_eq!(href("a/50% #1.mp3", false), "/dav/a/50%25%20%231.mp3");
+        assert_eq!(href("Mötley.mp3", false), "/dav/M%C3%B6tley.mp3");
+    }
+
+    #[test]
+    fn test_depth() {
+        assert_eq!(
+            Depth::parse(None, Depth::Infinity).unwrap(),
+            Depth::Infinity
+        );
+        assert_eq!(
+            Depth::parse(Some("0"), Depth::Infinity).unwrap(),
+            Depth::Zero
+        );
+        assert_eq!(
+            Depth::parse(Some("Infinity"), Depth::Zero).unwrap(),
+            Depth::Infinity
+        );
+        assert!(Depth::parse(Some("2"), Depth::Zero).is_err());
+    }
+}
@@ -0,0 +1,395 @@
+//! Just enough XML for WebDAV request and response bodies.
+//!
+//! Request bodies are small, so they are parsed into a tree of elements
+//! with resolved namespaces. Responses are written as strings, always with
+//! `D:` bound to `DAV:`.
+
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::name::ResolveResult;
+use quick_xml::NsReader;
+
+use crate::error::AppError;
+
+pub const DAV_NS: &str = "DAV:";
+
+/// A property name: namespace and local name.
+pub type PropName = (String, String);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Element {
+    pub ns: String,
+    pub name: String,
+    pub children: Vec<Element>,
+    /// Text content, trimmed.
+    pub text: String,
+}
+
+impl Element {
+    /// True for the `DAV:` element `name`.
+    pub fn is(&self, name: &str) -> bool {
+        self.ns == DAV_NS && self.name == name
+    }
+
+    /// First `DAV:` child called `name`.
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.is(name))
+    }
+
+    pub fn prop_name(&self) -> PropName {
+        (self.ns.clone(), self.name.clone())
+    }
+
+    /// Writes the element back out, e.g. to echo a lock owner.
+    pub fn to_xml(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out);
+        out
+    }
+
+    fn write(&self, out: &mut String) {
+        let tag = if self.ns == DAV_NS {
+            format!("D:{}", self.name)
+        } else {
+            self.name.clone()
+        };
+        out.push('<');
+        out.push_str(&tag);
+        if self.ns != DAV_NS {
+            out.push_str(&format!(" xmlns=\"{}\"", escape(&self.ns)));
+        }
+        out.push('>');
+        out.push_str(&escape(&self.text));
+        for child in &self.children {
+            child.write(out);
+        }
+        out.push_str(&format!("</{}>", tag));
+    }
+}
+
+/// Parses a request body into its root element.
+pub fn parse(body: &[u8]) -> Result<Element, AppError> {
+    let invalid = |e: &dyn std::fmt::Display| AppError::BadRequest(format!("invalid XML: {}", e));
+
+    let text = std::str::from_utf8(body).map_err(|e| invalid(&e))?;
+    let mut reader = NsReader::from_str(text);
+    let mut stack: Vec<Element> = Vec::new();
+
+    loop {
+        let (ns, event) = reader.read_resolved_event().map_err(|e| invalid(&e))?;
+        match event {
+            Event::Start(start) => stack.push(element(ns, &start)?),
+            Event::Empty(start) => {
+                let element = element(ns, &start)?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => return Ok(element),
+                }
+            }
+            Event::End(_) => {
+                let mut element = stack.pop().ok_or_else(|| invalid(&"unexpected end tag"))?;
+                element.text = element.text.trim().to_string();
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => return Ok(element),
+                }
+            }
+            Event::Text(text) => {
+                if let Some(current) = stack.last_mut() {
+                    current
+                        .text
+                        .push_str(&text.unescape().map_err(|e| invalid(&e))?);
+                }
+            }
+            Event::CData(data) => {
+                if let Some(current) = stack.last_mut() {
+                    current.text.push_str(&String::from_utf8_lossy(&data));
+                }
+            }
+            Event::Eof => return Err(invalid(&"no root element")),
+            _ => {}
+        }
+    }
+}
+
+fn element(ns: ResolveResult, start: &BytesStart) -> Result<Element, AppError> {
+    let ns = match ns {
+        ResolveResult::Bound(ns) => String::from_utf8_lossy(ns.as_ref()).to_string(),
+        ResolveResult::Unbound => String::new(),
+        ResolveResult::Unknown(prefix) => {
+            return Err(AppError::BadRequest(format!(
+                "invalid XML: undeclared prefix '{}'",
+                String::from_utf8_lossy(&prefix)
+            )))
+        }
+    };
+    Ok(Element {
+        ns,
+        name: String::from_utf8_lossy(start.local_name().as_ref()).to_string(),
+        children: Vec::new(),
+        text: String::new(),
+    })
+}
+
+/// What a PROPFIND asks for.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Propfind {
+    AllProp,
+    PropName,
+    Prop(Vec<PropName>),
+}
+
+/// An empty body means `allprop`.
+pub fn parse_propfind(body: &[u8]) -> Result<Propfind, AppError> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Propfind::AllProp);
+    }
+
+    let root = parse(body)?;
+    if !root.is("propfind") {
+        return Err(AppError::BadRequest("expected DAV:propfind".to_string()));
+    }
+    if root.child("allprop").is_some() {
+        Ok(Propfind::AllProp)
+    } else if root.child("propname").is_some() {
+        Ok(Propfind::PropName)
+    } else if let Some(prop) = root.child("prop") {
+        Ok(Propfind::Prop(
+            prop.children.iter().map(Element::prop_name).collect(),
+        ))
+    } else {
+        Err(AppError::BadRequest(
+            "expected allprop, propname or prop".to_string(),
+        ))
+    }
+}
+
+/// Properties a PROPPATCH sets or removes, in order.
+pub fn parse_proppatch(body: &[u8]) -> Result<Vec<PropName>, AppError> {
+    let root = parse(body)?;
+    if !root.is("propertyupdate") {
+        return Err(AppError::BadRequest(
+            "expected DAV:propertyupdate".to_string(),
+        ));
+    }
+
+    let props: Vec<PropName> = root
+        .children
+        .iter()
+        .filter(|update| update.is("set") || update.is("remove"))
+        .filter_map(|update| update.child("prop"))
+        .flat_map(|prop| prop.children.iter().map(Element::prop_name))
+        .collect();
+    if props.is_empty() {
+        return Err(AppError::BadRequest("no properties to update".to_string()));
+    }
+    Ok(props)
+}
+
+/// A LOCK request body.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LockInfo {
+    pub shared: bool,
+    /// The `DAV:owner` element, echoed back in lock discovery.
+    pub owner: Option<String>,
+}
+
+pub fn parse_lockinfo(body: &[u8]) -> Result<LockInfo, AppError> {
+    let root = parse(body)?;
+    if !root.is("lockinfo") {
+        return Err(AppError::BadRequest("expected DAV:lockinfo".to_string()));
+    }
+
+    let scope = root
+        .child("lockscope")
+        .and_then(|scope| scope.children.first())
+        .ok_or_else(|| AppError::BadRequest("missing lockscope".to_string()))?;
+    let shared = match scope {
+        s if s.is("exclusive") => false,
+        s if s.is("shared") => true,
+        _ => return Err(AppError::BadRequest("unknown lockscope".to_string())),
+    };
+
+    let is_write = root
+        .child("locktype")
+        .and_then(|t| t.children.first())
+        .is_some_and(|t| t.is("write"));
+    if !is_write {
+        return Err(AppError::BadRequest(
+            "only write locks are supported".to_string(),
+        ));
+    }
+
+    Ok(LockInfo {
+        shared,
+        owner: root.child("owner").map(Element::to_xml),
+    })
+}
+
+/// Builds a `DAV:multistatus` response body.
+pub struct Multistatus {
+    out: String,
+}
+
+impl Multistatus {
+    pub fn new() -> Self {
+        Self {
+            out: String::from(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">",
+            ),
+        }
+    }
+
+    /// A response with one `propstat` per status. Each property is
+    /// already written out.
+    pub fn response(&mut self, href: &str, propstats: &[(u16, Vec<String>)]) {
+        self.out.push_str("<D:response><D:href>");
+        self.out.push_str(&escape(href));
+        self.out.push_str("</D:href>");
+        for (status, props) in propstats.iter().filter(|(_, p)| !p.is_empty()) {
+            self.out.push_str("<D:propstat><D:prop>");
+            for prop in props {
+                self.out.push_str(prop);
+            }
+            self.out.push_str("</D:prop>");
+            self.out.push_str(&status_line(*status));
+            self.out.push_str("</D:propstat>");
+        }
+        self.out.push_str("</D:response>");
+    }
+
+    pub fn finish(mut self) -> String {
+        self.out.push_str("</D:multistatus>");
+        self.out
+    }
+}
+
+fn status_line(status: u16) -> String {
+    let reason = axum::http::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|s| s.canonical_reason())
+        .unwrap_or("");
+    format!("<D:status>HTTP/1.1 {} {}</D:status>", status, reason)
+}
+
+/// Writes a property with already escaped content (empty for none).
+pub fn property(name: &PropName, content: &str) -> String {
+    let (ns, local) = name;
+    let open = if ns == DAV_NS {
+        format!("D:{}", local)
+    } else {
+        format!("x:{} xmlns:x=\"{}\"", local, escape(ns))
+    };
+    let close = if ns == DAV_NS {
+        format!("D:{}", local)
+    } else {
+        format!("x:{}", local)
+    };
+
+    if content.is_empty() {
+        format!("<{}/>", open)
+    } else {
+        format!("<{}>{}</{}>", open, content, close)
+    }
+}
+
+pub fn text(s: &str) -> String {
+    escape(s).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dav(name: &str) -> PropName {
+        (DAV_NS.to_string(), name.to_string())
+    }
+
+    #[test]
+    fn test_parse_propfind() {
+        assert_eq!(parse_propfind(b"").unwrap(), Propfind::AllProp);
+        assert_eq!(
+            parse_propfind(b"<propfind xmlns='DAV:'><propname/></propfind>").unwrap(),
+            Propfind::PropName
+        );
+
+        let body = br#"<?xml version="1.0"?>
+            <D:propfind xmlns:D="DAV:" xmlns:z="http://example.com/ns">
+              <D:prop><D:getcontentlength/><z:color/></D:prop>
+            </D:propfind>"#;
+        assert_eq!(
+            parse_propfind(body).unwrap(),
+            Propfind::Prop(vec![
+                dav("getcontentlength"),
+                ("http://example.com/ns".to_string(), "color".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_xml() {
+        for body in [
+            &b"<D:propfind xmlns:D='DAV:'><D:prop>"[..],
+            b"<D:propfind><D:allprop/></D:propfind>",
+            b"<propfind xmlns='other'><allprop/></propfind>",
+            b"not xml",
+        ] {
+            assert!(parse_propfind(body).is_err(), "{:?}", body);
+        }
+    }
+
+    #[test]
+    fn test_parse_lockinfo() {
+        let body = br#"<D:lockinfo xmlns:D="DAV:">
+              <D:lockscope><D:shared/></D:lockscope>
+              <D:locktype><D:write/></D:locktype>
+              <D:owner><D:href>mailto:pi@example.com</D:href></D:owner>
+            </D:lockinfo>"#;
+        assert_eq!(
+            parse_lockinfo(body).unwrap(),
+            LockInfo {
+                shared: true,
+                owner: Some("<D:owner><D:href>mailto:pi@example.com</D:href></D:owner>".into()),
+            }
+        );
+
+        let read_lock = br#"<lockinfo xmlns="DAV:"><lockscope><exclusive/></lockscope>
+            <locktype><read/></locktype></lockinfo>"#;
+        assert!(parse_lockinfo(read_lock).is_err());
+    }
+
+    #[test]
+    fn test_parse_proppatch() {
+        let body = br#"<D:propertyupdate xmlns:D="DAV:" xmlns:Z="urn:z">
+              <D:set><D:prop><Z:author>Pi &amp; co</Z:author></D:prop></D:set>
+              <D:remove><D:prop><D:displayname/></D:prop></D:remove>
+            </D:propertyupdate>"#;
+        assert_eq!(
+            parse_proppatch(body).unwrap(),
+            vec![
+                ("urn:z".to_string(), "author".to_string()),
+                dav("displayname")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multistatus() {
+        let mut ms = Multistatus::new();
+        ms.response(
+            "/dav/a%20b.mp3",
+            &[
+                (200, vec![property(&dav("getcontentlength"), "3")]),
+                (404, vec![property(&("urn:z".into(), "color".into()), "")]),
+                (403, vec![]),
+            ],
+        );
+        let xml = ms.finish();
+        assert!(xml.contains("<D:href>/dav/a%20b.mp3</D:href>"));
+        assert!(xml.contains("<D:getcontentlength>3</D:getcontentlength>"));
+        assert!(xml.contains("<x:color xmlns:x=\"urn:z\"/>"));
+        assert!(xml.contains("HTTP/1.1 404 Not Found"));
+        assert!(!xml.contains("403"));
+        assert!(parse(xml.as_bytes()).is_ok());
+    }
+}
@@ -1,5 +1,5 @@
 use axum::{
-    http::StatusCode,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -10,6 +10,9 @@ pub enum AppError {
     #[error("bad request: {0}")]
     BadRequest(String),
 
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+
     #[error("forbidden: path access denied")]
     Forbidden,
 
@@ -19,12 +22,30 @@ pub enum AppError {
     #[error("conflict: {0}")]
     Conflict(String),
 
+    #[error("precondition failed: {0}")]
+    PreconditionFailed(String),
+
+    /// A WebDAV lock held by someone else (or not submitted).
+    #[error("locked: {0}")]
+    Locked(String),
+
     #[error("payload too large")]
     PayloadTooLarge,
 
     #[error("unsupported media type: {0}")]
     UnsupportedMediaType(String),
 
+    /// Not enough free space, or a quota would be exceeded.
+    #[error("insufficient storage: {0}")]
+    InsufficientStorage(String),
+
+    #[error("too many requests: {0}")]
+    TooManyRequests(String),
+
+    /// Carries the file size for the `Content-Range: bytes */size` header.
+    #[error("range not satisfiable")]
+    RangeNotSatisfiable(u64),
+
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
 
@@ -32,19 +53,33 @@ pub enum AppError {
     Internal(String),
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        let (status, message) = match &self {
+impl AppError {
+    /// Status code and the message shown to clients. IO and internal
+    /// errors are logged here and reported without details.
+    pub fn status_and_message(&self) -> (StatusCode, String) {
+        match self {
             AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.clone()),
+            AppError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg.clone()),
             AppError::Forbidden => (StatusCode::FORBIDDEN, "access denied".to_string()),
             AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg.clone()),
             AppError::Conflict(msg) => (StatusCode::CONFLICT, msg.clone()),
+            AppError::PreconditionFailed(msg) => (StatusCode::PRECONDITION_FAILED, msg.clone()),
+            AppError::Locked(msg) => (StatusCode::LOCKED, msg.clone()),
             AppError::PayloadTooLarge => {
                 (StatusCode::PAYLOAD_TOO_LARGE, "file too large".to_string())
             }
             AppError::UnsupportedMediaType(msg) => {
                 (StatusCode::UNSUPPORTED_MEDIA_TYPE, msg.clone())
             }
+            AppError::InsufficientStorage(msg) => (StatusCode::INSUFFICIENT_STORAGE, msg.clone()),
+            AppError::TooManyRequests(msg) => (StatusCode::TOO_MANY_REQUESTS, msg.clone()),
+            AppError::Io(e) if is_storage_full(e) => {
+                (StatusCode::INSUFFICIENT_STORAGE, "disk full".to_string())
+            }
+            AppError::RangeNotSatisfiable(_) => (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                "range not satisfiable".to_string(),
+            ),
             AppError::Io(e) => {
                 tracing::error!("IO error: {:?}", e);
                 (
@@ -59,8 +94,33 @@ impl IntoResponse for AppError {
                     "internal error".to_string(),
                 )
             }
-        };
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let (status, message) = self.status_and_message();
+        let body = Json(json!({"error": message}));
+
+        if let AppError::RangeNotSatisfiable(size) = self {
+            return (
+                status,
+                [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+                body,
+            )
+                .into_response();
+        }
 
-        (status, Json(json!({"error": message}))).into_response()
+        (status, body).into_response()
     }
 }
+
+/// ENOSPC / EDQUOT: the disk (or a filesystem quota) ran out mid-write.
+fn is_storage_full(e: &std::io::Error) -> bool {
+    use nix::errno::Errno;
+    matches!(
+        e.raw_os_error().map(Errno::from_raw),
+        Some(Errno::ENOSPC | Errno::EDQUOT)
+    )
+}
@@ -0,0 +1,449 @@
+//! Live change notifications.
+//!
+//! The root is watched with inotify (through `notify`). Raw events are
+//! turned into root-relative `ChangeEvent`s and handed to every subscriber
+//! through a broadcast channel; filtering by what each client may see is
+//! left to the handler. Along the way internal files are hidden, so an
+//! upload landing (a temp file renamed into place) shows up as `created`
+//! and a delete into the trash as `deleted`, the two halves of a rename
+//! are paired up, and bursts of writes to one file are folded into a
+//! single `modified`.
+//!
+//! The same bus carries the progress of multipart uploads, published from
+//! the upload loop through an `UploadReporter`.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+
+use crate::models::{ChangeEvent, ChangeKind, UploadProgress, UploadState};
+use crate::security::has_internal_component;
+use crate::util;
+
+/// How often collected events are sent out.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Events a slow client may fall behind by before it is told to resync.
+const CAPACITY: usize = 1024;
+
+/// How often a running upload reports its byte count.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notice {
+    Change(ChangeEvent),
+    Upload(UploadProgress),
+    /// Events were lost; clients should re-list what they show.
+    Resync,
+}
+
+pub struct EventBus {
+    tx: broadcast::Sender<Notice>,
+    closed: CancellationToken,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(CAPACITY);
+        Self {
+            tx,
+            closed: CancellationToken::new(),
+        }
+    }
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> broadcast::Receiver<Notice> {
+        self.tx.subscribe()
+    }
+
+    pub fn publish(&self, notice: Notice) {
+        // No subscribers is fine
+        let _ = self.tx.send(notice);
+    }
+
+    /// Ends every open stream, so shutdown doesn't wait on clients that
+    /// never disconnect by themselves.
+    pub fn close(&self) {
+        self.closed.cancel();
+    }
+
+    /// Resolves once the bus is closed.
+    pub async fn closed(&self) {
+        self.closed.cancelled().await
+    }
+}
+
+/// Starts watching `root` (canonical) and publishing its changes on `bus`.
+/// Events stop when the returned watcher is dropped.
+///
+/// Running out of inotify watches is logged rather than returned: the
+/// directories watched so far keep working.
+pub fn watch(root: &Path, bus: Arc<EventBus>) -> notify::Result<RecommendedWatcher> {
+    let (raw_tx, mut raw_rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res| {
+        let _ = raw_tx.send(res);
+    })?;
+
+    let mut translator = Translator::new(root.to_path_buf());
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                res = raw_rx.recv() => match res {
+                    Some(Ok(event)) => translator.feed(event, Instant::now()),
+                    Some(Err(e)) => tracing::warn!("File watcher error: {}", e),
+                    None => break,
+                },
+                _ = interval.tick() => {
+                    for notice in translator.flush(Instant::now()) {
+                        bus.publish(notice);
+                    }
+                }
+            }
+        }
+    });
+
+    if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+        if matches!(e.kind, notify::ErrorKind::MaxFilesWatch) {
+            tracing::warn!(
+                "Live updates incomplete: out of inotify watches \
+                 (raise fs.inotify.max_user_watches)"
+            );
+        } else {
+            return Err(e);
+        }
+    }
+
+    Ok(watcher)
+}
+
+/// Publishes the progress of one incoming file, at most every
+/// `PROGRESS_INTERVAL`. Dropped before `finish`, it reports the upload
+/// as failed.
+pub struct UploadReporter {
+    bus: Arc<EventBus>,
+    progress: UploadProgress,
+    published: Instant,
+}
+
+impl UploadReporter {
+    pub fn start(bus: Arc<EventBus>, path: String, username: String) -> Self {
+        let reporter = Self {
+            bus,
+            progress: UploadProgress {
+                id: util::random_id(),
+                path,
+                username,
+                bytes: 0,
+                status: UploadState::Uploading,
+            },
+            published: Instant::now(),
+        };
+        reporter.publish();
+        reporter
+    }
+
+    pub fn advance(&mut self, n: u64) {
+        self.progress.bytes += n;
+        if self.published.elapsed() >= PROGRESS_INTERVAL {
+            self.published = Instant::now();
+            self.publish();
+        }
+    }
+
+    /// Reports the file as fully received.
+    pub fn finish(mut self) {
+        self.progress.status = UploadState::Received;
+        self.publish();
+    }
+
+    fn publish(&self) {
+        self.bus.publish(Notice::Upload(self.progress.clone()));
+    }
+}
+
+impl Drop for UploadReporter {
+    fn drop(&mut self) {
+        if self.progress.status == UploadState::Uploading {
+            self.progress.status = UploadState::Failed;
+            self.publish();
+        }
+    }
+}
+
+/// Turns raw watcher events into `Notice`s, collected until `flush`.
+struct Translator {
+    root: PathBuf,
+    pending: Vec<Notice>,
+    /// Paths with a `created` or `modified` already pending.
+    touched: HashSet<String>,
+    /// First halves of renames by inotify cookie, waiting for the second.
+    /// `None` for internal files.
+    moved_from: HashMap<usize, (Option<String>, Instant)>,
+}
+
+impl Translator {
+    fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            pending: Vec::new(),
+            touched: HashSet::new(),
+            moved_from: HashMap::new(),
+        }
+    }
+
+    fn feed(&mut self, event: notify::Event, now: Instant) {
+        if event.need_rescan() {
+            self.pending.push(Notice::Resync);
+            return;
+        }
+
+        let path = match event.paths.first() {
+            Some(path) => self.relative(path),
+            None => return,
+        };
+
+        match event.kind {
+            EventKind::Create(_) => self.push(ChangeKind::Created, path, None),
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                // Without a cookie this is a watched folder noticing its
+                // own move, which its parent reports as well
+                if let Some(cookie) = event.tracker() {
+                    self.moved_from.insert(cookie, (path, now));
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                let from = event
+                    .tracker()
+                    .and_then(|cookie| self.moved_from.remove(&cookie));
+                match from {
+                    Some((Some(from), _)) if path.is_some() => {
+                        self.push(ChangeKind::Renamed, Some(from), path)
+                    }
+                    Some((Some(from), _)) => self.push(ChangeKind::Deleted, Some(from), None),
+                    _ => self.push(ChangeKind::Created, path, None),
+                }
+            }
+            EventKind::Modify(ModifyKind::Data(_))
+            | EventKind::Modify(ModifyKind::Any)
+            | EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                self.push(ChangeKind::Modified, path, None)
+            }
+            EventKind::Remove(_) => self.push(ChangeKind::Deleted, path, None),
+            // Renames are handled through their halves; metadata and
+            // reads don't change what a listing shows
+            _ => {}
+        }
+    }
+
+    /// Returns what was collected. Renames whose second half hasn't shown
+    /// up within a flush interval moved out of the root.
+    fn flush(&mut self, now: Instant) -> Vec<Notice> {
+        let expired: Vec<usize> = self
+            .moved_from
+            .iter()
+            .filter(|(_, (_, at))| now.duration_since(*at) >= FLUSH_INTERVAL)
+            .map(|(cookie, _)| *cookie)
+            .collect();
+        for cookie in expired {
+            if let Some((from, _)) = self.moved_from.remove(&cookie) {
+                self.push(ChangeKind::Deleted, from, None);
+            }
+        }
+
+        self.touched.clear();
+        std::mem::take(&mut self.pending)
+    }
+
+    fn push(&mut self, kind: ChangeKind, path: Option<String>, to: Option<String>) {
+        let Some(path) = path else {
+            return;
+        };
+        match kind {
+            ChangeKind::Created | ChangeKind::Modified => {
+                if !self.touched.insert(path.clone()) && kind == ChangeKind::Modified {
+                    return;
+                }
+            }
+            ChangeKind::Deleted | ChangeKind::Renamed => {
+                self.touched.remove(&path);
+            }
+        }
+        self.pending
+            .push(Notice::Change(ChangeEvent { kind, path, to }));
+    }
+
+    /// Root-relative form of a watched path, or `None` for the root itself
+    /// and internal files.
+    fn relative(&self, path: &Path) -> Option<String> {
+        let rel = path.strip_prefix(&self.root).ok()?;
+        if rel.as_os_str().is_empty() || has_internal_component(rel) {
+            return None;
+        }
+        Some(rel.to_string_lossy().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, DataChange, Flag, RemoveKind};
+    use notify::Event;
+
+    fn translator() -> Translator {
+        Translator::new(PathBuf::from("/media"))
+    }
+
+    fn event(kind: EventKind, path: &str) -> Event {
+        Event::new(kind).add_path(PathBuf::from("/media").join(path))
+    }
+
+    fn rename(mode: RenameMode, path: &str, cookie: usize) -> Event {
+        event(EventKind::Modify(ModifyKind::Name(mode)), path).set_tracker(cookie)
+    }
+
+    fn change(kind: ChangeKind, path: &str, to: Option<&str>) -> Notice {
+        Notice::Change(ChangeEvent {
+            kind,
+            path: path.into(),
+            to: to.map(String::from),
+        })
+    }
+
+    #[test]
+    fn test_basic_events() {
+        let mut t = translator();
+        let now = Instant::now();
+        t.feed(event(EventKind::Create(CreateKind::Folder), "new"), now);
+        t.feed(event(EventKind::Remove(RemoveKind::File), "a/b.mp3"), now);
+        t.feed(event(EventKind::Access(AccessKind::Read), "c.mp3"), now);
+        assert_eq!(
+            t.flush(now),
+            vec![
+                change(ChangeKind::Created, "new", None),
+                change(ChangeKind::Deleted, "a/b.mp3", None),
+            ]
+        );
+        assert!(t.flush(now).is_empty());
+    }
+
+    #[test]
+    fn test_writes_are_folded() {
+        let mut t = translator();
+        let now = Instant::now();
+        let write = EventKind::Modify(ModifyKind::Data(DataChange::Any));
+        t.feed(event(EventKind::Create(CreateKind::File), "a.txt"), now);
+        t.feed(event(write, "a.txt"), now);
+        t.feed(event(write, "b.txt"), now);
+        t.feed(event(write, "b.txt"), now);
+        assert_eq!(
+            t.flush(now),
+            vec![
+                change(ChangeKind::Created, "a.txt", None),
+                change(ChangeKind::Modified, "b.txt", None),
+            ]
+        );
+
+        t.feed(event(write, "b.txt"), now);
+        assert_eq!(
+            t.flush(now),
+            vec![change(ChangeKind::Modified, "b.txt", None)]
+        );
+    }
+
+    #[test]
+    fn test_rename_halves_are_paired() {
+        let mut t = translator();
+        let now = Instant::now();
+        t.feed(rename(RenameMode::From, "a.txt", 7), now);
+        t.feed(rename(RenameMode::To, "dir/b.txt", 7), now);
+        assert_eq!(
+            t.flush(now),
+            vec![change(ChangeKind::Renamed, "a.txt", Some("dir/b.txt"))]
+        );
+    }
+
+    #[test]
+    fn test_internal_files_are_hidden() {
+        let mut t = translator();
+        let now = Instant::now();
+
+        // Upload: temp file written, then renamed into place
+        let tmp = "music/.monkeyarch-tmp-1";
+        t.feed(event(EventKind::Create(CreateKind::File), tmp), now);
+        t.feed(event(EventKind::Modify(ModifyKind::Any), tmp), now);
+        t.feed(rename(RenameMode::From, tmp, 1), now);
+        t.feed(rename(RenameMode::To, "music/a.mp3", 1), now);
+
+        // Delete into the trash
+        t.feed(rename(RenameMode::From, "music/b.mp3", 2), now);
+        t.feed(rename(RenameMode::To, ".monkeyarch/trash/x.item", 2), now);
+
+        assert_eq!(
+            t.flush(now),
+            vec![
+                change(ChangeKind::Created, "music/a.mp3", None),
+                change(ChangeKind::Deleted, "music/b.mp3", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_moves_across_the_root_boundary() {
+        let mut t = translator();
+        let now = Instant::now();
+
+        // Moved in from outside: only the second half
+        t.feed(rename(RenameMode::To, "in.txt", 1), now);
+        // Moved out: only the first half, reported once it goes stale
+        t.feed(rename(RenameMode::From, "out.txt", 2), now);
+        assert_eq!(
+            t.flush(now),
+            vec![change(ChangeKind::Created, "in.txt", None)]
+        );
+        assert_eq!(
+            t.flush(now + FLUSH_INTERVAL),
+            vec![change(ChangeKind::Deleted, "out.txt", None)]
+        );
+    }
+
+    #[test]
+    fn test_upload_progress() {
+        let bus = Arc::new(EventBus::default());
+        let mut rx = bus.subscribe();
+        let status = |notice: Notice| match notice {
+            Notice::Upload(progress) => (progress.bytes, progress.status),
+            other => panic!("unexpected {:?}", other),
+        };
+
+        let mut upload = UploadReporter::start(bus.clone(), "a.mp3".into(), "pi".into());
+        upload.advance(10);
+        upload.advance(5);
+        upload.finish();
+        assert_eq!(status(rx.try_recv().unwrap()), (0, UploadState::Uploading));
+        // Throttled in between
+        assert_eq!(status(rx.try_recv().unwrap()), (15, UploadState::Received));
+        assert!(rx.try_recv().is_err());
+
+        let mut upload = UploadReporter::start(bus, "b.mp3".into(), "pi".into());
+        upload.advance(3);
+        drop(upload);
+        assert_eq!(status(rx.try_recv().unwrap()), (0, UploadState::Uploading));
+        assert_eq!(status(rx.try_recv().unwrap()), (3, UploadState::Failed));
+    }
+
+    #[test]
+    fn test_overflow_asks_for_resync() {
+        let mut t = translator();
+        let now = Instant::now();
+        t.feed(Event::new(EventKind::Other).set_flag(Flag::Rescan), now);
+        assert_eq!(t.flush(now), vec![Notice::Resync]);
+    }
+}
@@ -0,0 +1,549 @@
+//! Crash-safe file writes and the file operations built on them.
+//!
+//! New content goes to a hidden temp file next to its destination and is
+//! renamed into place only once it is complete and on disk. Readers see
+//! either the old file or the new one, never a half-written mix, and a
+//! failed overwrite leaves the original untouched.
+//!
+//! The same staging backs `copy_file`, and `move_across`, which copies
+//! between filesystems (a USB stick below the root) and checks the copy
+//! with `copy_verified` before removing the source. `unique_name` picks
+//! the "name (1).ext" names for the rename conflict strategy, and
+//! `sweep_temp_files` clears temp files a crash left behind at startup.
+
+use std::fs::Metadata;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use nix::errno::Errno;
+use nix::sys::stat::{utimensat, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::jobs;
+use crate::security::INTERNAL_DIR;
+use crate::util;
+
+/// Prefix of temp files; covered by `security::is_internal_name`.
+pub const TEMP_PREFIX: &str = ".monkeyarch-tmp-";
+
+/// A file being written, removed again unless committed.
+pub struct StagedFile {
+    file: File,
+    temp_path: PathBuf,
+    dest: PathBuf,
+    committed: bool,
+}
+
+impl StagedFile {
+    /// Creates the temp file in `dest`'s directory, so the final rename
+    /// never crosses filesystems.
+    pub async fn create(dest: &Path) -> io::Result<Self> {
+        let dir = dest
+            .parent()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no parent directory"))?;
+
+        let temp_path = dir.join(format!("{}{}", TEMP_PREFIX, util::random_id()));
+
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+            .await?;
+
+        Ok(Self {
+            file,
+            temp_path,
+            dest: dest.to_path_buf(),
+            committed: false,
+        })
+    }
+
+    pub fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Flushes, fsyncs and renames the file into place, replacing any
+    /// existing file at the destination.
+    pub async fn commit(mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        tokio::fs::rename(&self.temp_path, &self.dest).await?;
+        self.committed = true;
+        sync_parent(&self.dest).await;
+        Ok(())
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// Fsyncs a complete file and renames it to `dest`. When `dest` is on
+/// another filesystem (a drive mounted below the root), the file is copied
+/// through a staged file instead and then removed.
+pub async fn commit_rename(from: &Path, dest: &Path) -> io::Result<()> {
+    File::open(from).await?.sync_all().await?;
+    match tokio::fs::rename(from, dest).await {
+        Ok(()) => sync_parent(dest).await,
+        Err(e) if is_cross_device(&e) => {
+            copy_file(from, dest).await?;
+            tokio::fs::remove_file(from).await?;
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+/// Copies `from` to `dest` through a staged file, so an existing `dest` is
+/// only replaced once the copy is complete. Returns the bytes copied.
+pub async fn copy_file(from: &Path, dest: &Path) -> io::Result<u64> {
+    let mut source = File::open(from).await?;
+    let mut staged = StagedFile::create(dest).await?;
+    let bytes = copy_data(&mut source, staged.file(), None).await?;
+    staged.commit().await?;
+    Ok(bytes)
+}
+
+/// Copies one file's contents, reporting progress to the running job (if
+/// any) and stopping when it is cancelled.
+async fn copy_data(
+    source: &mut File,
+    dest: &mut File,
+    mut hasher: Option<&mut crc32fast::Hasher>,
+) -> io::Result<u64> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut total = 0;
+
+    loop {
+        jobs::check_cancelled()?;
+        let n = source.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(total);
+        }
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&buf[..n]);
+        }
+        dest.write_all(&buf[..n]).await?;
+        total += n as u64;
+        jobs::report_bytes(n as u64);
+    }
+}
+
+/// True for the EXDEV error `rename` gives across filesystems.
+pub fn is_cross_device(e: &io::Error) -> bool {
+    e.raw_os_error().map(Errno::from_raw) == Some(Errno::EXDEV)
+}
+
+/// Moves `from` to `dest` on another filesystem, where `rename` fails:
+/// copies, verifies each file, then deletes the source. Mtimes and
+/// permissions are kept and symlinks are recreated, not followed. Like
+/// `rename`, an existing file or empty directory at `dest` is replaced.
+///
+/// The copy is made under a temp name next to `dest` and renamed into
+/// place once complete, so on failure `dest` and the source are left
+/// untouched and the partial copy is removed.
+pub async fn move_across(from: &Path, dest: &Path) -> io::Result<()> {
+    let meta = tokio::fs::symlink_metadata(from).await?;
+    let staged = dest.with_file_name(format!("{}{}", TEMP_PREFIX, util::random_id()));
+
+    let copied = if meta.is_dir() {
+        copy_tree(from, &staged).await
+    } else {
+        copy_entry(from, &staged, &meta).await
+    };
+    let swapped = match copied {
+        Ok(()) => tokio::fs::rename(&staged, dest).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = swapped {
+        let _ = remove_entry(&staged).await;
+        return Err(e);
+    }
+
+    remove_entry(from).await
+}
+
+/// Removes a file, symlink or whole directory tree.
+async fn remove_entry(path: &Path) -> io::Result<()> {
+    if tokio::fs::symlink_metadata(path).await?.is_dir() {
+        tokio::fs::remove_dir_all(path).await
+    } else {
+        tokio::fs::remove_file(path).await
+    }
+}
+
+async fn copy_tree(from: &Path, dest: &Path) -> io::Result<()> {
+    let mut pending = vec![(from.to_path_buf(), dest.to_path_buf())];
+    let mut dirs = Vec::new();
+
+    while let Some((src, dst)) = pending.pop() {
+        let meta = tokio::fs::symlink_metadata(&src).await?;
+        if !meta.is_dir() {
+            copy_entry(&src, &dst, &meta).await?;
+            continue;
+        }
+
+        tokio::fs::create_dir(&dst).await?;
+        jobs::report_item();
+        let mut entries = tokio::fs::read_dir(&src).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            pending.push((entry.path(), dst.join(entry.file_name())));
+        }
+        dirs.push((dst, meta));
+    }
+
+    // Innermost last created, first stamped: filling a directory bumps its mtime
+    for (dir, meta) in dirs.iter().rev() {
+        preserve_metadata(dir, meta).await?;
+    }
+    Ok(())
+}
+
+async fn copy_entry(src: &Path, dst: &Path, meta: &Metadata) -> io::Result<()> {
+    if meta.file_type().is_symlink() {
+        tokio::fs::symlink(tokio::fs::read_link(src).await?, dst).await?;
+    } else if meta.is_file() {
+        copy_verified(src, dst).await?;
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot move special file {}", src.display()),
+        ));
+    }
+    preserve_metadata(dst, meta).await?;
+    jobs::report_item();
+    Ok(())
+}
+
+/// Copies a file, then reads the copy back and compares checksums. A
+/// mismatch removes the copy.
+async fn copy_verified(src: &Path, dst: &Path) -> io::Result<()> {
+    let mut source = File::open(src).await?;
+    let mut staged = StagedFile::create(dst).await?;
+    let mut hasher = crc32fast::Hasher::new();
+    copy_data(&mut source, staged.file(), Some(&mut hasher)).await?;
+    staged.commit().await?;
+
+    if file_crc32(dst).await? != hasher.finalize() {
+        let _ = tokio::fs::remove_file(dst).await;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("verification failed for {}", dst.display()),
+        ));
+    }
+    Ok(())
+}
+
+async fn file_crc32(path: &Path) -> io::Result<u32> {
+    let mut file = File::open(path).await?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// Copies permissions and access/modification times from `meta`.
+async fn preserve_metadata(path: &Path, meta: &Metadata) -> io::Result<()> {
+    if !meta.file_type().is_symlink() {
+        tokio::fs::set_permissions(path, meta.permissions()).await?;
+    }
+
+    let atime = TimeSpec::new(meta.atime(), meta.atime_nsec());
+    let mtime = TimeSpec::new(meta.mtime(), meta.mtime_nsec());
+    utimensat(None, path, &atime, &mtime, UtimensatFlags::NoFollowSymlink)?;
+    Ok(())
+}
+
+/// Makes a rename durable. Best effort: not every filesystem (e.g. some
+/// FAT drivers) supports fsync on a directory.
+async fn sync_parent(path: &Path) {
+    if let Some(dir) = path.parent() {
+        if let Ok(dir) = File::open(dir).await {
+            let _ = dir.sync_all().await;
+        }
+    }
+}
+
+/// Picks the first free name of the form `song (1).mp3`, `song (2).mp3`...
+/// A name that already carries a counter keeps counting from it.
+pub fn unique_name(filename: &str, taken: impl Fn(&str) -> bool) -> String {
+    let path = Path::new(filename);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+
+    let (base, start) = split_counter(&stem);
+    (start + 1..)
+        .map(|n| format!("{} ({}){}", base, n, ext))
+        .find(|candidate| !taken(candidate))
+        .unwrap()
+}
+
+/// Splits `"song (3)"` into `("song", 3)`; other names count from 0.
+fn split_counter(stem: &str) -> (&str, u64) {
+    stem.strip_suffix(')')
+        .and_then(|s| s.rsplit_once(" ("))
+        .and_then(|(base, n)| Some((base, n.parse().ok()?)))
+        .filter(|(base, _)| !base.is_empty())
+        .unwrap_or((stem, 0))
+}
+
+/// Removes temp files left behind by a crash or power loss. Files written
+/// since `started` belong to writes of the running server and are kept,
+/// so the sweep can run while requests are served. A folder that can't be
+/// read is logged and skipped; only an unreadable root is an error.
+pub fn sweep_temp_files(root: &Path, started: SystemTime) -> io::Result<usize> {
+    let mut removed = 0;
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if dir == root => return Err(e),
+            Err(e) => {
+                tracing::warn!("Skipping {:?} in temp file sweep: {}", dir, e);
+                continue;
+            }
+        };
+
+        for entry in entries {
+            let entry = entry.and_then(|entry| {
+                let file_type = entry.file_type()?;
+                Ok((entry, file_type))
+            });
+            let (entry, file_type) = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    tracing::warn!("Skipping an entry of {:?} in temp file sweep: {}", dir, e);
+                    continue;
+                }
+            };
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+
+            // Staged files, and the copies of interrupted cross-filesystem moves
+            if name.starts_with(TEMP_PREFIX) {
+                let stale = entry
+                    .metadata()
+                    .and_then(|m| m.modified())
+                    .is_ok_and(|modified| modified < started);
+                if !stale {
+                    continue;
+                }
+                let result = if file_type.is_dir() {
+                    std::fs::remove_dir_all(entry.path())
+                } else {
+                    std::fs::remove_file(entry.path())
+                };
+                match result {
+                    Ok(()) => removed += 1,
+                    Err(e) => tracing::warn!("Could not remove {:?}: {}", entry.path(), e),
+                }
+            } else if file_type.is_dir() && name != INTERNAL_DIR {
+                // file_type() doesn't follow symlinks, so no cycles
+                pending.push(entry.path());
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn temp_files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|e| {
+                e.as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(TEMP_PREFIX)
+            })
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_commit_replaces_atomically() {
+        let tmp = TempDir::new().unwrap();
+        let dest = tmp.path().join("song.mp3");
+        fs::write(&dest, "old").unwrap();
+
+        let mut staged = StagedFile::create(&dest).await.unwrap();
+        staged.file().write_all(b"new").await.unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"old");
+        assert_eq!(temp_files(tmp.path()), 1);
+
+        staged.commit().await.unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"new");
+        assert_eq!(temp_files(tmp.path()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_copy_file() {
+        let tmp = TempDir::new().unwrap();
+        let from = tmp.path().join("a.mp3");
+        let dest = tmp.path().join("b.mp3");
+        fs::write(&from, "content").unwrap();
+        fs::write(&dest, "old").unwrap();
+
+        assert_eq!(copy_file(&from, &dest).await.unwrap(), 7);
+        assert_eq!(fs::read(&dest).unwrap(), b"content");
+        assert_eq!(fs::read(&from).unwrap(), b"content");
+        assert_eq!(temp_files(tmp.path()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_move_across_keeps_tree_and_mtimes() {
+        let tmp = TempDir::new().unwrap();
+        let from = tmp.path().join("album");
+        fs::create_dir_all(from.join("cd1")).unwrap();
+        fs::write(from.join("cd1/01.mp3"), "one").unwrap();
+        std::os::unix::fs::symlink("cd1/01.mp3", from.join("first.mp3")).unwrap();
+
+        let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        for path in [from.join("cd1/01.mp3"), from.join("cd1"), from.clone()] {
+            fs::File::open(&path).unwrap().set_modified(old).unwrap();
+        }
+
+        let dest = tmp.path().join("moved");
+        fs::create_dir(&dest).unwrap();
+        move_across(&from, &dest).await.unwrap();
+
+        assert!(!from.exists());
+        assert_eq!(fs::read(dest.join("cd1/01.mp3")).unwrap(), b"one");
+        assert_eq!(fs::read(dest.join("first.mp3")).unwrap(), b"one");
+        assert!(fs::symlink_metadata(dest.join("first.mp3"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        for path in [dest.join("cd1/01.mp3"), dest.join("cd1"), dest.clone()] {
+            assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), old);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_move_across_refuses_non_empty_dir() {
+        let tmp = TempDir::new().unwrap();
+        let from = tmp.path().join("a");
+        let dest = tmp.path().join("b");
+        fs::create_dir(&from).unwrap();
+        fs::create_dir(&dest).unwrap();
+        fs::write(dest.join("keep.mp3"), "x").unwrap();
+
+        assert!(move_across(&from, &dest).await.is_err());
+        assert!(from.is_dir());
+        assert!(dest.join("keep.mp3").exists());
+    }
+
+    #[tokio::test]
+    async fn test_move_across_failure_leaves_dest() {
+        let tmp = TempDir::new().unwrap();
+        let from = tmp.path().join("album");
+        fs::create_dir(&from).unwrap();
+        fs::write(from.join("01.mp3"), "one").unwrap();
+        // A socket can't be copied, so the move fails halfway
+        let _socket = std::os::unix::net::UnixListener::bind(from.join("socket")).unwrap();
+        let dest = tmp.path().join("moved");
+        fs::create_dir(&dest).unwrap();
+
+        assert!(move_across(&from, &dest).await.is_err());
+        assert!(dest.is_dir());
+        assert_eq!(fs::read(from.join("01.mp3")).unwrap(), b"one");
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_move_across_symlink_replaces_dest() {
+        let tmp = TempDir::new().unwrap();
+        let from = tmp.path().join("link");
+        std::os::unix::fs::symlink("target.mp3", &from).unwrap();
+        let dest = tmp.path().join("song.mp3");
+        fs::write(&dest, "old").unwrap();
+
+        move_across(&from, &dest).await.unwrap();
+        assert!(fs::symlink_metadata(&from).is_err());
+        assert_eq!(fs::read_link(&dest).unwrap(), Path::new("target.mp3"));
+        assert_eq!(temp_files(tmp.path()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_drop_keeps_original() {
+        let tmp = TempDir::new().unwrap();
+        let dest = tmp.path().join("song.mp3");
+        fs::write(&dest, "old").unwrap();
+
+        let mut staged = StagedFile::create(&dest).await.unwrap();
+        staged.file().write_all(b"partial").await.unwrap();
+        drop(staged);
+
+        assert_eq!(fs::read(&dest).unwrap(), b"old");
+        assert_eq!(temp_files(tmp.path()), 0);
+    }
+
+    #[test]
+    fn test_unique_name() {
+        let taken = ["song.mp3", "song (1).mp3", "Album", "x (9).jpg"];
+        let taken = |name: &str| taken.contains(&name);
+
+        assert_eq!(unique_name("song.mp3", taken), "song (2).mp3");
+        assert_eq!(unique_name("song (1).mp3", taken), "song (2).mp3");
+        assert_eq!(unique_name("Album", taken), "Album (1)");
+        assert_eq!(unique_name("x (9).jpg", taken), "x (10).jpg");
+        assert_eq!(unique_name(".hidden", taken), ".hidden (1)");
+        assert_eq!(unique_name("(2).mp3", taken), "(2) (1).mp3");
+    }
+
+    #[test]
+    fn test_sweep_temp_files() {
+        let tmp = TempDir::new().unwrap();
+        fs::create_dir_all(tmp.path().join("a/b")).unwrap();
+        fs::create_dir_all(tmp.path().join(INTERNAL_DIR)).unwrap();
+        fs::write(tmp.path().join(".monkeyarch-tmp-1"), "").unwrap();
+        fs::write(tmp.path().join("a/b/.monkeyarch-tmp-2"), "").unwrap();
+        fs::write(tmp.path().join("a/keep.mp3"), "").unwrap();
+        fs::write(tmp.path().join(INTERNAL_DIR).join(".monkeyarch-tmp-3"), "").unwrap();
+        // The copy of a move that was cut off
+        fs::create_dir_all(tmp.path().join("a/.monkeyarch-tmp-4/cd1")).unwrap();
+
+        // All written after the server started: uploads in progress
+        assert_eq!(
+            sweep_temp_files(tmp.path(), SystemTime::UNIX_EPOCH).unwrap(),
+            0
+        );
+
+        let started = SystemTime::now() + std::time::Duration::from_secs(60);
+        assert_eq!(sweep_temp_files(tmp.path(), started).unwrap(), 3);
+        assert!(tmp.path().join("a/keep.mp3").exists());
+        assert!(!tmp.path().join("a/.monkeyarch-tmp-4").exists());
+        assert!(tmp
+            .path()
+            .join(INTERNAL_DIR)
+            .join(".monkeyarch-tmp-3")
+            .exists());
+    }
+}
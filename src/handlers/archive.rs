@@ -0,0 +1,115 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::Response,
+    Extension, Json,
+};
+
+use crate::access::{self, Access};
+use crate::archive::{self, Source, Walker};
+use crate::auth::AuthUser;
+use crate::error::AppError;
+use crate::headers::content_disposition;
+use crate::models::{ArchiveQuery, ArchiveRequest};
+use crate::security::validate_existing;
+use crate::AppState;
+
+/// `GET /api/archive?path=...`: download one file or directory as an archive.
+pub async fn download_archive(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(query): Query<ArchiveQuery>,
+) -> Result<Response, AppError> {
+    let source = resolve_source(&state, &user, &query.path)?;
+    let name = source.name.clone();
+
+    build_response(&state, user, vec![source], query.format, &name).await
+}
+
+/// `POST /api/archive`: download a multi-selection as one archive.
+pub async fn download_selection(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<ArchiveRequest>,
+) -> Result<Response, AppError> {
+    let sources = resolve_selection(&state, &user, &req.paths)?;
+    let name = req.name.unwrap_or_else(|| "archive".to_string());
+    build_response(&state, user, sources, req.format, &name).await
+}
+
+/// Validates a multi-selection, naming each entry uniquely. Shared with
+/// archive jobs.
+pub fn resolve_selection(
+    state: &AppState,
+    user: &AuthUser,
+    paths: &[String],
+) -> Result<Vec<Source>, AppError> {
+    if paths.is_empty() {
+        return Err(AppError::BadRequest("no paths selected".to_string()));
+    }
+
+    let mut sources: Vec<Source> = Vec::with_capacity(paths.len());
+    for user_path in paths {
+        let mut source = resolve_source(state, user, user_path)?;
+        if sources.iter().any(|s| s.path == source.path) {
+            continue;
+        }
+
+        // Two selected items may share a name (e.g. from different folders)
+        let base = source.name.clone();
+        let mut n = 2;
+        while sources.iter().any(|s| s.name == source.name) {
+            source.name = format!("{} ({})", base, n);
+            n += 1;
+        }
+
+        sources.push(source);
+    }
+
+    Ok(sources)
+}
+
+fn resolve_source(state: &AppState, user: &AuthUser, user_path: &str) -> Result<Source, AppError> {
+    let path = access::validate(
+        &state.config,
+        user,
+        user_path,
+        Access::Read,
+        validate_existing,
+    )?;
+
+    // The root itself has no useful name inside the archive
+    let rel = access::relative_to_root(&state.config, &path)?;
+    let name = match rel.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => "archive".to_string(),
+    };
+
+    Ok(Source { name, path })
+}
+
+async fn build_response(
+    state: &AppState,
+    user: AuthUser,
+    sources: Vec<Source>,
+    format: archive::ArchiveFormat,
+    name: &str,
+) -> Result<Response, AppError> {
+    let root = state.config.root_directory.canonicalize()?;
+    let walker = Walker::new(root, state.config.access_rules.clone(), user, sources).await?;
+
+    let filename = format!("{}.{}", name, format.extension());
+    tracing::info!("Streaming archive {}", filename);
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.mime())
+        .header(
+            header::CONTENT_DISPOSITION,
+            content_disposition(true, &filename),
+        )
+        .header(header::CACHE_CONTROL, "no-store")
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .body(archive::stream(format, walker))
+        .unwrap())
+}
@@ -0,0 +1,89 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+
+use crate::auth::AuthUser;
+use crate::error::AppError;
+use crate::handlers::{copy, delete, mkdir, move_file};
+use crate::models::{
+    BatchOnError, BatchOperation, BatchRequest, BatchResponse, BatchResult, BatchStatus,
+};
+use crate::AppState;
+
+/// Runs several operations in one request, in order, through the same
+/// handlers as the single-operation endpoints.
+pub async fn run_batch(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<BatchRequest>,
+) -> Result<Response, AppError> {
+    let mut results = Vec::with_capacity(req.operations.len());
+    let mut first_error: Option<(StatusCode, String)> = None;
+
+    for operation in req.operations {
+        let mut result = BatchResult {
+            op: operation.name(),
+            status: BatchStatus::Ok,
+            to: None,
+            error: None,
+        };
+
+        if first_error.is_some() && req.on_error == BatchOnError::Stop {
+            result.status = BatchStatus::Skipped;
+            results.push(result);
+            continue;
+        }
+
+        match run(&state, &user, operation).await {
+            Ok(to) => result.to = to,
+            Err(e) => {
+                let (status, message) = e.status_and_message();
+                result.status = BatchStatus::Failed;
+                result.error = Some(message.clone());
+                first_error.get_or_insert((status, message));
+            }
+        }
+        results.push(result);
+    }
+
+    let (status, error) = match first_error {
+        Some((status, message)) => (status, Some(message)),
+        None => (StatusCode::OK, None),
+    };
+
+    let response = BatchResponse {
+        status: if error.is_none() { "ok" } else { "error" },
+        error,
+        results,
+    };
+    Ok((status, Json(response)).into_response())
+}
+
+/// Returns the final destination for moves.
+async fn run(
+    state: &AppState,
+    user: &AuthUser,
+    operation: BatchOperation,
+) -> Result<Option<String>, AppError> {
+    let state = || State(state.clone());
+    let user = || Extension(user.clone());
+
+    match operation {
+        BatchOperation::Move(req) => {
+            let Json(response) = move_file::move_file(state(), user(), Json(req)).await?;
+            Ok(Some(response.to))
+        }
+        BatchOperation::Copy(req) => copy::copy_path(state(), user(), Json(req))
+            .await
+            .map(|_| None),
+        BatchOperation::Delete(req) => delete::delete_path(state(), user(), Json(req))
+            .await
+            .map(|_| None),
+        BatchOperation::Mkdir(req) => mkdir::create_directory(state(), user(), Json(req))
+            .await
+            .map(|_| None),
+    }
+}
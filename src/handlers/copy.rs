@@ -0,0 +1,176 @@
+use std::path::Path;
+
+use axum::{extract::State, Extension, Json};
+
+use crate::access::{self, Access};
+use crate::archive::{Entry, EntryKind, Source, Walker};
+use crate::auth::AuthUser;
+use crate::error::AppError;
+use crate::fsutil;
+use crate::handlers::delete;
+use crate::jobs;
+use crate::models::{CopyRequest, StatusResponse};
+use crate::security::{validate_existing, validate_path};
+use crate::storage;
+use crate::util;
+use crate::AppState;
+
+/// Copies a file or directory tree. Only what the user can read is copied:
+/// entries hidden by access rules and symlinked directories are left out,
+/// as in archive downloads.
+pub async fn copy_path(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<CopyRequest>,
+) -> Result<Json<StatusResponse>, AppError> {
+    let from_path = access::validate(
+        &state.config,
+        &user,
+        &req.from,
+        Access::Read,
+        validate_existing,
+    )?;
+    let to_path = access::validate(&state.config, &user, &req.to, Access::Create, validate_path)?;
+
+    let canonical_root = state.config.root_directory.canonicalize()?;
+    if to_path == canonical_root {
+        return Err(AppError::BadRequest(
+            "cannot copy onto root directory".to_string(),
+        ));
+    }
+
+    let is_dir = from_path.is_dir();
+    if is_dir && !req.recursive {
+        return Err(AppError::BadRequest(
+            "copying a directory requires recursive".to_string(),
+        ));
+    }
+
+    // Prevent copying a directory into itself
+    if to_path.starts_with(&from_path) {
+        return Err(AppError::BadRequest(if is_dir {
+            "cannot copy directory into itself".to_string()
+        } else {
+            "source and destination are the same".to_string()
+        }));
+    }
+
+    let existed = to_path.exists();
+    if existed {
+        if !req.overwrite {
+            return Err(AppError::Conflict("destination already exists".to_string()));
+        }
+        if to_path.is_dir() != is_dir {
+            return Err(AppError::Conflict(format!(
+                "destination exists and is not a {}",
+                if is_dir { "folder" } else { "file" }
+            )));
+        }
+    }
+
+    let to_rel = access::relative_to_root(&state.config, &to_path)?;
+    state.dav_locks.guard_entry(&to_rel, &user.username)?;
+    // Replacing the destination takes more than adding to it
+    if existed {
+        access::check(&state.config, &user, &to_rel, Access::Modify)?;
+    }
+
+    // Entries are named by their destination, relative to the root. One
+    // walk both sizes the copy and lists what to create.
+    let source = Source {
+        name: to_rel.to_string_lossy().to_string(),
+        path: from_path.clone(),
+    };
+    let mut walker = Walker::new(
+        canonical_root.clone(),
+        state.config.access_rules.clone(),
+        user.clone(),
+        vec![source],
+    )
+    .await?;
+    let mut entries = Vec::new();
+    let mut total_size = 0;
+    while let Some(entry) = walker.next().await? {
+        if let EntryKind::File { size } = entry.kind {
+            total_size += size;
+        }
+        entries.push(entry);
+    }
+
+    jobs::set_totals(total_size, entries.len() as u64);
+
+    let parent = to_path.parent().unwrap_or(&to_path);
+    let parent_rel = to_rel.parent().unwrap_or(Path::new(""));
+    storage::budget(&state.config, parent, parent_rel)
+        .await?
+        .check(total_size)?;
+
+    // An existing destination is replaced whole, once the copy beside it
+    // is complete
+    let target = if existed {
+        to_path.with_file_name(format!("{}{}", fsutil::TEMP_PREFIX, util::random_id()))
+    } else {
+        to_path.clone()
+    };
+
+    let mut result = copy_entries(&state, &user, entries, &to_rel, &target).await;
+    if result.is_ok() && existed {
+        result = delete::remove_replaced(&state, &to_path, &to_rel, &user.username).await;
+        if result.is_ok() {
+            result = tokio::fs::rename(&target, &to_path)
+                .await
+                .map_err(AppError::from);
+        }
+    }
+
+    if let Err(e) = result {
+        // Don't leave half a copy behind
+        let _ = if is_dir {
+            tokio::fs::remove_dir_all(&target).await
+        } else {
+            tokio::fs::remove_file(&target).await
+        };
+        return Err(e);
+    }
+
+    tracing::info!(
+        "Copied {} to {} ({} bytes)",
+        from_path.display(),
+        to_path.display(),
+        total_size
+    );
+    Ok(Json(StatusResponse::ok()))
+}
+
+/// Creates the entries below `target` in walk order, so directories exist
+/// before their contents. Each destination is checked on its own, since a
+/// write rule may cover part of the tree.
+async fn copy_entries(
+    state: &AppState,
+    user: &AuthUser,
+    entries: Vec<Entry>,
+    to_rel: &Path,
+    target: &Path,
+) -> Result<(), AppError> {
+    for entry in entries {
+        jobs::check_cancelled()?;
+        let rel = Path::new(&entry.name);
+        access::check(&state.config, user, rel, Access::Create)?;
+        let inner = rel.strip_prefix(to_rel).map_err(|_| AppError::Forbidden)?;
+        let dest = if inner.as_os_str().is_empty() {
+            target.to_path_buf()
+        } else {
+            target.join(inner)
+        };
+
+        match entry.kind {
+            EntryKind::Directory => tokio::fs::create_dir(&dest).await?,
+            EntryKind::File { .. } => {
+                fsutil::copy_file(&entry.path, &dest).await?;
+            }
+        }
+        jobs::report_item();
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1034 @@
+use std::collections::HashSet;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
+
+use crate::access::{self, Access};
+use crate::auth::AuthUser;
+use crate::dav::if_header::{Condition, IfHeader};
+use crate::dav::locks::{self, Lock};
+use crate::dav::xml::{self, Multistatus, Propfind, DAV_NS};
+use crate::dav::{self, Depth};
+use crate::error::AppError;
+use crate::events::UploadReporter;
+use crate::fsutil::StagedFile;
+use crate::handlers::upload::destination;
+use crate::handlers::{copy, delete, mkdir, move_file, serve};
+use crate::headers::Validators;
+use crate::mime_policy::{self, ContentCheck};
+use crate::models::{CopyRequest, DeleteRequest, FileQuery, MkdirRequest, MoveRequest, OnConflict};
+use crate::security::{is_internal_name, validate_existing, validate_path};
+use crate::storage;
+use crate::util;
+use crate::AppState;
+
+/// Largest XML request body accepted.
+const XML_LIMIT: usize = 64 * 1024;
+
+const ALLOW: &str =
+    "OPTIONS, GET, HEAD, PUT, DELETE, MKCOL, COPY, MOVE, PROPFIND, PROPPATCH, LOCK, UNLOCK";
+
+/// Live properties, in the order `allprop` lists them.
+const LIVE_PROPS: &[&str] = &[
+    "creationdate",
+    "displayname",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "lockdiscovery",
+    "resourcetype",
+    "supportedlock",
+];
+
+const SUPPORTED_LOCK: &str = "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope>\
+    <D:locktype><D:write/></D:locktype></D:lockentry>\
+    <D:lockentry><D:lockscope><D:shared/></D:lockscope>\
+    <D:locktype><D:write/></D:locktype></D:lockentry>";
+
+/// Prefix for a destination set aside while COPY or MOVE replaces it.
+/// Internal, so hidden; unlike temp files the startup sweep leaves it be.
+const ASIDE_PREFIX: &str = ".monkeyarch-replaced-";
+
+const XML_HEADER: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n";
+
+/// Entry point for every method under `/dav/`.
+pub async fn handle(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let Some(rel) = dav::relative_path(uri.path()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let result = match DavRequest::new(&state, &user, rel, &headers) {
+        Ok(req) => locks::with_tokens(req.tokens.clone(), req.dispatch(method, body)).await,
+        Err(e) => Err(e),
+    };
+
+    result.unwrap_or_else(|e| e.into_response())
+}
+
+/// A request whose `If` header held.
+struct DavRequest<'a> {
+    state: &'a AppState,
+    user: &'a AuthUser,
+    /// Root-relative path of the request URI.
+    rel: String,
+    headers: &'a HeaderMap,
+    /// Lock tokens submitted in the `If` header.
+    tokens: Vec<String>,
+}
+
+impl<'a> DavRequest<'a> {
+    fn new(
+        state: &'a AppState,
+        user: &'a AuthUser,
+        rel: String,
+        headers: &'a HeaderMap,
+    ) -> Result<Self, AppError> {
+        let mut req = Self {
+            state,
+            user,
+            rel,
+            headers,
+            tokens: Vec::new(),
+        };
+
+        if let Some(value) = req.header("if") {
+            let condition = IfHeader::parse(value)?;
+            if !condition.evaluate(|resource, c| req.holds(resource, c)) {
+                return Err(AppError::PreconditionFailed(
+                    "If header conditions not met".to_string(),
+                ));
+            }
+            req.tokens = condition.tokens();
+        }
+
+        Ok(req)
+    }
+
+    async fn dispatch(&self, method: Method, body: Body) -> Result<Response, AppError> {
+        match method.as_str() {
+            "OPTIONS" => Ok(options()),
+            "PROPFIND" => self.propfind(body).await,
+            "PROPPATCH" => self.proppatch(body).await,
+            "GET" | "HEAD" => self.get(method).await,
+            "PUT" => self.put(body).await,
+            "MKCOL" => self.mkcol(body).await,
+            "DELETE" => self.delete().await,
+            "COPY" => self.copy_or_move(false).await,
+            "MOVE" => self.copy_or_move(true).await,
+            "LOCK" => self.lock(body).await,
+            "UNLOCK" => self.unlock(),
+            _ => Ok(method_not_allowed()),
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    /// Evaluates one condition of the `If` header.
+    fn holds(&self, resource: Option<&str>, condition: &Condition) -> bool {
+        let rel = match resource {
+            Some(url) => match dav::destination(url, self.header("host")) {
+                Ok(rel) => rel,
+                Err(_) => return false,
+            },
+            None => self.rel.clone(),
+        };
+
+        match condition {
+            Condition::Token(token) => self
+                .state
+                .dav_locks
+                .covering(Path::new(&rel))
+                .iter()
+                .any(|lock| lock.token == *token),
+            // Weak and strong forms of our tags name the same version
+            Condition::ETag(etag) => self
+                .validate_as(&rel, Access::Read, validate_existing)
+                .ok()
+                .and_then(|path| std::fs::metadata(path).ok())
+                .is_some_and(|meta| {
+                    let ours = Validators::from_metadata(&meta).etag;
+                    ours.trim_start_matches("W/") == etag.trim_start_matches("W/")
+                }),
+        }
+    }
+
+    fn validate<F>(&self, access: Access, validate: F) -> Result<PathBuf, AppError>
+    where
+        F: FnOnce(&Path, &str) -> Result<PathBuf, AppError>,
+    {
+        self.validate_as(&self.rel, access, validate)
+    }
+
+    fn validate_as<F>(&self, rel: &str, access: Access, validate: F) -> Result<PathBuf, AppError>
+    where
+        F: FnOnce(&Path, &str) -> Result<PathBuf, AppError>,
+    {
+        access::validate(&self.state.config, self.user, rel, access, validate)
+    }
+
+    /// The folder a new item goes into; missing answers 409 as WebDAV
+    /// expects.
+    fn parent_dir(&self, rel: &str) -> Result<(PathBuf, PathBuf), AppError> {
+        let missing = || AppError::Conflict("parent folder does not exist".to_string());
+        let dir = self
+            .validate_as(parent_of(rel), Access::Read, validate_path)
+            .map_err(|e| match e {
+                AppError::NotFound(_) => missing(),
+                e => e,
+            })?;
+        if !dir.is_dir() {
+            return Err(missing());
+        }
+        let dir_rel = access::relative_to_root(&self.state.config, &dir)?;
+        Ok((dir, dir_rel))
+    }
+
+    /// Fails with 423 unless the user submitted the locks in the way of
+    /// changing `rel` (with `deep`, also everything below it).
+    fn check_locks(&self, rel: &str, deep: bool) -> Result<(), AppError> {
+        self.state
+            .dav_locks
+            .check(Path::new(rel), deep, &self.tokens, &self.user.username)
+    }
+
+    /// Adding or removing `rel` also changes its parent folder.
+    fn check_parent_locks(&self, rel: &str) -> Result<(), AppError> {
+        self.check_locks(parent_of(rel), false)
+    }
+
+    fn state(&self) -> State<AppState> {
+        State(self.state.clone())
+    }
+
+    fn extension(&self) -> Extension<AuthUser> {
+        Extension(self.user.clone())
+    }
+
+    async fn propfind(&self, body: Body) -> Result<Response, AppError> {
+        let path = self.validate(Access::Read, validate_existing)?;
+        let depth = Depth::parse(self.header("depth"), Depth::Infinity)?;
+        if depth == Depth::Infinity {
+            return Ok(xml_response(
+                StatusCode::FORBIDDEN,
+                format!(
+                    "{}<D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>",
+                    XML_HEADER
+                ),
+            ));
+        }
+        let request = xml::parse_propfind(&read_body(body).await?)?;
+
+        let meta = tokio::fs::metadata(&path).await?;
+        let mut multistatus = Multistatus::new();
+        self.describe(&mut multistatus, &self.rel, &meta, &request);
+
+        if depth == Depth::One && meta.is_dir() {
+            let mut children = Vec::new();
+            let mut dir = tokio::fs::read_dir(&path).await?;
+            while let Some(entry) = dir.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let rel = join(&self.rel, &name);
+                if is_internal_name(&name)
+                    || !access::can_read(&self.state.config.access_rules, self.user, &rel)
+                {
+                    continue;
+                }
+                // Broken symlinks are left out
+                if let Ok(meta) = tokio::fs::metadata(entry.path()).await {
+                    children.push((rel, meta));
+                }
+            }
+            children.sort_by(|a, b| a.0.cmp(&b.0));
+
+            for (rel, meta) in children {
+                self.describe(&mut multistatus, &rel.to_string_lossy(), &meta, &request);
+            }
+        }
+
+        Ok(xml_response(StatusCode::MULTI_STATUS, multistatus.finish()))
+    }
+
+    fn describe(&self, multistatus: &mut Multistatus, rel: &str, meta: &Metadata, req: &Propfind) {
+        let href = dav::href(rel, meta.is_dir());
+        let dav_name = |name: &str| (DAV_NS.to_string(), name.to_string());
+
+        match req {
+            Propfind::AllProp => {
+                let props = LIVE_PROPS
+                    .iter()
+                    .filter_map(|name| {
+                        let value = self.live_prop(name, rel, meta)?;
+                        Some(xml::property(&dav_name(name), &value))
+                    })
+                    .collect();
+                multistatus.response(&href, &[(200, props)]);
+            }
+            Propfind::PropName => {
+                let props = LIVE_PROPS
+                    .iter()
+                    .filter(|name| self.live_prop(name, rel, meta).is_some())
+                    .map(|name| xml::property(&dav_name(name), ""))
+                    .collect();
+                multistatus.response(&href, &[(200, props)]);
+            }
+            Propfind::Prop(names) => {
+                let mut found = Vec::new();
+                let mut missing = Vec::new();
+                for name in names {
+                    let value = (name.0 == DAV_NS)
+                        .then(|| self.live_prop(&name.1, rel, meta))
+                        .flatten();
+                    match value {
+                        Some(value) => found.push(xml::property(name, &value)),
+                        None => missing.push(xml::property(name, "")),
+                    }
+                }
+                multistatus.response(&href, &[(200, found), (404, missing)]);
+            }
+        }
+    }
+
+    /// A `DAV:` property of a resource, written out. `None` if unknown or
+    /// not applicable (the length of a folder, say).
+    fn live_prop(&self, name: &str, rel: &str, meta: &Metadata) -> Option<String> {
+        let is_file = meta.is_file();
+        match name {
+            "creationdate" => meta.created().or_else(|_| meta.modified()).ok().map(|t| {
+                DateTime::<Utc>::from(t)
+                    .format("%Y-%m-%dT%H:%M:%SZ")
+                    .to_string()
+            }),
+            "displayname" => Some(xml::text(rel.rsplit('/').next().unwrap_or_default())),
+            "getcontentlength" => is_file.then(|| meta.len().to_string()),
+            "getcontenttype" => is_file
+                .then(|| xml::text(mime_guess::from_path(rel).first_or_octet_stream().as_ref())),
+            "getetag" => is_file.then(|| xml::text(&Validators::from_metadata(meta).etag)),
+            "getlastmodified" => meta.modified().ok().map(httpdate::fmt_http_date),
+            "lockdiscovery" => Some(
+                self.state
+                    .dav_locks
+                    .covering(Path::new(rel))
+                    .iter()
+                    .map(active_lock)
+                    .collect(),
+            ),
+            "resourcetype" => Some(if meta.is_dir() {
+                "<D:collection/>".to_string()
+            } else {
+                String::new()
+            }),
+            "supportedlock" => Some(SUPPORTED_LOCK.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Properties can't be changed: live ones are computed and dead ones
+    /// aren't stored, so every property is refused.
+    async fn proppatch(&self, body: Body) -> Result<Response, AppError> {
+        let path = self.validate(Access::Modify, validate_existing)?;
+        self.check_locks(&self.rel, false)?;
+        let props = xml::parse_proppatch(&read_body(body).await?)?;
+
+        let mut multistatus = Multistatus::new();
+        multistatus.response(
+            &dav::href(&self.rel, path.is_dir()),
+            &[(403, props.iter().map(|p| xml::property(p, "")).collect())],
+        );
+        Ok(xml_response(StatusCode::MULTI_STATUS, multistatus.finish()))
+    }
+
+    async fn get(&self, method: Method) -> Result<Response, AppError> {
+        let path = self.validate(Access::Read, validate_existing)?;
+        if path.is_dir() {
+            return Ok(method_not_allowed());
+        }
+
+        let query = FileQuery {
+            path: self.rel.clone(),
+            download: false,
+        };
+        serve::serve_file(
+            self.state(),
+            self.extension(),
+            method,
+            self.headers.clone(),
+            Query(query),
+        )
+        .await
+    }
+
+    /// Uploads a file, with the same checks as `/api/upload`. The type is
+    /// taken from the file name, since WebDAV clients rarely send one.
+    async fn put(&self, body: Body) -> Result<Response, AppError> {
+        let target = self.new_file()?;
+        if target.path.is_dir() {
+            return Ok(method_not_allowed());
+        }
+        let existed = target.path.exists();
+        self.check_locks(&self.rel, false)?;
+        if !existed {
+            self.check_parent_locks(&self.rel)?;
+        }
+
+        let config = &self.state.config;
+        let budget = storage::budget(config, &target.dir, &target.dir_rel).await?;
+        // The file being replaced frees its space once the new one is in
+        let replaced = if existed {
+            tokio::fs::metadata(&target.path).await?.len()
+        } else {
+            0
+        };
+        let mut staged = StagedFile::create(&target.path).await?;
+        let mut content = ContentCheck::new(
+            &config.upload_policy,
+            &target.dir_rel,
+            &target.name,
+            &target.mime,
+        );
+        let mut progress = UploadReporter::start(
+            self.state.events.clone(),
+            target
+                .dir_rel
+                .join(&target.name)
+                .to_string_lossy()
+                .to_string(),
+            self.user.username.clone(),
+        );
+
+        let mut total_size: u64 = 0;
+        let mut stream = body.into_data_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk: Bytes =
+                chunk.map_err(|e| AppError::BadRequest(format!("upload error: {}", e)))?;
+            total_size += chunk.len() as u64;
+            if total_size > config.max_upload_size {
+                return Err(AppError::PayloadTooLarge);
+            }
+            budget.check(total_size.saturating_sub(replaced))?;
+
+            progress.advance(chunk.len() as u64);
+            staged.file().write_all(&content.feed(chunk)?).await?;
+        }
+        // Clients create empty files before writing them; there is no
+        // content to check
+        if total_size > 0 {
+            staged.file().write_all(&content.finish()?).await?;
+        }
+        progress.finish();
+
+        staged.commit().await?;
+        tracing::info!(
+            "Uploaded file: {} ({} bytes, WebDAV)",
+            target.path.display(),
+            total_size
+        );
+
+        Ok(if existed {
+            StatusCode::NO_CONTENT
+        } else {
+            StatusCode::CREATED
+        }
+        .into_response())
+    }
+
+    /// Resolves the request URI as a file to create or replace, checking
+    /// its name and type against the upload policy.
+    fn new_file(&self) -> Result<NewFile, AppError> {
+        let name = self.rel.rsplit('/').next().unwrap_or_default().to_string();
+        if name.is_empty() {
+            return Err(AppError::Conflict("cannot write to the root".to_string()));
+        }
+
+        let (dir, dir_rel) = self.parent_dir(&self.rel)?;
+        let (path, _) = destination(
+            self.state,
+            self.user,
+            &dir_rel,
+            &name,
+            OnConflict::Overwrite,
+            &HashSet::new(),
+        )?;
+
+        let mime = mime_guess::from_path(&name)
+            .first_or_octet_stream()
+            .to_string();
+        mime_policy::check_declared(&self.state.config.upload_policy, &dir_rel, &name, &mime)?;
+
+        Ok(NewFile {
+            dir,
+            dir_rel,
+            name,
+            path,
+            mime,
+        })
+    }
+
+    async fn mkcol(&self, body: Body) -> Result<Response, AppError> {
+        if !read_body(body).await?.is_empty() {
+            return Err(AppError::UnsupportedMediaType(
+                "MKCOL does not take a body".to_string(),
+            ));
+        }
+        if self.rel.is_empty() {
+            return Ok(method_not_allowed());
+        }
+
+        self.parent_dir(&self.rel)?;
+        let path = self.validate(Access::Create, validate_path)?;
+        if tokio::fs::symlink_metadata(&path).await.is_ok() {
+            return Ok(method_not_allowed());
+        }
+        self.check_locks(&self.rel, false)?;
+        self.check_parent_locks(&self.rel)?;
+
+        let req = MkdirRequest {
+            path: self.rel.clone(),
+        };
+        let _ = mkdir::create_directory(self.state(), self.extension(), Json(req)).await?;
+        Ok(StatusCode::CREATED.into_response())
+    }
+
+    /// Deletes through `/api/delete`, so the trash and `enable_delete`
+    /// apply.
+    async fn delete(&self) -> Result<Response, AppError> {
+        self.validate(Access::Modify, validate_existing)?;
+        self.check_locks(&self.rel, true)?;
+        self.check_parent_locks(&self.rel)?;
+
+        self.delete_tree(&self.rel).await?;
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+
+    async fn delete_tree(&self, rel: &str) -> Result<(), AppError> {
+        let req = DeleteRequest {
+            path: rel.to_string(),
+            recursive: true,
+            permanent: false,
+        };
+        let _ = delete::delete_path(self.state(), self.extension(), Json(req)).await?;
+        self.state.dav_locks.remove_tree(Path::new(rel));
+        Ok(())
+    }
+
+    /// COPY and MOVE go through `/api/copy` and `/api/move`. WebDAV
+    /// replaces rather than merges: an existing destination is set aside
+    /// first and only deleted (into the trash, when enabled) once the copy
+    /// or move succeeded; on failure it is put back.
+    async fn copy_or_move(&self, is_move: bool) -> Result<Response, AppError> {
+        let value = self
+            .header("destination")
+            .ok_or_else(|| AppError::BadRequest("missing Destination".to_string()))?;
+        let Ok(dest) = dav::destination(value, self.header("host")) else {
+            // Not on this server
+            return Ok(StatusCode::BAD_GATEWAY.into_response());
+        };
+        let overwrite = match self.header("overwrite").map(str::trim) {
+            None | Some("T") | Some("t") => true,
+            Some("F") | Some("f") => false,
+            Some(v) => return Err(AppError::BadRequest(format!("invalid Overwrite: {}", v))),
+        };
+        let depth = Depth::parse(self.header("depth"), Depth::Infinity)?;
+        if depth == Depth::One || (is_move && depth != Depth::Infinity) {
+            return Err(AppError::BadRequest(format!(
+                "invalid Depth for {}",
+                if is_move { "MOVE" } else { "COPY" }
+            )));
+        }
+
+        let access = if is_move {
+            Access::Modify
+        } else {
+            Access::Read
+        };
+        let from_path = self.validate(access, validate_existing)?;
+        if dest == self.rel {
+            return Err(AppError::Forbidden);
+        }
+        if dest.is_empty()
+            || Path::new(&dest).starts_with(&self.rel)
+            || Path::new(&self.rel).starts_with(&dest)
+        {
+            return Err(AppError::Conflict(
+                "source and destination overlap".to_string(),
+            ));
+        }
+
+        let (dest_dir, _) = self.parent_dir(&dest)?;
+        self.validate_as(&dest, Access::Create, validate_path)?;
+        // The entry itself, even where it is a symlink
+        let slot = dest_dir.join(Path::new(&dest).file_name().unwrap_or_default());
+        let existed = tokio::fs::symlink_metadata(&slot).await.is_ok();
+        if existed && !overwrite {
+            return Err(AppError::PreconditionFailed(
+                "destination exists".to_string(),
+            ));
+        }
+
+        if is_move {
+            self.check_locks(&self.rel, true)?;
+            self.check_parent_locks(&self.rel)?;
+        }
+        self.check_locks(&dest, true)?;
+        if !existed {
+            self.check_parent_locks(&dest)?;
+        }
+
+        let aside = if existed {
+            if !self.state.config.enable_delete {
+                return Err(AppError::Forbidden);
+            }
+            self.validate_as(&dest, Access::Modify, validate_existing)?;
+            let aside = slot.with_file_name(format!("{}{}", ASIDE_PREFIX, util::random_id()));
+            tokio::fs::rename(&slot, &aside).await?;
+            Some(aside)
+        } else {
+            None
+        };
+
+        let result = self.transfer(is_move, depth, &from_path, &dest).await;
+        match (result, aside) {
+            (Ok(()), Some(aside)) => self.discard(&aside, &dest).await?,
+            (Ok(()), None) => {}
+            (Err(e), Some(aside)) => {
+                restore(&aside, &slot).await;
+                return Err(e);
+            }
+            (Err(e), None) => return Err(e),
+        }
+        if is_move {
+            self.state.dav_locks.remove_tree(Path::new(&self.rel));
+        }
+
+        Ok(if existed {
+            StatusCode::NO_CONTENT
+        } else {
+            StatusCode::CREATED
+        }
+        .into_response())
+    }
+
+    async fn transfer(
+        &self,
+        is_move: bool,
+        depth: Depth,
+        from_path: &Path,
+        dest: &str,
+    ) -> Result<(), AppError> {
+        let dest = dest.to_string();
+        if is_move {
+            let req = MoveRequest {
+                from: self.rel.clone(),
+                to: dest,
+                overwrite: false,
+                conflict: None,
+            };
+            let _ = move_file::move_file(self.state(), self.extension(), Json(req)).await?;
+        } else if from_path.is_dir() && depth == Depth::Zero {
+            let req = MkdirRequest { path: dest };
+            let _ = mkdir::create_directory(self.state(), self.extension(), Json(req)).await?;
+        } else {
+            let req = CopyRequest {
+                from: self.rel.clone(),
+                to: dest,
+                overwrite: false,
+                recursive: true,
+            };
+            let _ = copy::copy_path(self.state(), self.extension(), Json(req)).await?;
+        }
+        Ok(())
+    }
+
+    /// Deletes a replaced destination the way `/api/delete` would have.
+    async fn discard(&self, aside: &Path, dest: &str) -> Result<(), AppError> {
+        self.state.dav_locks.remove_tree(Path::new(dest));
+        delete::remove_replaced(self.state, aside, Path::new(dest), &self.user.username).await
+    }
+
+    /// Takes or, without a body, refreshes a lock. Locking a missing file
+    /// creates it empty.
+    async fn lock(&self, body: Body) -> Result<Response, AppError> {
+        let body = read_body(body).await?;
+        let timeout = locks::parse_timeout(self.header("timeout"));
+        let locks = &self.state.dav_locks;
+
+        if body.iter().all(u8::is_ascii_whitespace) {
+            self.validate(Access::Create, validate_existing)?;
+            let lock = locks.refresh(
+                Path::new(&self.rel),
+                &self.tokens,
+                &self.user.username,
+                timeout,
+            )?;
+            return Ok(lock_response(StatusCode::OK, &lock, false));
+        }
+
+        let info = xml::parse_lockinfo(&body)?;
+        let depth = Depth::parse(self.header("depth"), Depth::Infinity)?;
+        if depth == Depth::One {
+            return Err(AppError::BadRequest("invalid Depth for LOCK".to_string()));
+        }
+
+        let path = self.validate(Access::Create, validate_path)?;
+        let target = if tokio::fs::symlink_metadata(&path).await.is_ok() {
+            None
+        } else {
+            Some(self.new_file()?)
+        };
+
+        let lock = locks.lock(
+            Path::new(&self.rel),
+            info.shared,
+            depth == Depth::Infinity,
+            info.owner,
+            &self.user.username,
+            timeout,
+        )?;
+
+        if let Some(target) = target {
+            let created = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&target.path)
+                .await;
+            if let Err(e) = created {
+                let _ = locks.unlock(Path::new(&self.rel), &lock.token, &self.user.username);
+                return Err(e.into());
+            }
+            return Ok(lock_response(StatusCode::CREATED, &lock, true));
+        }
+
+        Ok(lock_response(StatusCode::OK, &lock, true))
+    }
+
+    fn unlock(&self) -> Result<Response, AppError> {
+        let token = self
+            .header("lock-token")
+            .map(|t| t.trim().trim_start_matches('<').trim_end_matches('>'))
+            .ok_or_else(|| AppError::BadRequest("missing Lock-Token".to_string()))?;
+        self.validate(Access::Read, validate_path)?;
+
+        self.state
+            .dav_locks
+            .unlock(Path::new(&self.rel), token, &self.user.username)?;
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+}
+
+/// Where a PUT (or a LOCK on a missing file) writes.
+struct NewFile {
+    dir: PathBuf,
+    dir_rel: PathBuf,
+    name: String,
+    path: PathBuf,
+    mime: String,
+}
+
+fn options() -> Response {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("dav", "1, 2")
+        .header(header::ALLOW, ALLOW)
+        // Makes Windows use WebDAV rather than FrontPage extensions
+        .header("ms-author-via", "DAV")
+        .header(header::CONTENT_LENGTH, 0)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn method_not_allowed() -> Response {
+    (StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, ALLOW)]).into_response()
+}
+
+fn xml_response(status: StatusCode, body: String) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        body,
+    )
+        .into_response()
+}
+
+fn lock_response(status: StatusCode, lock: &Lock, with_token: bool) -> Response {
+    let body = format!(
+        "{}<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>",
+        XML_HEADER,
+        active_lock(lock)
+    );
+    let mut response = xml_response(status, body);
+    if with_token {
+        if let Ok(value) = format!("<{}>", lock.token).parse() {
+            response.headers_mut().insert("lock-token", value);
+        }
+    }
+    response
+}
+
+fn active_lock(lock: &Lock) -> String {
+    format!(
+        "<D:activelock><D:locktype><D:write/></D:locktype>\
+         <D:lockscope>{}</D:lockscope><D:depth>{}</D:depth>{}\
+         <D:timeout>Second-{}</D:timeout>\
+         <D:locktoken><D:href>{}</D:href></D:locktoken>\
+         <D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+        if lock.shared {
+            "<D:shared/>"
+        } else {
+            "<D:exclusive/>"
+        },
+        if lock.deep { "infinity" } else { "0" },
+        lock.owner.as_deref().unwrap_or_default(),
+        lock.remaining().as_millis().div_ceil(1000),
+        xml::text(&lock.token),
+        xml::text(&dav::href(&lock.path.to_string_lossy(), false)),
+    )
+}
+
+/// Puts a set-aside destination back, over whatever a failed copy or
+/// move left in its place.
+async fn restore(aside: &Path, slot: &Path) {
+    let partial = match tokio::fs::symlink_metadata(slot).await {
+        Ok(meta) if meta.is_dir() => tokio::fs::remove_dir_all(slot).await,
+        Ok(_) => tokio::fs::remove_file(slot).await,
+        Err(_) => Ok(()),
+    };
+    if let Err(e) = partial.and(tokio::fs::rename(aside, slot).await) {
+        tracing::error!("Could not restore {}: {}", slot.display(), e);
+    }
+}
+
+async fn read_body(body: Body) -> Result<Bytes, AppError> {
+    axum::body::to_bytes(body, XML_LIMIT)
+        .await
+        .map_err(|_| AppError::PayloadTooLarge)
+}
+
+fn parent_of(rel: &str) -> &str {
+    rel.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("")
+}
+
+fn join(rel: &str, name: &str) -> PathBuf {
+    Path::new(rel).join(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use axum::http::HeaderName;
+    use tempfile::TempDir;
+
+    use crate::auth::Role;
+    use crate::config::{AccessRule, Config, UploadPolicy};
+    use crate::jobs::JobStore;
+
+    const LOCKINFO: &str = r#"<?xml version="1.0"?>
+        <D:lockinfo xmlns:D="DAV:">
+          <D:lockscope><D:exclusive/></D:lockscope>
+          <D:locktype><D:write/></D:locktype>
+        </D:lockinfo>"#;
+
+    fn state(root: &Path) -> AppState {
+        let config = Config {
+            root_directory: root.to_path_buf(),
+            static_directory: None,
+            bind_address: "127.0.0.1".to_string(),
+            port: 0,
+            max_upload_size: 1024 * 1024,
+            enable_delete: true,
+            auth: Default::default(),
+            allowed_networks: Vec::new(),
+            trusted_proxies: Vec::new(),
+            access_rules: vec![AccessRule {
+                path: "private".to_string(),
+                read: Some(vec!["dj".to_string()]),
+                write: None,
+            }],
+            upload_policy: UploadPolicy {
+                mime_types: vec!["text/*".to_string()],
+                sniff: false,
+                ..Default::default()
+            },
+            quotas: Vec::new(),
+            trash: Default::default(),
+            jobs: Default::default(),
+            events: Default::default(),
+            tus: Default::default(),
+        };
+
+        AppState {
+            config: Arc::new(config),
+            sessions: Default::default(),
+            uploads: Default::default(),
+            jobs: Arc::new(JobStore::new(1, Duration::from_secs(60))),
+            events: Default::default(),
+            dav_locks: Default::default(),
+        }
+    }
+
+    fn user(role: Role) -> AuthUser {
+        AuthUser {
+            username: "pi".to_string(),
+            role,
+        }
+    }
+
+    async fn request(
+        state: &AppState,
+        user: &AuthUser,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> (StatusCode, HeaderMap, String) {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+
+        let response = handle(
+            State(state.clone()),
+            Extension(user.clone()),
+            Method::from_bytes(method.as_bytes()).unwrap(),
+            path.parse().unwrap(),
+            map,
+            Body::from(body.to_string()),
+        )
+        .await;
+
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, headers, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    async fn lock(state: &AppState, path: &str) -> String {
+        let (status, headers, _) =
+            request(state, &user(Role::Admin), "LOCK", path, &[], LOCKINFO).await;
+        assert!(status.is_success());
+        let token = headers["lock-token"].to_str().unwrap();
+        token.trim_matches(['<', '>']).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_propfind_hides_internal_and_unreadable() {
+        let tmp = TempDir::new().unwrap();
+        fs::create_dir(tmp.path().join(".monkeyarch")).unwrap();
+        fs::create_dir(tmp.path().join("private")).unwrap();
+        fs::write(tmp.path().join("song.txt"), "la").unwrap();
+        let state = state(tmp.path());
+
+        let (status, _, body) = request(
+            &state,
+            &user(Role::Uploader),
+            "PROPFIND",
+            "/dav/",
+            &[("depth", "1")],
+            "",
+        )
+        .await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(body.contains("/dav/song.txt"));
+        assert!(!body.contains(".monkeyarch"));
+        assert!(!body.contains("private"));
+    }
+
+    #[tokio::test]
+    async fn test_put_created_then_replaced() {
+        let tmp = TempDir::new().unwrap();
+        let state = state(tmp.path());
+        let admin = user(Role::Admin);
+
+        let (status, _, _) = request(&state, &admin, "PUT", "/dav/a.txt", &[], "one").await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _, _) = request(&state, &admin, "PUT", "/dav/a.txt", &[], "two").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(fs::read_to_string(tmp.path().join("a.txt")).unwrap(), "two");
+    }
+
+    #[tokio::test]
+    async fn test_lock_blocks_put_without_token() {
+        let tmp = TempDir::new().unwrap();
+        fs::write(tmp.path().join("a.txt"), "one").unwrap();
+        let state = state(tmp.path());
+        let admin = user(Role::Admin);
+        let token = lock(&state, "/dav/a.txt").await;
+
+        let (status, _, _) = request(&state, &admin, "PUT", "/dav/a.txt", &[], "two").await;
+        assert_eq!(status, StatusCode::LOCKED);
+        assert_eq!(fs::read_to_string(tmp.path().join("a.txt")).unwrap(), "one");
+
+        let condition = format!("(<{}>)", token);
+        let headers = [("if", condition.as_str())];
+        let (status, _, _) = request(&state, &admin, "PUT", "/dav/a.txt", &headers, "two").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(fs::read_to_string(tmp.path().join("a.txt")).unwrap(), "two");
+    }
+
+    #[tokio::test]
+    async fn test_copy_without_overwrite() {
+        let tmp = TempDir::new().unwrap();
+        fs::write(tmp.path().join("a.txt"), "a").unwrap();
+        fs::write(tmp.path().join("b.txt"), "b").unwrap();
+        let state = state(tmp.path());
+
+        let headers = [("destination", "/dav/b.txt"), ("overwrite", "F")];
+        let (status, _, _) = request(
+            &state,
+            &user(Role::Admin),
+            "COPY",
+            "/dav/a.txt",
+            &headers,
+            "",
+        )
+        .await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(fs::read_to_string(tmp.path().join("b.txt")).unwrap(), "b");
+    }
+
+    #[tokio::test]
+    async fn test_move_drops_source_locks() {
+        let tmp = TempDir::new().unwrap();
+        fs::write(tmp.path().join("a.txt"), "a").unwrap();
+        let state = state(tmp.path());
+        let admin = user(Role::Admin);
+        let token = lock(&state, "/dav/a.txt").await;
+
+        let condition = format!("(<{}>)", token);
+        let headers = [("destination", "/dav/c.txt"), ("if", condition.as_str())];
+        let (status, _, _) = request(&state, &admin, "MOVE", "/dav/a.txt", &headers, "").await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(state.dav_locks.covering(Path::new("a.txt")).is_empty());
+
+        // The old name is free again, without a token
+        let (status, _, _) = request(&state, &admin, "PUT", "/dav/a.txt", &[], "new").await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+}
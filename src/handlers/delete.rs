@@ -1,19 +1,31 @@
-use axum::{extract::State, Json};
+use std::path::Path;
 
+use axum::{extract::State, Extension, Json};
+
+use crate::access::{self, Access};
+use crate::auth::AuthUser;
 use crate::error::AppError;
 use crate::models::{DeleteRequest, StatusResponse};
 use crate::security::validate_existing;
+use crate::trash;
 use crate::AppState;
 
 pub async fn delete_path(
     State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
     Json(req): Json<DeleteRequest>,
 ) -> Result<Json<StatusResponse>, AppError> {
     if !state.config.enable_delete {
         return Err(AppError::Forbidden);
     }
 
-    let path = validate_existing(&state.config.root_directory, &req.path)?;
+    let path = access::validate(
+        &state.config,
+        &user,
+        &req.path,
+        Access::Modify,
+        validate_existing,
+    )?;
 
     // Prevent deleting the root directory itself
     let canonical_root = state.config.root_directory.canonicalize()?;
@@ -23,6 +35,20 @@ pub async fn delete_path(
         ));
     }
 
+    let rel = access::relative_to_root(&state.config, &path)?;
+    state.dav_locks.guard_entry(&rel, &user.username)?;
+
+    if state.config.trash.enabled && !req.permanent {
+        // Without `recursive`, only an empty directory may go
+        if path.is_dir() && !req.recursive && !is_empty_dir(&path).await? {
+            return Err(AppError::Conflict("directory not empty".to_string()));
+        }
+
+        let info = trash::move_to_trash(&canonical_root, &path, &rel, &user.username).await?;
+        tracing::info!("Moved {} to trash ({})", info.path, info.id);
+        return Ok(Json(StatusResponse::ok()));
+    }
+
     if path.is_dir() {
         if req.recursive {
             tokio::fs::remove_dir_all(&path).await?;
@@ -41,3 +67,31 @@ pub async fn delete_path(
 
     Ok(Json(StatusResponse::ok()))
 }
+
+/// Gets an item that is about to be replaced out of the way, as a delete
+/// would: into the trash when enabled, otherwise for good.
+pub async fn remove_replaced(
+    state: &AppState,
+    path: &Path,
+    rel: &Path,
+    username: &str,
+) -> Result<(), AppError> {
+    if state.config.trash.enabled {
+        let root = state.config.root_directory.canonicalize()?;
+        let info = trash::move_to_trash(&root, path, rel, username).await?;
+        tracing::info!("Moved replaced {} to trash ({})", info.path, info.id);
+    } else if tokio::fs::symlink_metadata(path).await?.is_dir() {
+        tokio::fs::remove_dir_all(path).await?;
+    } else {
+        tokio::fs::remove_file(path).await?;
+    }
+    Ok(())
+}
+
+async fn is_empty_dir(path: &Path) -> Result<bool, AppError> {
+    Ok(tokio::fs::read_dir(path)
+        .await?
+        .next_entry()
+        .await?
+        .is_none())
+}
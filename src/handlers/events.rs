@@ -0,0 +1,80 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::State,
+    response::sse::{Event, KeepAlive, Sse},
+    Extension,
+};
+use futures::{stream, Stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::access;
+use crate::auth::AuthUser;
+use crate::config::AccessRule;
+use crate::error::AppError;
+use crate::events::Notice;
+use crate::models::{ChangeEvent, ChangeKind};
+use crate::AppState;
+
+/// Streams changes below the root as Server-Sent Events, limited to paths
+/// the user can see. Each change is a message with a `ChangeEvent` as
+/// JSON; a `resync` event means changes were missed and the client should
+/// re-list what it shows.
+pub async fn stream_events(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    if !state.config.events.enabled {
+        return Err(AppError::NotFound("live events are disabled".into()));
+    }
+
+    let rx = state.events.subscribe();
+    let bus = state.events.clone();
+    let events = stream::unfold((rx, state, user), |(mut rx, state, user)| async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(Notice::Change(change)) => {
+                    match visible(&state.config.access_rules, &user, change) {
+                        Some(change) => Event::default()
+                            .json_data(&change)
+                            .expect("change events serialize"),
+                        None => continue,
+                    }
+                }
+                Ok(Notice::Resync) | Err(RecvError::Lagged(_)) => {
+                    Event::default().event("resync").data("")
+                }
+                // Upload progress is only sent over the WebSocket
+                Ok(Notice::Upload(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            };
+            return Some((Ok(event), (rx, state, user)));
+        }
+    })
+    .take_until(async move { bus.closed().await });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// The part of a change the user may see. A rename between a visible and
+/// a hidden folder looks like the item appearing or disappearing.
+pub fn visible(rules: &[AccessRule], user: &AuthUser, change: ChangeEvent) -> Option<ChangeEvent> {
+    let can_read = |path: &str| access::can_read(rules, user, path.as_ref());
+
+    let from = can_read(&change.path);
+    let to = change.to.as_deref().map(can_read);
+    match (from, to) {
+        (true, None | Some(true)) => Some(change),
+        (true, Some(false)) => Some(ChangeEvent {
+            kind: ChangeKind::Deleted,
+            path: change.path,
+            to: None,
+        }),
+        (false, Some(true)) => Some(ChangeEvent {
+            kind: ChangeKind::Created,
+            path: change.to?,
+            to: None,
+        }),
+        (false, _) => None,
+    }
+}
@@ -0,0 +1,205 @@
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::Response,
+    Extension, Json,
+};
+use serde::Serialize;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio_util::io::ReaderStream;
+
+use crate::archive::{self, Walker};
+use crate::auth::{AuthUser, Role};
+use crate::error::AppError;
+use crate::handlers::{archive::resolve_selection, copy, delete, move_file};
+use crate::headers::content_disposition;
+use crate::jobs::{self, Job, Outcome};
+use crate::models::{JobListResponse, JobRequest, JobResponse};
+use crate::storage;
+use crate::AppState;
+
+/// Starts an operation in the background and answers `202` right away.
+/// The operation runs through its endpoint's handler, so its checks apply
+/// as usual; a rejected request shows up as a failed job. Archive paths
+/// are checked up front.
+pub async fn create_job(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<JobRequest>,
+) -> Result<(StatusCode, Json<JobResponse>), AppError> {
+    let username = user.username.clone();
+    if state.jobs.unfinished(&username) >= state.config.jobs.max_per_user {
+        return Err(AppError::TooManyRequests(format!(
+            "at most {} jobs may be queued or running at once",
+            state.config.jobs.max_per_user
+        )));
+    }
+
+    let task_state = state.clone();
+    let task_user = user.clone();
+
+    let job = match req {
+        JobRequest::Move(req) => state.jobs.spawn("move", username, async move {
+            let response =
+                move_file::move_file(State(task_state), Extension(task_user), Json(req)).await?;
+            outcome(response.0)
+        }),
+        JobRequest::Copy(req) => state.jobs.spawn("copy", username, async move {
+            let response =
+                copy::copy_path(State(task_state), Extension(task_user), Json(req)).await?;
+            outcome(response.0)
+        }),
+        JobRequest::Delete(req) => state.jobs.spawn("delete", username, async move {
+            let response =
+                delete::delete_path(State(task_state), Extension(task_user), Json(req)).await?;
+            outcome(response.0)
+        }),
+        JobRequest::Archive(req) => {
+            let sources = resolve_selection(&state, &user, &req.paths)?;
+            let name = req.name.unwrap_or_else(|| "archive".to_string());
+            let filename = format!("{}.{}", name, req.format.extension());
+
+            // The uncompressed size bounds the archive, give or take headers
+            let paths: Vec<PathBuf> = sources.iter().map(|s| s.path.clone()).collect();
+            let estimate = tokio::task::spawn_blocking(move || {
+                paths.iter().try_fold(0, |total, path| {
+                    let size = if path.is_dir() {
+                        storage::dir_usage(path)?
+                    } else {
+                        path.metadata()?.len()
+                    };
+                    Ok::<u64, std::io::Error>(total + size)
+                })
+            })
+            .await
+            .map_err(|e| AppError::Internal(format!("space check failed: {}", e)))??;
+            archive_budget(&state).await?.check(estimate)?;
+
+            state.jobs.spawn("archive", username, async move {
+                // Jobs queued ahead may have used up the space meanwhile
+                archive_budget(&task_state).await?.check(estimate)?;
+
+                let root = task_state.config.root_directory.canonicalize()?;
+                let rules = task_state.config.access_rules.clone();
+                let walker = Walker::new(root.clone(), rules, task_user, sources).await?;
+
+                let dir = jobs::output_dir(&root);
+                tokio::fs::create_dir_all(&dir).await?;
+                let path = dir.join(crate::util::random_id());
+
+                let mut out = BufWriter::new(tokio::fs::File::create(&path).await?);
+                let written = match archive::write(req.format, &mut out, walker).await {
+                    Ok(()) => out.flush().await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = written {
+                    let _ = tokio::fs::remove_file(&path).await;
+                    return Err(e.into());
+                }
+
+                Ok(Outcome::File { path, filename })
+            })
+        }
+    };
+
+    Ok((StatusCode::ACCEPTED, Json(job.to_response())))
+}
+
+/// Space for archives, which are written to `.monkeyarch/jobs`.
+async fn archive_budget(state: &AppState) -> Result<storage::Budget, AppError> {
+    let root = state.config.root_directory.canonicalize()?;
+    let rel = jobs::output_dir(FsPath::new(""));
+    storage::budget(&state.config, &root, &rel).await
+}
+
+/// The user's own jobs; admins see everyone's.
+pub async fn list_jobs(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Json<JobListResponse> {
+    let jobs = state
+        .jobs
+        .list()
+        .into_iter()
+        .filter(|job| can_see(&user, job))
+        .map(|job| job.to_response())
+        .collect();
+
+    Json(JobListResponse { jobs })
+}
+
+pub async fn job_status(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<String>,
+) -> Result<Json<JobResponse>, AppError> {
+    let job = find(&state, &user, &id)?;
+    Ok(Json(job.to_response()))
+}
+
+pub async fn cancel_job(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<String>,
+) -> Result<Json<JobResponse>, AppError> {
+    let job = find(&state, &user, &id)?;
+    if job.is_finished() {
+        return Err(AppError::Conflict("job already finished".to_string()));
+    }
+
+    job.cancel();
+    tracing::info!("Job {} cancelled by '{}'", job.id, user.username);
+    Ok(Json(job.to_response()))
+}
+
+/// Sends the archive a finished job produced.
+pub async fn download_output(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    let job = find(&state, &user, &id)?;
+    let Some((path, filename)) = job.download() else {
+        return Err(AppError::NotFound(format!("no download for job {}", id)));
+    };
+
+    let file = tokio::fs::File::open(&path).await?;
+    let size = file.metadata().await?.len();
+    let mime = mime_guess::from_path(&filename).first_or_octet_stream();
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, mime.as_ref())
+        .header(header::CONTENT_LENGTH, size)
+        .header(
+            header::CONTENT_DISPOSITION,
+            content_disposition(true, &filename),
+        )
+        .header(header::CACHE_CONTROL, "no-store")
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .body(Body::from_stream(ReaderStream::new(file)))
+        .unwrap())
+}
+
+/// Other users' jobs are reported missing.
+fn find(state: &AppState, user: &AuthUser, id: &str) -> Result<Arc<Job>, AppError> {
+    state
+        .jobs
+        .get(id)
+        .filter(|job| can_see(user, job))
+        .ok_or_else(|| AppError::NotFound(format!("job not found: {}", id)))
+}
+
+pub fn can_see(user: &AuthUser, job: &Job) -> bool {
+    user.role == Role::Admin || job.username == user.username
+}
+
+fn outcome<T: Serialize>(response: T) -> Result<Outcome, AppError> {
+    serde_json::to_value(response)
+        .map(Outcome::Value)
+        .map_err(|e| AppError::Internal(format!("job result: {}", e)))
+}
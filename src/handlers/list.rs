@@ -1,27 +1,45 @@
 use axum::{
     extract::{Query, State},
-    Json,
+    Extension, Json,
 };
 use chrono::{DateTime, Utc};
 use std::time::SystemTime;
 
+use crate::access::{self, Access};
+use crate::auth::AuthUser;
 use crate::error::AppError;
 use crate::models::{EntryType, FileEntry, ListQuery, ListResponse};
-use crate::security::validate_directory;
+use crate::security::{is_internal_name, validate_directory};
 use crate::AppState;
 
 pub async fn list_directory(
     State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
     Query(query): Query<ListQuery>,
 ) -> Result<Json<ListResponse>, AppError> {
-    let dir_path = validate_directory(&state.config.root_directory, &query.path)?;
+    let dir_path = access::validate(
+        &state.config,
+        &user,
+        &query.path,
+        Access::Read,
+        validate_directory,
+    )?;
+    let dir_rel = access::relative_to_root(&state.config, &dir_path)?;
 
     let mut entries = Vec::new();
     let mut dir = tokio::fs::read_dir(&dir_path).await?;
 
     while let Some(entry) = dir.next_entry().await? {
-        let metadata = entry.metadata().await?;
         let name = entry.file_name().to_string_lossy().to_string();
+        if is_internal_name(&name) {
+            continue;
+        }
+        let metadata = entry.metadata().await?;
+
+        // Hidden by an access rule: leave it out entirely
+        if !access::can_read(&state.config.access_rules, &user, &dir_rel.join(&name)) {
+            continue;
+        }
 
         let entry_type = if metadata.is_dir() {
             EntryType::Directory
@@ -33,7 +51,7 @@ pub async fn list_directory(
             let modified = metadata
                 .modified()
                 .ok()
-                .and_then(|t| system_time_to_datetime(t));
+                .and_then(system_time_to_datetime);
             (Some(metadata.len()), modified)
         } else {
             (None, None)
@@ -65,6 +83,5 @@ pub async fn list_directory(
 fn system_time_to_datetime(time: SystemTime) -> Option<DateTime<Utc>> {
     time.duration_since(SystemTime::UNIX_EPOCH)
         .ok()
-        .map(|d| DateTime::from_timestamp(d.as_secs() as i64, d.subsec_nanos()))
-        .flatten()
+        .and_then(|d| DateTime::from_timestamp(d.as_secs() as i64, d.subsec_nanos()))
 }
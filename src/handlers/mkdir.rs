@@ -1,5 +1,7 @@
-use axum::{extract::State, Json};
+use axum::{extract::State, Extension, Json};
 
+use crate::access::{self, Access};
+use crate::auth::AuthUser;
 use crate::error::AppError;
 use crate::models::{MkdirRequest, StatusResponse};
 use crate::security::validate_path;
@@ -7,14 +9,24 @@ use crate::AppState;
 
 pub async fn create_directory(
     State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
     Json(req): Json<MkdirRequest>,
 ) -> Result<Json<StatusResponse>, AppError> {
-    let dir_path = validate_path(&state.config.root_directory, &req.path)?;
+    let dir_path = access::validate(
+        &state.config,
+        &user,
+        &req.path,
+        Access::Create,
+        validate_path,
+    )?;
 
     if dir_path.exists() {
         return Err(AppError::Conflict("path already exists".to_string()));
     }
 
+    let rel = access::relative_to_root(&state.config, &dir_path)?;
+    state.dav_locks.guard_entry(&rel, &user.username)?;
+
     tokio::fs::create_dir(&dir_path).await?;
 
     Ok(Json(StatusResponse::ok()))
@@ -1,6 +1,16 @@
+pub mod archive;
+pub mod batch;
+pub mod copy;
+pub mod dav;
 pub mod delete;
+pub mod events;
+pub mod jobs;
 pub mod list;
 pub mod mkdir;
 pub mod move_file;
 pub mod serve;
+pub mod session;
+pub mod trash;
+pub mod tus;
 pub mod upload;
+pub mod ws;
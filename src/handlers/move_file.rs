@@ -1,16 +1,34 @@
-use axum::{extract::State, Json};
+use axum::{extract::State, Extension, Json};
 
+use crate::access::{self, Access};
+use crate::auth::AuthUser;
 use crate::error::AppError;
-use crate::models::{MoveRequest, StatusResponse};
-use crate::security::{validate_existing, validate_path};
+use crate::fsutil;
+use crate::handlers::delete;
+use crate::models::{MoveRequest, MoveResponse, OnConflict};
+use crate::security::{validate_directory, validate_existing, validate_path};
+use crate::storage;
 use crate::AppState;
 
 pub async fn move_file(
     State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
     Json(req): Json<MoveRequest>,
-) -> Result<Json<StatusResponse>, AppError> {
-    let from_path = validate_existing(&state.config.root_directory, &req.from)?;
-    let to_path = validate_path(&state.config.root_directory, &req.to)?;
+) -> Result<Json<MoveResponse>, AppError> {
+    let from_path = access::validate(
+        &state.config,
+        &user,
+        &req.from,
+        Access::Modify,
+        validate_existing,
+    )?;
+    let mut to_path = access::validate(
+        &state.config,
+        &user,
+        &req.to,
+        Access::Modify,
+        validate_path,
+    )?;
 
     // Prevent moving the root directory
     let canonical_root = state.config.root_directory.canonicalize()?;
@@ -21,8 +39,45 @@ pub async fn move_file(
     }
 
     // Check if destination exists
-    if to_path.exists() && !req.overwrite {
-        return Err(AppError::Conflict("destination already exists".to_string()));
+    let mut to = req.to.clone();
+    let mut replace = false;
+    if to_path.exists() {
+        match req.on_conflict() {
+            OnConflict::Fail => {
+                return Err(AppError::Conflict("destination already exists".to_string()));
+            }
+            OnConflict::Overwrite => {
+                // The replaced item can't hold, or be, what is being moved
+                if to_path == from_path || from_path.starts_with(&to_path) {
+                    return Err(AppError::Conflict(
+                        "cannot overwrite the item being moved or a folder containing it"
+                            .to_string(),
+                    ));
+                }
+                replace = true;
+            }
+            OnConflict::Rename => {
+                let (parent, filename) = match req.to.trim_end_matches('/').rsplit_once('/') {
+                    Some((parent, filename)) => (parent, filename),
+                    None => ("", req.to.trim_end_matches('/')),
+                };
+                let dir = validate_directory(&state.config.root_directory, parent)?;
+                let name = fsutil::unique_name(filename, |n| dir.join(n).exists());
+
+                to = if parent.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", parent, name)
+                };
+                to_path = access::validate(
+                    &state.config,
+                    &user,
+                    &to,
+                    Access::Modify,
+                    validate_path,
+                )?;
+            }
+        }
     }
 
     // Prevent moving a directory into itself
@@ -32,7 +87,43 @@ pub async fn move_file(
         ));
     }
 
-    tokio::fs::rename(&from_path, &to_path).await?;
+    let from_rel = access::relative_to_root(&state.config, &from_path)?;
+    let to_rel = access::relative_to_root(&state.config, &to_path)?;
+    state.dav_locks.guard_entry(&from_rel, &user.username)?;
+    state.dav_locks.guard_entry(&to_rel, &user.username)?;
+
+    // Moving into a folder with a quota adds the item to its usage
+    if storage::enters_quota(&state.config.quotas, &from_rel, &to_rel) {
+        let path = from_path.clone();
+        let size = tokio::task::spawn_blocking(move || {
+            if path.is_dir() {
+                storage::dir_usage(&path)
+            } else {
+                path.metadata().map(|m| m.len())
+            }
+        })
+        .await
+        .map_err(|e| AppError::Internal(format!("space check failed: {}", e)))??;
+
+        let to_dir = to_path.parent().unwrap_or(&canonical_root);
+        storage::budget(&state.config, to_dir, &to_rel)
+            .await?
+            .check_quota(size)?;
+    }
+
+    if replace {
+        delete::remove_replaced(&state, &to_path, &to_rel, &user.username).await?;
+    }
+
+    match tokio::fs::rename(&from_path, &to_path).await {
+        Ok(()) => {}
+        // A USB stick or SD card mounted below the root
+        Err(e) if fsutil::is_cross_device(&e) => {
+            tracing::info!("Moving {} across filesystems", from_path.display());
+            fsutil::move_across(&from_path, &to_path).await?;
+        }
+        Err(e) => return Err(e.into()),
+    }
 
-    Ok(Json(StatusResponse::ok()))
+    Ok(Json(MoveResponse { status: "ok", to }))
 }
@@ -1,25 +1,44 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
 use axum::{
-    body::Body,
+    body::{Body, Bytes},
     extract::{Query, State},
-    http::{header, StatusCode},
-    response::{IntoResponse, Response},
+    http::{header, HeaderMap, Method, StatusCode},
+    response::Response,
+    Extension,
 };
+use futures::{stream, StreamExt, TryStreamExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio_util::io::ReaderStream;
 
+use crate::access::{self, Access};
+use crate::auth::AuthUser;
 use crate::error::AppError;
-use crate::models::ListQuery;
+use crate::headers::{content_disposition, parse_range, ByteRange, RangeRequest, Validators};
+use crate::models::FileQuery;
 use crate::security::validate_file;
+use crate::util;
 use crate::AppState;
 
 pub async fn serve_file(
     State(state): State<AppState>,
-    Query(query): Query<ListQuery>,
+    Extension(user): Extension<AuthUser>,
+    method: Method,
+    headers: HeaderMap,
+    Query(query): Query<FileQuery>,
 ) -> Result<Response, AppError> {
-    let file_path = validate_file(&state.config.root_directory, &query.path)?;
+    let file_path = access::validate(
+        &state.config,
+        &user,
+        &query.path,
+        Access::Read,
+        validate_file,
+    )?;
 
-    let file = tokio::fs::File::open(&file_path).await?;
-    let stream = ReaderStream::new(file);
-    let body = Body::from_stream(stream);
+    let metadata = tokio::fs::metadata(&file_path).await?;
+    let size = metadata.len();
+    let validators = Validators::from_metadata(&metadata);
 
     let mime = mime_guess::from_path(&file_path)
         .first_or_octet_stream()
@@ -30,13 +49,139 @@ pub async fn serve_file(
         .map(|n| n.to_string_lossy().to_string())
         .unwrap_or_default();
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, mime)
-        .header(
-            header::CONTENT_DISPOSITION,
-            format!("inline; filename=\"{}\"", filename),
-        )
-        .body(body)
-        .unwrap())
+    // Always revalidate, but let clients reuse their copy via 304
+    let mut builder = Response::builder()
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(header::ETAG, &validators.etag)
+        .header(header::CACHE_CONTROL, "private, no-cache");
+    if let Some(last_modified) = validators.last_modified_header() {
+        builder = builder.header(header::LAST_MODIFIED, last_modified);
+    }
+
+    if validators.is_not_modified(&headers) {
+        return Ok(builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    let builder = builder.header(header::ACCEPT_RANGES, "bytes").header(
+        header::CONTENT_DISPOSITION,
+        content_disposition(query.download || runs_script(&mime), &filename),
+    );
+
+    // Range only applies to GET; HEAD describes the full representation.
+    // A stale If-Range means the client's partial copy is outdated.
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| method == Method::GET && validators.if_range_matches(&headers))
+        .map(|v| parse_range(v, size))
+        .unwrap_or(RangeRequest::Full);
+
+    let is_head = method == Method::HEAD;
+
+    match range {
+        RangeRequest::Unsatisfiable => Err(AppError::RangeNotSatisfiable(size)),
+
+        RangeRequest::Full => {
+            let body = if is_head {
+                Body::empty()
+            } else {
+                let file = tokio::fs::File::open(&file_path).await?;
+                Body::from_stream(ReaderStream::new(file))
+            };
+
+            Ok(builder
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, mime)
+                .header(header::CONTENT_LENGTH, size)
+                .body(body)
+                .unwrap())
+        }
+
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            let stream = open_range(&file_path, range).await?;
+
+            Ok(builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, mime)
+                .header(header::CONTENT_LENGTH, range.len())
+                .header(header::CONTENT_RANGE, range.content_range(size))
+                .body(Body::from_stream(stream))
+                .unwrap())
+        }
+
+        RangeRequest::Partial(ranges) => {
+            let boundary = util::random_id();
+            let (length, body) = multipart_body(file_path, ranges, size, &mime, &boundary);
+
+            Ok(builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    header::CONTENT_TYPE,
+                    format!("multipart/byteranges; boundary={}", boundary),
+                )
+                .header(header::CONTENT_LENGTH, length)
+                .body(body)
+                .unwrap())
+        }
+    }
+}
+
+/// Types a browser would run script from if shown inline; these are
+/// always downloaded instead.
+fn runs_script(mime: &str) -> bool {
+    matches!(
+        mime,
+        "text/html" | "application/xhtml+xml" | "image/svg+xml" | "text/xml" | "application/xml"
+    )
+}
+
+/// Streams one byte range of a file.
+async fn open_range(
+    path: &Path,
+    range: ByteRange,
+) -> std::io::Result<ReaderStream<tokio::io::Take<tokio::fs::File>>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(range.start)).await?;
+    Ok(ReaderStream::new(file.take(range.len())))
+}
+
+/// Builds a `multipart/byteranges` body and its exact length.
+/// Each part is read from disk only when the client gets to it.
+fn multipart_body(
+    path: PathBuf,
+    ranges: Vec<ByteRange>,
+    size: u64,
+    mime: &str,
+    boundary: &str,
+) -> (u64, Body) {
+    let mut length = 0;
+    let mut parts = Vec::with_capacity(ranges.len());
+
+    for range in ranges {
+        let head = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            boundary,
+            mime,
+            range.content_range(size)
+        );
+        length += head.len() as u64 + range.len();
+        parts.push((Bytes::from(head), range));
+    }
+
+    let tail = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+    length += tail.len() as u64;
+
+    let body = stream::iter(parts)
+        .flat_map(move |(head, range)| {
+            let path = path.clone();
+            stream::once(async move { Ok(head) })
+                .chain(stream::once(async move { open_range(&path, range).await }).try_flatten())
+        })
+        .chain(stream::once(async move { Ok(tail) }));
+
+    (length, Body::from_stream(body))
 }
@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::auth::{check_credentials, session_cookie, SESSION_COOKIE};
+use crate::error::AppError;
+use crate::models::{LoginRequest, StatusResponse};
+use crate::AppState;
+
+pub async fn login(
+    State(state): State<AppState>,
+    Json(req): Json<LoginRequest>,
+) -> Result<Response, AppError> {
+    let auth = &state.config.auth;
+    if !auth.enabled || !auth.session_login {
+        return Err(AppError::NotFound("session login is disabled".to_string()));
+    }
+
+    let user = check_credentials(auth, &req.username, req.password)
+        .await
+        .ok_or_else(|| AppError::Unauthorized("invalid username or password".to_string()))?;
+
+    let token = state
+        .sessions
+        .create(&user.username, Duration::from_secs(auth.session_ttl));
+    tracing::info!("User '{}' logged in", user.username);
+
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+        SESSION_COOKIE, token, auth.session_ttl
+    );
+
+    Ok(([(header::SET_COOKIE, cookie)], Json(StatusResponse::ok())).into_response())
+}
+
+pub async fn logout(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(token) = session_cookie(&headers) {
+        state.sessions.remove(token);
+    }
+
+    let cookie = format!(
+        "{}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0",
+        SESSION_COOKIE
+    );
+
+    ([(header::SET_COOKIE, cookie)], Json(StatusResponse::ok())).into_response()
+}
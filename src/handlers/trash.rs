@@ -0,0 +1,208 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use axum::{extract::State, Extension, Json};
+
+use crate::access::{self, Access};
+use crate::auth::AuthUser;
+use crate::error::AppError;
+use crate::fsutil;
+use crate::handlers::delete;
+use crate::models::{
+    EntryType, OnConflict, PurgeRequest, RestoreRequest, RestoreResponse, StatusResponse,
+    TrashEntry, TrashListResponse,
+};
+use crate::security::validate_path;
+use crate::trash::{self, TrashInfo};
+use crate::AppState;
+
+/// Lists trashed items whose original location the user can see.
+pub async fn list_trash(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Json<TrashListResponse>, AppError> {
+    let items = visible(&state, &user).await?;
+
+    Ok(Json(TrashListResponse {
+        items: items
+            .into_iter()
+            .map(|info| TrashEntry {
+                id: info.id,
+                path: info.path,
+                entry_type: if info.is_dir {
+                    EntryType::Directory
+                } else {
+                    EntryType::File
+                },
+                size: info.size,
+                deleted: info.deleted,
+                deleted_by: info.username,
+            })
+            .collect(),
+    }))
+}
+
+/// Moves an item back to where it was deleted from, recreating missing
+/// parent folders.
+pub async fn restore(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<RestoreRequest>,
+) -> Result<Json<RestoreResponse>, AppError> {
+    let root = &state.config.root_directory;
+    let (info, info_path, item_path) = trash::load(root, &req.id).await?;
+    let rel = PathBuf::from(&info.path);
+    access::check(&state.config, &user, &rel, Access::Modify).map_err(|e| hidden(e, &req.id))?;
+
+    // Settle the destination before creating anything, so a rejected
+    // restore leaves no empty folders behind
+    let parent = rel.parent().unwrap_or(Path::new(""));
+    let mut dest_rel = rel.clone();
+    let mut replace = false;
+    if exists(&state, &user, &dest_rel).await? {
+        match req.conflict.unwrap_or_default() {
+            OnConflict::Fail => {
+                return Err(AppError::Conflict(format!(
+                    "'{}' already exists",
+                    info.path
+                )));
+            }
+            OnConflict::Overwrite => replace = true,
+            OnConflict::Rename => {
+                let dir = validate(&state, &user, parent)?;
+                let filename = rel
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let name = fsutil::unique_name(&filename, |n| dir.join(n).exists());
+                dest_rel = parent.join(name);
+            }
+        }
+    }
+    state.dav_locks.guard_entry(&dest_rel, &user.username)?;
+
+    create_parents(&state, &user, parent).await?;
+    let dest_path = validate(&state, &user, &dest_rel)?;
+    if replace {
+        delete::remove_replaced(&state, &dest_path, &dest_rel, &user.username).await?;
+    }
+
+    let moved = match tokio::fs::rename(&item_path, &dest_path).await {
+        Err(e) if fsutil::is_cross_device(&e) => fsutil::move_across(&item_path, &dest_path).await,
+        result => result,
+    };
+    moved.map_err(|e| match e.kind() {
+        ErrorKind::AlreadyExists
+        | ErrorKind::DirectoryNotEmpty
+        | ErrorKind::IsADirectory
+        | ErrorKind::NotADirectory => {
+            AppError::Conflict(format!("'{}' already exists", dest_rel.display()))
+        }
+        _ => e.into(),
+    })?;
+    tokio::fs::remove_file(&info_path).await?;
+
+    let path = dest_rel.to_string_lossy().to_string();
+    tracing::info!("Restored {} from trash to {}", info.id, path);
+    Ok(Json(RestoreResponse { status: "ok", path }))
+}
+
+/// Removes one item, or everything the user can see, for good.
+pub async fn purge(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<PurgeRequest>,
+) -> Result<Json<StatusResponse>, AppError> {
+    if !state.config.enable_delete {
+        return Err(AppError::Forbidden);
+    }
+
+    let root = &state.config.root_directory;
+    let items = match req.id {
+        Some(id) => {
+            let (info, _, _) = trash::load(root, &id).await?;
+            access::check(&state.config, &user, Path::new(&info.path), Access::Modify)
+                .map_err(|e| hidden(e, &id))?;
+            vec![info]
+        }
+        None => {
+            let items = visible(&state, &user).await?;
+            // Emptying the trash only removes what the user could delete
+            items
+                .into_iter()
+                .filter(|info| {
+                    access::check(&state.config, &user, Path::new(&info.path), Access::Modify)
+                        .is_ok()
+                })
+                .collect()
+        }
+    };
+
+    for info in &items {
+        trash::purge(root, info).await?;
+        tracing::info!("Purged {} ({}) from trash", info.path, info.id);
+    }
+
+    Ok(Json(StatusResponse::ok()))
+}
+
+async fn visible(state: &AppState, user: &AuthUser) -> Result<Vec<TrashInfo>, AppError> {
+    let items = trash::list(&state.config.root_directory).await?;
+    Ok(items
+        .into_iter()
+        .filter(|info| access::can_read(&state.config.access_rules, user, Path::new(&info.path)))
+        .collect())
+}
+
+/// Whether something is at `rel`; a missing parent folder means no.
+async fn exists(state: &AppState, user: &AuthUser, rel: &Path) -> Result<bool, AppError> {
+    match validate(state, user, rel) {
+        Ok(path) => Ok(tokio::fs::symlink_metadata(&path).await.is_ok()),
+        Err(AppError::NotFound(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn validate(state: &AppState, user: &AuthUser, rel: &Path) -> Result<PathBuf, AppError> {
+    access::validate(
+        &state.config,
+        user,
+        &rel.to_string_lossy(),
+        Access::Modify,
+        validate_path,
+    )
+}
+
+/// Recreates the folders an item was deleted from, if they are gone too.
+async fn create_parents(state: &AppState, user: &AuthUser, dir_rel: &Path) -> Result<(), AppError> {
+    let mut ancestors: Vec<&Path> = dir_rel
+        .ancestors()
+        .filter(|a| !a.as_os_str().is_empty())
+        .collect();
+    ancestors.reverse();
+
+    for rel in ancestors {
+        let path = validate(state, user, rel)?;
+        match tokio::fs::create_dir(&path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && path.is_dir() => {}
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Err(AppError::Conflict(format!(
+                    "'{}' exists and is not a folder",
+                    rel.display()
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Items the user may not see answer like missing ones.
+fn hidden(err: AppError, id: &str) -> AppError {
+    match err {
+        AppError::NotFound(_) => AppError::NotFound(format!("trash item not found: {}", id)),
+        other => other,
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::HashSet;
+use std::path::Path as FsPath;
+
+use axum::{
+    body::Body,
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use futures::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::access::{self, Access};
+use crate::auth::AuthUser;
+use crate::error::AppError;
+use crate::fsutil;
+use crate::handlers::upload::destination;
+use crate::mime_policy::{self, SNIFF_LEN};
+use crate::models::OnConflict;
+use crate::security::validate_directory;
+use crate::storage;
+use crate::tus::{self, UploadInfo, TUS_EXTENSIONS, TUS_VERSION};
+use crate::util;
+use crate::AppState;
+
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// Checks `Tus-Resumable` on requests and adds it to every response.
+pub async fn protocol(req: Request, next: Next) -> Response {
+    let supported = req.method() == Method::OPTIONS
+        || req
+            .headers()
+            .get("tus-resumable")
+            .is_some_and(|v| v == TUS_VERSION);
+
+    let mut response = if supported {
+        next.run(req).await
+    } else {
+        let mut response =
+            AppError::PreconditionFailed("unsupported tus version".to_string()).into_response();
+        response
+            .headers_mut()
+            .insert("tus-version", HeaderValue::from_static(TUS_VERSION));
+        response
+    };
+
+    response
+        .headers_mut()
+        .insert("tus-resumable", HeaderValue::from_static(TUS_VERSION));
+    response
+}
+
+pub async fn options(State(state): State<AppState>) -> Response {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header("tus-version", TUS_VERSION)
+        .header("tus-extension", TUS_EXTENSIONS)
+        .header("tus-max-size", state.config.max_upload_size)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Creation: validates the destination up front and starts an empty upload.
+pub async fn create_upload(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let length = header_u64(&headers, "upload-length")?;
+    if length > state.config.max_upload_size {
+        return Err(AppError::PayloadTooLarge);
+    }
+
+    let metadata = tus::parse_metadata(header_str(&headers, "upload-metadata").unwrap_or(""))?;
+    let filename = metadata
+        .get("filename")
+        .cloned()
+        .ok_or_else(|| AppError::BadRequest("missing filename".to_string()))?;
+    let dir = metadata.get("path").cloned().unwrap_or_default();
+    let overwrite = metadata
+        .get("overwrite")
+        .is_some_and(|v| matches!(v.as_str(), "" | "1" | "true"));
+    let conflict = match metadata.get("conflict").map(String::as_str) {
+        None => None,
+        Some("fail") => Some(OnConflict::Fail),
+        Some("overwrite") => Some(OnConflict::Overwrite),
+        Some("rename") => Some(OnConflict::Rename),
+        Some(other) => {
+            return Err(AppError::BadRequest(format!(
+                "invalid conflict strategy '{}'",
+                other
+            )))
+        }
+    };
+    let conflict = OnConflict::resolve(conflict, overwrite);
+
+    // Fall back to the extension, as browsers do for multipart uploads
+    let mime = metadata
+        .get("filetype")
+        .filter(|t| !t.is_empty())
+        .cloned()
+        .unwrap_or_else(|| {
+            mime_guess::from_path(&filename)
+                .first_or_octet_stream()
+                .to_string()
+        });
+
+    let dest_dir = access::validate(
+        &state.config,
+        &user,
+        &dir,
+        Access::Create,
+        validate_directory,
+    )?;
+    let dest_rel = access::relative_to_root(&state.config, &dest_dir)?;
+    destination(
+        &state,
+        &user,
+        &dest_rel,
+        &filename,
+        conflict,
+        &HashSet::new(),
+    )?;
+    mime_policy::check_declared(&state.config.upload_policy, &dest_rel, &filename, &mime)?;
+    storage::budget(&state.config, &dest_dir, &dest_rel)
+        .await?
+        .check(length)?;
+
+    let info = UploadInfo {
+        id: util::random_id(),
+        username: user.username.clone(),
+        dir: dest_rel.to_string_lossy().to_string(),
+        filename,
+        mime,
+        length,
+        conflict,
+    };
+
+    let root = &state.config.root_directory;
+    let (info_path, part_path) = tus::upload_paths(root, &info.id)?;
+    tokio::fs::create_dir_all(tus::staging_dir(root)).await?;
+    tokio::fs::File::create(&part_path).await?;
+    tokio::fs::write(&info_path, serde_json::to_vec(&info).unwrap()).await?;
+
+    tracing::info!(
+        "Resumable upload {} started: {}/{} ({} bytes)",
+        info.id,
+        info.dir,
+        info.filename,
+        info.length
+    );
+
+    // An empty file is complete as soon as it exists
+    if length == 0 {
+        finalize(&state, &user, &info, &part_path, &info_path).await?;
+    }
+
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .header(header::LOCATION, format!("/api/tus/{}", info.id))
+        .header("upload-offset", 0)
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub async fn upload_status(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    let (info, _, part_path) = load(&state, &user, &id).await?;
+    let offset = tokio::fs::metadata(&part_path).await?.len();
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("upload-offset", offset)
+        .header("upload-length", info.length)
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// Appends a chunk at `Upload-Offset`. Whatever arrives before a dropped
+/// connection is kept, so the client can resume from the new offset.
+pub async fn upload_chunk(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, AppError> {
+    if header_str(&headers, header::CONTENT_TYPE.as_str()) != Some(OFFSET_CONTENT_TYPE) {
+        return Err(AppError::UnsupportedMediaType(format!(
+            "expected {}",
+            OFFSET_CONTENT_TYPE
+        )));
+    }
+    let client_offset = header_u64(&headers, "upload-offset")?;
+
+    let _lock = state.uploads.lock(&id)?;
+    let (info, info_path, part_path) = load(&state, &user, &id).await?;
+
+    let mut offset = tokio::fs::metadata(&part_path).await?.len();
+    if client_offset != offset {
+        return Err(AppError::Conflict(format!(
+            "offset mismatch: upload is at {}",
+            offset
+        )));
+    }
+
+    // Other writes may have used up the space since the upload was created
+    let dest_dir = access::validate(
+        &state.config,
+        &user,
+        &info.dir,
+        Access::Create,
+        validate_directory,
+    )?;
+    storage::budget(&state.config, &dest_dir, FsPath::new(&info.dir))
+        .await?
+        .check(info.length - offset)?;
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(&part_path)
+        .await?;
+    let mut stream = body.into_data_stream();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                file.flush().await?;
+                tracing::info!("Resumable upload {} interrupted at {}", id, offset);
+                return Err(AppError::BadRequest(format!("upload error: {}", e)));
+            }
+        };
+
+        if offset + chunk.len() as u64 > info.length {
+            file.flush().await?;
+            return Err(AppError::BadRequest(
+                "chunk exceeds Upload-Length".to_string(),
+            ));
+        }
+
+        file.write_all(&chunk).await?;
+        offset += chunk.len() as u64;
+    }
+
+    file.flush().await?;
+    drop(file);
+
+    // A PATCH at the final offset also retries a finalize that failed
+    if offset == info.length {
+        finalize(&state, &user, &info, &part_path, &info_path).await?;
+    }
+
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header("upload-offset", offset)
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// Termination: discards an unfinished upload.
+pub async fn delete_upload(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let _lock = state.uploads.lock(&id)?;
+    let (_, info_path, part_path) = load(&state, &user, &id).await?;
+
+    tokio::fs::remove_file(&part_path).await?;
+    tokio::fs::remove_file(&info_path).await?;
+    tracing::info!("Resumable upload {} terminated", id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Loads an upload's metadata. Other users' uploads are reported missing.
+async fn load(
+    state: &AppState,
+    user: &AuthUser,
+    id: &str,
+) -> Result<(UploadInfo, std::path::PathBuf, std::path::PathBuf), AppError> {
+    let not_found = || AppError::NotFound(format!("upload not found: {}", id));
+
+    let (info_path, part_path) = tus::upload_paths(&state.config.root_directory, id)?;
+    let data = tokio::fs::read(&info_path).await.map_err(|_| not_found())?;
+    let info: UploadInfo = serde_json::from_slice(&data)
+        .map_err(|e| AppError::Internal(format!("corrupt upload info {}: {}", id, e)))?;
+
+    if info.username != user.username {
+        return Err(not_found());
+    }
+
+    Ok((info, info_path, part_path))
+}
+
+/// Moves a complete upload into place, re-checking the destination since
+/// access rules or the directory may have changed in the meantime.
+async fn finalize(
+    state: &AppState,
+    user: &AuthUser,
+    info: &UploadInfo,
+    part_path: &FsPath,
+    info_path: &FsPath,
+) -> Result<(), AppError> {
+    let (dest_path, _) = destination(
+        state,
+        user,
+        FsPath::new(&info.dir),
+        &info.filename,
+        info.conflict,
+        &HashSet::new(),
+    )?;
+
+    // Content can only be checked now; a rejected file is discarded
+    if let Err(e) = check_part_content(state, info, part_path).await {
+        let _ = tokio::fs::remove_file(part_path).await;
+        let _ = tokio::fs::remove_file(info_path).await;
+        return Err(e);
+    }
+
+    fsutil::commit_rename(part_path, &dest_path).await?;
+    tokio::fs::remove_file(info_path).await?;
+
+    tracing::info!(
+        "Uploaded file: {} ({} bytes, resumable)",
+        dest_path.display(),
+        info.length
+    );
+    Ok(())
+}
+
+async fn check_part_content(
+    state: &AppState,
+    info: &UploadInfo,
+    part_path: &FsPath,
+) -> Result<(), AppError> {
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    tokio::fs::File::open(part_path)
+        .await?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut head)
+        .await?;
+
+    mime_policy::check_content(
+        &state.config.upload_policy,
+        FsPath::new(&info.dir),
+        &info.filename,
+        &info.mime,
+        &head,
+    )
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Result<u64, AppError> {
+    header_str(headers, name)
+        .and_then(|v| v.trim().parse().ok())
+        .ok_or_else(|| AppError::BadRequest(format!("missing or invalid {}", name)))
+}
@@ -1,63 +1,341 @@
 use axum::{
-    extract::{Multipart, Query, State},
-    Json,
+    extract::{
+        multipart::{Field, MultipartError},
+        Multipart, Query, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
 };
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use tokio::io::AsyncWriteExt;
 
+use crate::access::{self, Access};
+use crate::auth::AuthUser;
+use crate::checksum::{Checksum, Digests, Expected};
 use crate::error::AppError;
-use crate::models::{StatusResponse, UploadQuery};
-use crate::security::{validate_directory, validate_filename};
+use crate::events::UploadReporter;
+use crate::fsutil::{self, StagedFile};
+use crate::mime_policy::{self, ContentCheck};
+use crate::models::{OnConflict, OnError, UploadQuery, UploadResponse, UploadResult, UploadStatus};
+use crate::security::{
+    validate_directory, validate_filename, validate_path, validate_relative_path,
+};
+use crate::storage;
 use crate::AppState;
 
-const ALLOWED_AUDIO: &[&str] = &["audio/mpeg", "audio/mp3"];
-const ALLOWED_IMAGE_PREFIX: &str = "image/";
-
 pub async fn upload_file(
     State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
     Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
     mut multipart: Multipart,
-) -> Result<Json<StatusResponse>, AppError> {
-    let dest_dir = validate_directory(&state.config.root_directory, &query.path)?;
+) -> Result<Response, AppError> {
+    let dest_dir = access::validate(
+        &state.config,
+        &user,
+        &query.path,
+        Access::Create,
+        validate_directory,
+    )?;
+    let dest_rel = access::relative_to_root(&state.config, &dest_dir)?;
 
-    while let Some(mut field) = multipart
-        .next_field()
-        .await
-        .map_err(|e| AppError::BadRequest(format!("multipart error: {}", e)))?
-    {
-        let filename = field
-            .file_name()
-            .ok_or_else(|| AppError::BadRequest("missing filename".to_string()))?
-            .to_string();
-
-        validate_filename(&filename)?;
-
-        // Check MIME type
-        let content_type = field
-            .content_type()
-            .ok_or_else(|| AppError::BadRequest("missing content type".to_string()))?
-            .to_string();
-
-        if !is_allowed_mime(&content_type) {
-            return Err(AppError::UnsupportedMediaType(format!(
-                "type '{}' not allowed, only MP3 and images accepted",
-                content_type
+    // A digest of the whole multipart body can't be checked against any one
+    // file, and quietly ignoring it would look like a verified upload
+    for name in ["Content-MD5", "Digest"] {
+        if headers.contains_key(name) {
+            return Err(AppError::BadRequest(format!(
+                "{} is only supported on each file part, not on the request",
+                name
             )));
         }
+    }
+
+    // Refuse early what can't fit; the multipart overhead errs on the safe side
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let mut batch = Batch::default();
+    if let Some(length) = content_length {
+        storage::budget_with(&state.config, &dest_dir, &dest_rel, &mut batch.usage)
+            .await?
+            .check(length)?;
+    }
 
-        let dest_path = dest_dir.join(&filename);
+    let mut files: Vec<UploadResult> = Vec::new();
+    let mut fields = PartFields::default();
+    let mut first_error: Option<(StatusCode, String)> = None;
+    // Rollback mode holds every file back until the whole batch succeeded
+    let mut held: Vec<(usize, Received)> = Vec::new();
 
-        // Check overwrite
-        if dest_path.exists() && !query.overwrite {
-            return Err(AppError::Conflict(format!(
-                "file '{}' already exists",
-                filename
-            )));
+    // A broken request body ends the loop, but the files handled so far
+    // are still reported
+    let broken = |e: MultipartError| {
+        AppError::BadRequest(format!("multipart error: {}", e)).status_and_message()
+    };
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => {
+                first_error.get_or_insert(broken(e));
+                break;
+            }
+        };
+
+        // Text parts describe the file part that follows them
+        if let Some(slot) = fields.slot(field.name()) {
+            match field.text().await {
+                Ok(value) => *slot = Some(value),
+                Err(e) => {
+                    first_error.get_or_insert(broken(e));
+                    break;
+                }
+            }
+            continue;
         }
 
-        // Stream to file using chunk() method
-        let mut file = tokio::fs::File::create(&dest_path).await?;
-        let mut total_size: u64 = 0;
+        let part = std::mem::take(&mut fields);
+        let name = part
+            .relative_path
+            .clone()
+            .unwrap_or_else(|| field.file_name().unwrap_or_default().to_string());
+
+        // Dropping the field skips over its data
+        if first_error.is_some() && query.on_error != OnError::Continue {
+            files.push(UploadResult::new(name, UploadStatus::Skipped, 0, None));
+            continue;
+        }
+
+        let received = receive(
+            &state,
+            &user,
+            &dest_rel,
+            &query,
+            &mut batch,
+            &part,
+            field,
+        )
+        .await;
+        let saved_as = received.as_ref().ok().and_then(|r| r.saved_as.clone());
+        let digests = received.as_ref().ok().map(|r| r.digests.clone());
+        let result = match received {
+            Ok(received) if query.on_error == OnError::Rollback => {
+                let bytes = received.bytes;
+                held.push((files.len(), received));
+                Ok(bytes)
+            }
+            Ok(received) => commit(received).await,
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(bytes) => {
+                let mut result = UploadResult::new(name, UploadStatus::Ok, bytes, None);
+                result.saved_as = saved_as;
+                if let Some(digests) = digests {
+                    result.sha256 = Some(digests.sha256);
+                    result.md5 = digests.md5;
+                }
+                files.push(result);
+            }
+            Err((bytes, e)) => {
+                let (status, message) = e.status_and_message();
+                files.push(UploadResult::new(
+                    name,
+                    UploadStatus::Failed,
+                    bytes,
+                    Some(message.clone()),
+                ));
+                first_error.get_or_insert((status, message));
+            }
+        }
+    }
 
+    // Fields after the last file part have no file to apply to
+    if fields.relative_path.is_some() || fields.sha256.is_some() {
+        first_error.get_or_insert(
+            AppError::BadRequest(
+                "relative_path and sha256 must come before their file part".to_string(),
+            )
+            .status_and_message(),
+        );
+    }
+
+    if first_error.is_some() {
+        // Discarding the staged files is the rollback
+        for (index, _) in held {
+            files[index].status = UploadStatus::RolledBack;
+        }
+    } else {
+        for (index, received) in held {
+            if let Err((_, e)) = commit(received).await {
+                // Too late to undo the files already renamed into place
+                let (status, message) = e.status_and_message();
+                files[index].status = UploadStatus::Failed;
+                files[index].error = Some(message.clone());
+                first_error = Some((status, message));
+                break;
+            }
+        }
+    }
+
+    // Folders created for files that then failed or were rolled back
+    for dir in batch.created_dirs.iter().rev() {
+        let _ = tokio::fs::remove_dir(dir).await;
+    }
+
+    let (status, error) = match first_error {
+        Some((status, message)) => (status, Some(message)),
+        None => (StatusCode::OK, None),
+    };
+
+    let response = UploadResponse {
+        status: if error.is_none() { "ok" } else { "error" },
+        error,
+        files,
+    };
+    Ok((status, Json(response)).into_response())
+}
+
+/// State shared by the files of one upload request.
+#[derive(Default)]
+struct Batch {
+    /// Root-relative paths claimed so far.
+    names: HashSet<PathBuf>,
+    /// Folders created for folder uploads, removed again if left empty.
+    created_dirs: Vec<PathBuf>,
+    /// Quota usage, walked once and then kept up to date per file.
+    usage: storage::Usage,
+}
+
+/// Text parts sent before a file part, applying to that file only.
+#[derive(Default)]
+struct PartFields {
+    /// Path below the upload directory, for folder uploads.
+    relative_path: Option<String>,
+    /// Expected SHA-256, hex.
+    sha256: Option<String>,
+}
+
+impl PartFields {
+    fn slot(&mut self, name: Option<&str>) -> Option<&mut Option<String>> {
+        match name? {
+            "relative_path" => Some(&mut self.relative_path),
+            "sha256" => Some(&mut self.sha256),
+            _ => None,
+        }
+    }
+
+    /// Expected digests from the `sha256` field and the part's own
+    /// `Content-MD5` and `Digest` headers.
+    fn expected(&self, field: &Field<'_>) -> Result<Vec<Expected>, AppError> {
+        let mut expected = Vec::new();
+        if let Some(sha256) = &self.sha256 {
+            expected.push(Expected::sha256_hex(sha256)?);
+        }
+
+        let header = |name: &str| {
+            field.headers().get(name).map(|v| {
+                v.to_str()
+                    .map_err(|_| AppError::BadRequest(format!("invalid {} header", name)))
+            })
+        };
+        if let Some(value) = header("content-md5") {
+            expected.push(Expected::content_md5(value?)?);
+        }
+        if let Some(value) = header("digest") {
+            expected.extend(Expected::digest_header(value?)?);
+        }
+
+        Ok(expected)
+    }
+}
+
+/// A fully received file, staged next to its destination.
+struct Received {
+    staged: StagedFile,
+    dest_path: PathBuf,
+    bytes: u64,
+    saved_as: Option<String>,
+    digests: Digests,
+}
+
+/// Validates and streams one multipart field to a temp file, hashing it on
+/// the way. With a `relative_path`, missing folders below `dest_rel` are
+/// created first. Errors carry the number of bytes received before the
+/// failure.
+async fn receive(
+    state: &AppState,
+    user: &AuthUser,
+    dest_rel: &Path,
+    query: &UploadQuery,
+    batch: &mut Batch,
+    part: &PartFields,
+    mut field: Field<'_>,
+) -> Result<Received, (u64, AppError)> {
+    let expected = part.expected(&field).map_err(|e| (0, e))?;
+    let (folders, filename) = match part.relative_path.as_deref() {
+        Some(rel) => {
+            let mut segments = validate_relative_path(rel).map_err(|e| (0, e))?;
+            let filename = segments.pop().unwrap_or_default().to_string();
+            (segments, filename)
+        }
+        None => {
+            let filename = field
+                .file_name()
+                .ok_or_else(|| (0, AppError::BadRequest("missing filename".to_string())))?
+                .to_string();
+            (Vec::new(), filename)
+        }
+    };
+    let dest_rel = &dest_rel.join(folders.iter().collect::<PathBuf>());
+
+    let content_type = field
+        .content_type()
+        .ok_or_else(|| (0, AppError::BadRequest("missing content type".to_string())))?
+        .to_string();
+
+    let policy = &state.config.upload_policy;
+    mime_policy::check_declared(policy, dest_rel, &filename, &content_type).map_err(|e| (0, e))?;
+
+    create_folders(state, user, dest_rel, folders.len(), batch)
+        .await
+        .map_err(|e| (0, e))?;
+    let (dest_path, final_name) = destination(
+        state,
+        user,
+        dest_rel,
+        &filename,
+        query.on_conflict(),
+        &batch.names,
+    )
+    .map_err(|e| (0, e))?;
+    batch.names.insert(dest_rel.join(&final_name));
+
+    // Earlier files of the batch were recorded in `batch.usage` as they landed
+    let dest_dir = dest_path.parent().unwrap_or(&state.config.root_directory);
+    let budget = storage::budget_with(&state.config, dest_dir, dest_rel, &mut batch.usage)
+        .await
+        .map_err(|e| (0, e))?;
+
+    // Stream to a temp file; the destination only changes on commit
+    let mut staged = StagedFile::create(&dest_path)
+        .await
+        .map_err(|e| (0, e.into()))?;
+    let mut content = ContentCheck::new(policy, dest_rel, &final_name, &content_type);
+    let mut checksum = Checksum::new(expected);
+    let mut total_size: u64 = 0;
+    let mut progress = UploadReporter::start(
+        state.events.clone(),
+        dest_rel.join(&final_name).to_string_lossy().to_string(),
+        user.username.clone(),
+    );
+
+    let result: Result<(), AppError> = async {
         while let Some(chunk) = field
             .chunk()
             .await
@@ -66,39 +344,152 @@ pub async fn upload_file(
             total_size += chunk.len() as u64;
 
             // Check size limit during streaming
+            // (the temp file is removed when `staged` is dropped)
             if total_size > state.config.max_upload_size {
-                // Clean up partial file
-                drop(file);
-                let _ = tokio::fs::remove_file(&dest_path).await;
                 return Err(AppError::PayloadTooLarge);
             }
+            budget.check(total_size)?;
 
-            file.write_all(&chunk).await?;
+            checksum.update(&chunk);
+            progress.advance(chunk.len() as u64);
+            staged.file().write_all(&content.feed(chunk)?).await?;
         }
 
-        file.flush().await?;
-        tracing::info!(
-            "Uploaded file: {} ({} bytes)",
-            dest_path.display(),
-            total_size
-        );
+        staged.file().write_all(&content.finish()?).await?;
+        Ok(())
     }
+    .await;
+    result.map_err(|e| (total_size, e))?;
+
+    // A mismatch drops `staged`, which removes the temp file
+    let digests = checksum.finish().map_err(|e| (total_size, e))?;
+    progress.finish();
+    batch.usage.record(&state.config, dest_rel, total_size);
 
-    Ok(Json(StatusResponse::ok()))
+    Ok(Received {
+        staged,
+        dest_path,
+        bytes: total_size,
+        saved_as: (final_name != filename).then_some(final_name),
+        digests,
+    })
 }
 
-fn is_allowed_mime(mime: &str) -> bool {
-    let mime_lower = mime.to_lowercase();
+/// Creates the last `count` components of `dir_rel` where missing.
+/// Existing folders are merged into, whatever the conflict strategy.
+async fn create_folders(
+    state: &AppState,
+    user: &AuthUser,
+    dir_rel: &Path,
+    count: usize,
+    batch: &mut Batch,
+) -> Result<(), AppError> {
+    let mut ancestors: Vec<&Path> = dir_rel.ancestors().take(count).collect();
+    ancestors.reverse();
 
-    // Check audio types
-    if ALLOWED_AUDIO.iter().any(|&allowed| mime_lower == allowed) {
-        return true;
+    for rel in ancestors {
+        let path = access::validate(
+            &state.config,
+            user,
+            &rel.to_string_lossy(),
+            Access::Create,
+            validate_path,
+        )?;
+
+        if !path.is_dir() {
+            state.dav_locks.guard_entry(rel, &user.username)?;
+        }
+        match tokio::fs::create_dir(&path).await {
+            Ok(()) => batch.created_dirs.push(path),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && path.is_dir() => {}
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Err(AppError::Conflict(format!(
+                    "'{}' exists and is not a folder",
+                    rel.display()
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        }
     }
 
-    // Check image types
-    if mime_lower.starts_with(ALLOWED_IMAGE_PREFIX) {
-        return true;
+    Ok(())
+}
+
+async fn commit(received: Received) -> Result<u64, (u64, AppError)> {
+    let Received {
+        staged,
+        dest_path,
+        bytes,
+        ..
+    } = received;
+
+    staged.commit().await.map_err(|e| (bytes, e.into()))?;
+    tracing::info!("Uploaded file: {} ({} bytes)", dest_path.display(), bytes);
+    Ok(bytes)
+}
+
+/// Validates an upload's file name and resolves where it will be written,
+/// returning the path and final file name. `reserved` holds names already
+/// claimed by earlier files of the same upload.
+/// Shared with the resumable (tus) uploads, which finalize through here.
+pub fn destination(
+    state: &AppState,
+    user: &AuthUser,
+    dest_rel: &Path,
+    filename: &str,
+    conflict: OnConflict,
+    reserved: &HashSet<PathBuf>,
+) -> Result<(PathBuf, String), AppError> {
+    validate_filename(filename)?;
+
+    // Checked per file: a rule may cover this exact name
+    let validate = |name: &str, access: Access| {
+        access::validate(
+            &state.config,
+            user,
+            &dest_rel.join(name).to_string_lossy(),
+            access,
+            validate_path,
+        )
+    };
+    let dest_path = validate(filename, Access::Create)?;
+    let locks = &state.dav_locks;
+    let guard_new = |name: &str| locks.guard_entry(&dest_rel.join(name), &user.username);
+
+    // Two parts with the same name would silently replace each other
+    let duplicate = reserved.contains(&dest_rel.join(filename));
+    if !duplicate && !dest_path.exists() {
+        guard_new(filename)?;
+        return Ok((dest_path, filename.to_string()));
     }
 
-    false
+    match conflict {
+        OnConflict::Rename => {
+            let dir = access::validate(
+                &state.config,
+                user,
+                &dest_rel.to_string_lossy(),
+                Access::Create,
+                validate_path,
+            )?;
+            let name = fsutil::unique_name(filename, |name| {
+                reserved.contains(&dest_rel.join(name)) || dir.join(name).exists()
+            });
+            guard_new(&name)?;
+            Ok((validate(&name, Access::Create)?, name))
+        }
+        _ if duplicate => Err(AppError::Conflict(format!(
+            "file '{}' appears twice in the upload",
+            filename
+        ))),
+        // Replacing a file takes more than adding one
+        OnConflict::Overwrite => {
+            locks.guard(&dest_rel.join(filename), false, &user.username)?;
+            Ok((validate(filename, Access::Modify)?, filename.to_string()))
+        }
+        OnConflict::Fail => Err(AppError::Conflict(format!(
+            "file '{}' already exists",
+            filename
+        ))),
+    }
 }
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::Response,
+    Extension,
+};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::access::{self, Access};
+use crate::auth::AuthUser;
+use crate::error::AppError;
+use crate::events::Notice;
+use crate::handlers::{events, jobs};
+use crate::models::{JobStatus, WsMessage, WsRequest};
+use crate::security::{normalize_relative, validate_directory};
+use crate::AppState;
+
+/// How often job progress is checked for changes.
+const JOB_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Pushes changes and upload progress in the folders the client
+/// subscribed to, and the progress of the user's jobs (everyone's for
+/// admins). Nothing path-related is sent until the first `subscribe`.
+pub async fn websocket(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| session(socket, state, user))
+}
+
+async fn session(mut socket: WebSocket, state: AppState, user: AuthUser) {
+    let mut rx = state.events.subscribe();
+    let mut subscriptions: Vec<PathBuf> = Vec::new();
+    let mut jobs = tokio::time::interval(JOB_INTERVAL);
+    // Status, bytes and items last sent for each job
+    let mut sent: HashMap<String, (JobStatus, u64, u64)> = HashMap::new();
+
+    loop {
+        let messages = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    vec![request(&state, &user, &mut subscriptions, &text)]
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum
+                Some(Ok(_)) => continue,
+            },
+            notice = rx.recv() => match notice {
+                Ok(notice) => filter(&state, &user, &subscriptions, notice)
+                    .into_iter()
+                    .collect(),
+                Err(RecvError::Lagged(_)) => vec![WsMessage::Resync],
+                Err(RecvError::Closed) => break,
+            },
+            _ = jobs.tick() => job_updates(&state, &user, &mut sent),
+            _ = state.events.closed() => break,
+        };
+
+        for message in messages {
+            let text = serde_json::to_string(&message).expect("messages serialize");
+            if socket.send(Message::Text(text)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Applies a client request; answers with the subscriptions now in place.
+fn request(
+    state: &AppState,
+    user: &AuthUser,
+    subscriptions: &mut Vec<PathBuf>,
+    text: &str,
+) -> WsMessage {
+    let request = match serde_json::from_str::<WsRequest>(text) {
+        Ok(request) => request,
+        Err(e) => {
+            return WsMessage::Error {
+                error: format!("invalid message: {}", e),
+            }
+        }
+    };
+
+    let result: Result<(), AppError> = match request {
+        WsRequest::Subscribe { paths } => paths.iter().try_for_each(|path| {
+            let rel = subscription(state, user, path)?;
+            if !subscriptions.contains(&rel) {
+                subscriptions.push(rel);
+            }
+            Ok(())
+        }),
+        WsRequest::Unsubscribe { paths } => {
+            for path in paths {
+                let rel = normalize_relative(&path);
+                subscriptions.retain(|s| *s != rel);
+            }
+            Ok(())
+        }
+    };
+
+    match result {
+        Ok(()) => WsMessage::Subscribed {
+            paths: subscriptions
+                .iter()
+                .map(|rel| rel.to_string_lossy().to_string())
+                .collect(),
+        },
+        Err(e) => WsMessage::Error {
+            error: e.status_and_message().1,
+        },
+    }
+}
+
+/// Checks a folder to subscribe to as listing it would.
+fn subscription(state: &AppState, user: &AuthUser, path: &str) -> Result<PathBuf, AppError> {
+    let dir = access::validate(&state.config, user, path, Access::Read, validate_directory)?;
+    access::relative_to_root(&state.config, &dir)
+}
+
+/// What of a notice goes to this client.
+fn filter(
+    state: &AppState,
+    user: &AuthUser,
+    subscriptions: &[PathBuf],
+    notice: Notice,
+) -> Option<WsMessage> {
+    let rules = &state.config.access_rules;
+    let subscribed = |path: &str| subscriptions.iter().any(|s| Path::new(path).starts_with(s));
+
+    match notice {
+        Notice::Change(change) => {
+            let change = events::visible(rules, user, change)?;
+            let to = change.to.as_deref();
+            (subscribed(&change.path) || to.is_some_and(subscribed))
+                .then_some(WsMessage::Change { change })
+        }
+        Notice::Upload(progress) => (subscribed(&progress.path)
+            && access::can_read(rules, user, progress.path.as_ref()))
+        .then_some(WsMessage::Upload(progress)),
+        Notice::Resync => Some(WsMessage::Resync),
+    }
+}
+
+/// Jobs that changed since they were last sent.
+fn job_updates(
+    state: &AppState,
+    user: &AuthUser,
+    sent: &mut HashMap<String, (JobStatus, u64, u64)>,
+) -> Vec<WsMessage> {
+    let mut updates = Vec::new();
+    let mut seen = HashMap::new();
+
+    for job in state.jobs.list() {
+        if !jobs::can_see(user, &job) {
+            continue;
+        }
+        let response = job.to_response();
+        let key = (
+            response.status,
+            response.progress.bytes,
+            response.progress.items,
+        );
+        if sent.get(&response.id) != Some(&key) {
+            updates.push(WsMessage::Job(response));
+        }
+        seen.insert(job.id.clone(), key);
+    }
+
+    // Forget pruned jobs
+    *sent = seen;
+    updates
+}
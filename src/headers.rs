@@ -0,0 +1,433 @@
+use std::fs::Metadata;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::http::{header, HeaderMap};
+
+/// Upper bound on ranges served in one multipart response. Requests with
+/// more (after merging overlaps) get the whole file instead.
+const MAX_RANGES: usize = 16;
+
+/// An inclusive byte range within a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// `Content-Range` value for this range.
+    pub fn content_range(&self, size: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, size)
+    }
+}
+
+/// Outcome of evaluating a `Range` header against a file size.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// No usable `Range` header: send the whole file with 200.
+    Full,
+    /// One or more ranges to send with 206.
+    Partial(Vec<ByteRange>),
+    /// Syntactically valid but no range overlaps the file: 416.
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header (RFC 9110 §14.2) for a file of `size` bytes.
+///
+/// Malformed headers and unknown units are ignored, as the RFC allows.
+/// Overlapping and adjacent ranges are merged.
+pub fn parse_range(header: &str, size: u64) -> RangeRequest {
+    let Some((unit, specs)) = header.split_once('=') else {
+        return RangeRequest::Full;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return RangeRequest::Full;
+    }
+
+    let mut ranges = Vec::new();
+    let mut any_spec = false;
+    for spec in specs.split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        any_spec = true;
+        let Some((first, last)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+
+        let range = if first.is_empty() {
+            // Suffix range: the last N bytes
+            let Ok(suffix) = last.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            if suffix == 0 || size == 0 {
+                continue;
+            }
+            ByteRange {
+                start: size.saturating_sub(suffix),
+                end: size - 1,
+            }
+        } else {
+            let Ok(start) = first.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            let end = if last.is_empty() {
+                u64::MAX
+            } else {
+                match last.parse::<u64>() {
+                    Ok(end) if end >= start => end,
+                    _ => return RangeRequest::Full,
+                }
+            };
+            if start >= size {
+                continue;
+            }
+            ByteRange {
+                start,
+                end: end.min(size - 1),
+            }
+        };
+
+        ranges.push(range);
+    }
+
+    if !any_spec {
+        return RangeRequest::Full;
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    let ranges = merge(ranges);
+    if ranges.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+    RangeRequest::Partial(ranges)
+}
+
+fn merge(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    if ranges.len() < 2 {
+        return ranges;
+    }
+
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Cache validators for a file on disk.
+#[derive(Debug, Clone)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Derives an ETag from inode, size and modification time.
+    ///
+    /// Files modified within the last second get a weak ETag: the
+    /// filesystem timestamp may not change if they are written again
+    /// within the same tick, so byte-for-byte identity is not guaranteed.
+    pub fn from_metadata(meta: &Metadata) -> Self {
+        let modified = meta.modified().ok();
+        let mtime = modified
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+
+        let weak = modified
+            .and_then(|t| SystemTime::now().duration_since(t).ok())
+            .is_none_or(|age| age < Duration::from_secs(1));
+
+        let opaque = format!(
+            "{:x}-{:x}-{:x}.{:x}",
+            inode(meta),
+            meta.len(),
+            mtime.as_secs(),
+            mtime.subsec_nanos()
+        );
+
+        Self {
+            etag: if weak {
+                format!("W/\"{}\"", opaque)
+            } else {
+                format!("\"{}\"", opaque)
+            },
+            last_modified: modified,
+        }
+    }
+
+    /// `Last-Modified` header value.
+    pub fn last_modified_header(&self) -> Option<String> {
+        self.last_modified.map(httpdate::fmt_http_date)
+    }
+
+    /// Evaluates `If-None-Match` and `If-Modified-Since` (RFC 9110 §13.2.2)
+    /// for a GET or HEAD. Returns true if a 304 should be sent.
+    pub fn is_not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(inm) = header_str(headers, header::IF_NONE_MATCH) {
+            // If-Modified-Since is ignored when If-None-Match is present
+            return etag_list_matches(inm, &self.etag);
+        }
+
+        match (
+            header_str(headers, header::IF_MODIFIED_SINCE).and_then(parse_date),
+            self.last_modified,
+        ) {
+            (Some(since), Some(modified)) => truncate_to_secs(modified) <= since,
+            _ => false,
+        }
+    }
+
+    /// Evaluates `If-Range`: the `Range` header is honored only if the
+    /// client's copy is still current. Weak ETags never match.
+    pub fn if_range_matches(&self, headers: &HeaderMap) -> bool {
+        let Some(value) = header_str(headers, header::IF_RANGE) else {
+            return true;
+        };
+
+        if value.starts_with('"') || value.starts_with("W/") {
+            return strong_eq(value.trim(), &self.etag);
+        }
+
+        match (parse_date(value), self.last_modified) {
+            (Some(date), Some(modified)) => truncate_to_secs(modified) == date,
+            _ => false,
+        }
+    }
+}
+
+/// Builds a `Content-Disposition` value (RFC 6266).
+///
+/// The quoted `filename` is an ASCII fallback with quotes and backslashes
+/// escaped and anything else unsafe replaced by `_`. Names that are not
+/// plain ASCII also get `filename*` with the exact UTF-8 name (RFC 8187).
+pub fn content_disposition(attachment: bool, filename: &str) -> String {
+    let kind = if attachment { "attachment" } else { "inline" };
+
+    let mut fallback = String::with_capacity(filename.len());
+    for c in filename.chars() {
+        match c {
+            '"' | '\\' => {
+                fallback.push('\\');
+                fallback.push(c);
+            }
+            ' '..='~' => fallback.push(c),
+            _ => fallback.push('_'),
+        }
+    }
+
+    let plain = filename.chars().all(|c| matches!(c, ' '..='~'));
+    if plain {
+        return format!("{}; filename=\"{}\"", kind, fallback);
+    }
+
+    let mut encoded = String::with_capacity(filename.len() * 3);
+    for byte in filename.bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        kind, fallback, encoded
+    )
+}
+
+#[cfg(unix)]
+fn inode(meta: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(meta)
+}
+
+#[cfg(not(unix))]
+fn inode(_meta: &Metadata) -> u64 {
+    0
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn parse_date(value: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.trim()).ok()
+}
+
+/// HTTP dates have one-second resolution.
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Matches an `If-None-Match` list against an ETag (weak comparison).
+fn etag_list_matches(list: &str, etag: &str) -> bool {
+    list.split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || weak_eq(candidate, etag))
+}
+
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+fn strong_eq(a: &str, b: &str) -> bool {
+    !a.starts_with("W/") && !b.starts_with("W/") && a == b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn partial(ranges: &[(u64, u64)]) -> RangeRequest {
+        RangeRequest::Partial(
+            ranges
+                .iter()
+                .map(|&(start, end)| ByteRange { start, end })
+                .collect(),
+        )
+    }
+
+    fn validators(etag: &str, secs: u64) -> Validators {
+        Validators {
+            etag: etag.to_string(),
+            last_modified: Some(UNIX_EPOCH + Duration::from_millis(secs * 1000 + 250)),
+        }
+    }
+
+    fn with(name: header::HeaderName, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let v = validators("\"abc\"", 0);
+        assert!(v.is_not_modified(&with(header::IF_NONE_MATCH, "\"abc\"")));
+        assert!(v.is_not_modified(&with(header::IF_NONE_MATCH, "\"x\", W/\"abc\"")));
+        assert!(v.is_not_modified(&with(header::IF_NONE_MATCH, "*")));
+        assert!(!v.is_not_modified(&with(header::IF_NONE_MATCH, "\"other\"")));
+        assert!(!v.is_not_modified(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        // 1994-11-06 08:49:37 UTC
+        let v = validators("\"abc\"", 784111777);
+        assert!(v.is_not_modified(&with(
+            header::IF_MODIFIED_SINCE,
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        )));
+        assert!(!v.is_not_modified(&with(
+            header::IF_MODIFIED_SINCE,
+            "Sun, 06 Nov 1994 08:49:36 GMT"
+        )));
+
+        // If-None-Match takes precedence
+        let mut headers = with(header::IF_MODIFIED_SINCE, "Sun, 06 Nov 1994 08:49:37 GMT");
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        assert!(!v.is_not_modified(&headers));
+    }
+
+    #[test]
+    fn test_if_range() {
+        let v = validators("\"abc\"", 784111777);
+        assert!(v.if_range_matches(&HeaderMap::new()));
+        assert!(v.if_range_matches(&with(header::IF_RANGE, "\"abc\"")));
+        assert!(!v.if_range_matches(&with(header::IF_RANGE, "\"old\"")));
+        assert!(v.if_range_matches(&with(header::IF_RANGE, "Sun, 06 Nov 1994 08:49:37 GMT")));
+        assert!(!v.if_range_matches(&with(header::IF_RANGE, "Sun, 06 Nov 1994 08:49:38 GMT")));
+
+        // Weak validators never satisfy If-Range
+        let weak = validators("W/\"abc\"", 784111777);
+        assert!(!weak.if_range_matches(&with(header::IF_RANGE, "W/\"abc\"")));
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition(false, "song.mp3"),
+            "inline; filename=\"song.mp3\""
+        );
+        assert_eq!(
+            content_disposition(true, "say \"hi\".mp3"),
+            "attachment; filename=\"say \\\"hi\\\".mp3\""
+        );
+        assert_eq!(
+            content_disposition(false, "Canción ñ.mp3"),
+            "inline; filename=\"Canci_n _.mp3\"; filename*=UTF-8''Canci%C3%B3n%20%C3%B1.mp3"
+        );
+        // Header injection attempts end up as inert characters
+        assert_eq!(
+            content_disposition(false, "a\r\nSet-Cookie: x.mp3"),
+            "inline; filename=\"a__Set-Cookie: x.mp3\"; filename*=UTF-8''a%0D%0ASet-Cookie%3A%20x.mp3"
+        );
+    }
+
+    #[test]
+    fn test_single_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), partial(&[(0, 99)]));
+        assert_eq!(parse_range("bytes=500-", 1000), partial(&[(500, 999)]));
+        assert_eq!(parse_range("bytes=-100", 1000), partial(&[(900, 999)]));
+        // End past EOF is clamped, suffix longer than file is the whole file
+        assert_eq!(parse_range("bytes=900-5000", 1000), partial(&[(900, 999)]));
+        assert_eq!(parse_range("bytes=-5000", 1000), partial(&[(0, 999)]));
+    }
+
+    #[test]
+    fn test_multiple_ranges_merged() {
+        assert_eq!(
+            parse_range("bytes=0-9, 20-29", 100),
+            partial(&[(0, 9), (20, 29)])
+        );
+        assert_eq!(
+            parse_range("bytes=20-29,0-9,5-15,30-40", 100),
+            partial(&[(0, 15), (20, 40)])
+        );
+    }
+
+    #[test]
+    fn test_unsatisfiable() {
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-10", 0), RangeRequest::Unsatisfiable);
+        // One good range is enough
+        assert_eq!(parse_range("bytes=2000-3000,0-0", 1000), partial(&[(0, 0)]));
+    }
+
+    #[test]
+    fn test_malformed_ignored() {
+        assert_eq!(parse_range("items=0-10", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=10-5", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=abc", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=", 1000), RangeRequest::Full);
+
+        let many: Vec<String> = (0..40).map(|i| format!("{}-{}", i * 10, i * 10)).collect();
+        assert_eq!(
+            parse_range(&format!("bytes={}", many.join(",")), 1000),
+            RangeRequest::Full
+        );
+    }
+}
@@ -0,0 +1,152 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+
+use crate::error::AppError;
+use crate::AppState;
+
+/// Middleware rejecting peers outside `allowed_networks` with a 403.
+///
+/// Requires the server to be started with
+/// `into_make_service_with_connect_info::<SocketAddr>()`.
+pub async fn filter_ip(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let config = &state.config;
+    if config.allowed_networks.is_empty() {
+        return next.run(req).await;
+    }
+
+    let client = client_ip(peer.ip(), req.headers(), &config.trusted_proxies);
+
+    if !contains(&config.allowed_networks, client) {
+        tracing::warn!("Rejected request from {} (peer {})", client, peer);
+        return AppError::Forbidden.into_response();
+    }
+
+    next.run(req).await
+}
+
+/// Determines the real client address.
+///
+/// If the direct peer is a trusted proxy, `X-Forwarded-For` is walked from the
+/// right (most recent hop) and the first untrusted address wins. Headers from
+/// untrusted peers are ignored, since anyone can send them.
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let peer = normalize(peer);
+    if !contains(trusted_proxies, peer) {
+        return peer;
+    }
+
+    let hops: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .collect();
+
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        match hop.parse::<IpAddr>() {
+            Ok(ip) => {
+                client = normalize(ip);
+                if !contains(trusted_proxies, client) {
+                    break;
+                }
+            }
+            // A malformed hop breaks the chain; stop at the last good address
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+fn contains(networks: &[IpNet], ip: IpAddr) -> bool {
+    networks.iter().any(|net| net.contains(&ip))
+}
+
+/// Maps IPv4-mapped IPv6 addresses (`::ffff:192.168.1.5`) back to IPv4 so
+/// they match IPv4 networks when listening on a dual-stack socket.
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(v6)),
+        v4 => v4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn nets(list: &[&str]) -> Vec<IpNet> {
+        list.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_contains_v4_and_v6() {
+        let allowed = nets(&["192.168.1.0/24", "fd00::/8"]);
+        assert!(contains(&allowed, ip("192.168.1.42")));
+        assert!(!contains(&allowed, ip("192.168.2.1")));
+        assert!(contains(&allowed, ip("fd12:3456::1")));
+        assert!(!contains(&allowed, ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn test_mapped_ipv4_normalized() {
+        let headers = HeaderMap::new();
+        assert_eq!(
+            client_ip(ip("::ffff:192.168.1.5"), &headers, &[]),
+            ip("192.168.1.5")
+        );
+    }
+
+    #[test]
+    fn test_forwarded_for_ignored_from_untrusted_peer() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("192.168.1.5"));
+        assert_eq!(
+            client_ip(ip("203.0.113.9"), &headers, &nets(&["127.0.0.1/32"])),
+            ip("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn test_forwarded_for_from_trusted_proxy() {
+        let trusted = nets(&["127.0.0.1/32", "10.0.0.0/8"]);
+        let mut headers = HeaderMap::new();
+
+        // Spoofed leftmost entry is skipped; rightmost untrusted hop wins
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("192.168.1.5, 203.0.113.9, 10.0.0.2"),
+        );
+        assert_eq!(
+            client_ip(ip("127.0.0.1"), &headers, &trusted),
+            ip("203.0.113.9")
+        );
+
+        // No header: the proxy itself is the client
+        assert_eq!(
+            client_ip(ip("127.0.0.1"), &HeaderMap::new(), &trusted),
+            ip("127.0.0.1")
+        );
+    }
+}
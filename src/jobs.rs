@@ -0,0 +1,350 @@
+//! Background jobs for operations that can outlast an HTTP request.
+//!
+//! A job runs the same code as the matching endpoint, on a pool bounded by
+//! `[jobs] workers`. While it runs, the job is available as a task-local,
+//! so the file copy loops can report progress and notice cancellation
+//! without every function taking extra arguments; outside a job those
+//! calls do nothing. Finished jobs are kept for `[jobs] result_ttl`.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::AppError;
+use crate::models::{JobProgress, JobResponse, JobStatus};
+use crate::security::INTERNAL_DIR;
+
+tokio::task_local! {
+    static CURRENT: Arc<Job>;
+}
+
+/// Adds to the running job's byte count.
+pub fn report_bytes(n: u64) {
+    let _ = CURRENT.try_with(|job| job.bytes.fetch_add(n, Ordering::Relaxed));
+}
+
+/// Counts one more file or folder done for the running job.
+pub fn report_item() {
+    let _ = CURRENT.try_with(|job| job.items.fetch_add(1, Ordering::Relaxed));
+}
+
+/// Sets what the running job expects to process, when known up front.
+pub fn set_totals(bytes: u64, items: u64) {
+    let _ = CURRENT.try_with(|job| {
+        let mut state = job.state.lock().unwrap();
+        state.total_bytes = Some(bytes);
+        state.total_items = Some(items);
+    });
+}
+
+/// Fails with `Interrupted` once the running job has been cancelled.
+pub fn check_cancelled() -> io::Result<()> {
+    match CURRENT.try_with(|job| job.cancel.is_cancelled()) {
+        Ok(true) => Err(io::Error::new(io::ErrorKind::Interrupted, "job cancelled")),
+        _ => Ok(()),
+    }
+}
+
+/// Where jobs write files for download, e.g. archives.
+pub fn output_dir(root: &Path) -> PathBuf {
+    root.join(INTERNAL_DIR).join("jobs")
+}
+
+/// What a finished job produced.
+pub enum Outcome {
+    /// The response the endpoint would have sent.
+    Value(serde_json::Value),
+    /// A file to download, removed along with the job.
+    File { path: PathBuf, filename: String },
+}
+
+pub struct Job {
+    pub id: String,
+    pub op: &'static str,
+    pub username: String,
+    created: DateTime<Utc>,
+    bytes: AtomicU64,
+    items: AtomicU64,
+    cancel: CancellationToken,
+    state: Mutex<JobState>,
+}
+
+#[derive(Default)]
+struct JobState {
+    status: JobStatus,
+    started: Option<DateTime<Utc>>,
+    finished: Option<DateTime<Utc>>,
+    /// For expiry, immune to clock changes.
+    finished_at: Option<Instant>,
+    total_bytes: Option<u64>,
+    total_items: Option<u64>,
+    result: Option<serde_json::Value>,
+    error: Option<String>,
+    download: Option<(PathBuf, String)>,
+}
+
+impl Job {
+    fn new(op: &'static str, username: String) -> Self {
+        Self {
+            id: crate::util::random_id(),
+            op,
+            username,
+            created: Utc::now(),
+            bytes: AtomicU64::new(0),
+            items: AtomicU64::new(0),
+            cancel: CancellationToken::new(),
+            state: Mutex::new(JobState::default()),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().unwrap().finished.is_some()
+    }
+
+    /// Requests cancellation. The operation stops at its next check and
+    /// cleans up as it would after an error.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// The downloadable file, once the job has completed.
+    pub fn download(&self) -> Option<(PathBuf, String)> {
+        self.state.lock().unwrap().download.clone()
+    }
+
+    pub fn to_response(&self) -> JobResponse {
+        let state = self.state.lock().unwrap();
+        JobResponse {
+            id: self.id.clone(),
+            op: self.op,
+            status: state.status,
+            created: self.created,
+            started: state.started,
+            finished: state.finished,
+            progress: JobProgress {
+                bytes: self.bytes.load(Ordering::Relaxed),
+                items: self.items.load(Ordering::Relaxed),
+                total_bytes: state.total_bytes,
+                total_items: state.total_items,
+            },
+            result: state.result.clone(),
+            download: state
+                .download
+                .as_ref()
+                .map(|_| format!("/api/jobs/{}/download", self.id)),
+            error: state.error.clone(),
+        }
+    }
+
+    fn start(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.status = JobStatus::Running;
+        state.started = Some(Utc::now());
+    }
+
+    fn finish(&self, result: Result<Outcome, AppError>) {
+        let mut state = self.state.lock().unwrap();
+        state.finished = Some(Utc::now());
+        state.finished_at = Some(Instant::now());
+
+        match result {
+            Ok(Outcome::Value(value)) => {
+                state.status = JobStatus::Completed;
+                state.result = Some(value);
+            }
+            Ok(Outcome::File { path, filename }) => {
+                state.status = JobStatus::Completed;
+                state.download = Some((path, filename));
+            }
+            Err(_) if self.cancel.is_cancelled() => state.status = JobStatus::Cancelled,
+            Err(e) => {
+                state.status = JobStatus::Failed;
+                state.error = Some(e.status_and_message().1);
+            }
+        }
+    }
+}
+
+pub struct JobStore {
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+    workers: Arc<Semaphore>,
+    result_ttl: Duration,
+}
+
+impl JobStore {
+    pub fn new(workers: usize, result_ttl: Duration) -> Self {
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+            workers: Arc::new(Semaphore::new(workers.max(1))),
+            result_ttl,
+        }
+    }
+
+    /// Queues `task`; it starts once a worker is free.
+    pub fn spawn<F>(&self, op: &'static str, username: String, task: F) -> Arc<Job>
+    where
+        F: Future<Output = Result<Outcome, AppError>> + Send + 'static,
+    {
+        self.prune();
+
+        let job = Arc::new(Job::new(op, username));
+        self.jobs
+            .lock()
+            .unwrap()
+            .insert(job.id.clone(), Arc::clone(&job));
+
+        let workers = Arc::clone(&self.workers);
+        let running = Arc::clone(&job);
+        tokio::spawn(async move {
+            let permit = tokio::select! {
+                permit = workers.acquire_owned() => permit.expect("job pool closed"),
+                _ = running.cancel.cancelled() => {
+                    running.finish(Err(AppError::Internal("cancelled".to_string())));
+                    return;
+                }
+            };
+
+            running.start();
+            tracing::info!("Job {} ({}) started", running.id, running.op);
+            let result = CURRENT.scope(Arc::clone(&running), task).await;
+            drop(permit);
+
+            running.finish(result);
+            let status = running.state.lock().unwrap().status;
+            tracing::info!("Job {} ({}) {:?}", running.id, running.op, status);
+        });
+
+        job
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Job>> {
+        self.prune();
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    /// How many of `username`'s jobs are queued or running.
+    pub fn unfinished(&self, username: &str) -> usize {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| job.username == username && !job.is_finished())
+            .count()
+    }
+
+    /// All jobs, oldest first.
+    pub fn list(&self) -> Vec<Arc<Job>> {
+        self.prune();
+        let mut jobs: Vec<Arc<Job>> = self.jobs.lock().unwrap().values().cloned().collect();
+        jobs.sort_by_key(|job| job.created);
+        jobs
+    }
+
+    /// Forgets jobs finished longer than `result_ttl` ago, with their files.
+    fn prune(&self) {
+        let ttl = self.result_ttl;
+        self.jobs.lock().unwrap().retain(|_, job| {
+            let state = job.state.lock().unwrap();
+            let expired = state.finished_at.is_some_and(|at| at.elapsed() > ttl);
+            if expired {
+                if let Some((path, _)) = &state.download {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+            !expired
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn wait(job: &Job) {
+        while !job.is_finished() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_job_reports_progress() {
+        let store = JobStore::new(1, Duration::from_secs(60));
+        let job = store.spawn("copy", "pi".to_string(), async {
+            set_totals(30, 3);
+            for _ in 0..3 {
+                report_bytes(10);
+                report_item();
+            }
+            Ok(Outcome::Value(serde_json::json!({"status": "ok"})))
+        });
+        wait(&job).await;
+
+        let response = job.to_response();
+        assert_eq!(response.status, JobStatus::Completed);
+        assert_eq!(response.progress.bytes, 30);
+        assert_eq!(response.progress.items, 3);
+        assert_eq!(response.progress.total_items, Some(3));
+        assert!(response.result.is_some());
+        assert_eq!(store.list().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_queued_and_running() {
+        let store = JobStore::new(1, Duration::from_secs(60));
+        let running = store.spawn("copy", "pi".to_string(), async {
+            loop {
+                check_cancelled()?;
+                tokio::task::yield_now().await;
+            }
+        });
+        let queued = store.spawn("copy", "pi".to_string(), async {
+            Ok(Outcome::Value(serde_json::Value::Null))
+        });
+
+        tokio::task::yield_now().await;
+        assert_eq!(store.unfinished("pi"), 2);
+        assert_eq!(store.unfinished("dj"), 0);
+
+        queued.cancel();
+        wait(&queued).await;
+        assert_eq!(store.unfinished("pi"), 1);
+        assert_eq!(queued.to_response().status, JobStatus::Cancelled);
+        assert!(queued.to_response().started.is_none());
+
+        running.cancel();
+        wait(&running).await;
+        assert_eq!(running.to_response().status, JobStatus::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn test_finished_jobs_expire() {
+        let store = JobStore::new(1, Duration::ZERO);
+        let job = store.spawn("delete", "pi".to_string(), async {
+            Err(AppError::NotFound("not found: x".to_string()))
+        });
+        wait(&job).await;
+
+        let response = job.to_response();
+        assert_eq!(response.status, JobStatus::Failed);
+        assert_eq!(response.error.as_deref(), Some("not found: x"));
+
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(store.get(&job.id).is_none());
+    }
+
+    #[test]
+    fn test_outside_a_job_is_a_no_op() {
+        report_bytes(1);
+        report_item();
+        set_totals(1, 1);
+        assert!(check_cancelled().is_ok());
+    }
+}
@@ -1,14 +1,32 @@
+mod access;
+mod archive;
+mod auth;
+mod checksum;
 mod config;
+mod dav;
 mod error;
+mod events;
+mod fsutil;
 mod handlers;
+mod headers;
+mod ip_filter;
+mod jobs;
+mod mime_policy;
 mod models;
 mod security;
 mod static_files;
+mod storage;
+mod trash;
+mod tus;
+mod util;
 
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use axum::{
-    routing::{get, post},
+    middleware,
+    routing::{any, get, head, post},
     Router,
 };
 use tower_http::{
@@ -17,15 +35,31 @@ use tower_http::{
 };
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+use crate::auth::SessionStore;
 use crate::config::Config;
+use crate::dav::locks::LockStore;
+use crate::events::EventBus;
+use crate::jobs::JobStore;
+use crate::tus::TusStore;
 
 #[derive(Clone)]
 pub struct AppState {
     pub config: Arc<Config>,
+    pub sessions: Arc<SessionStore>,
+    pub uploads: Arc<TusStore>,
+    pub jobs: Arc<JobStore>,
+    pub events: Arc<EventBus>,
+    pub dav_locks: Arc<LockStore>,
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
+    // `monkeyarch hash-password` prints a hash for the [auth] users list
+    if std::env::args().nth(1).as_deref() == Some("hash-password") {
+        hash_password_command();
+        return;
+    }
+
     // Initialize logging
     tracing_subscriber::registry()
         .with(
@@ -66,7 +100,10 @@ async fn main() {
 
     if !root.is_dir() {
         eprintln!();
-        eprintln!("ERROR: Root path exists but is not a directory: {}", root.display());
+        eprintln!(
+            "ERROR: Root path exists but is not a directory: {}",
+            root.display()
+        );
         eprintln!();
         std::process::exit(1);
     }
@@ -82,12 +119,56 @@ async fn main() {
     );
     tracing::info!("Delete enabled: {}", config.enable_delete);
 
+    if config.auth.enabled {
+        if config.auth.users.is_empty() {
+            eprintln!();
+            eprintln!("ERROR: auth.enabled is set but no [[auth.users]] are configured.");
+            eprintln!();
+            std::process::exit(1);
+        }
+        for user in &config.auth.users {
+            if !auth::is_supported_hash(&user.password_hash) {
+                eprintln!();
+                eprintln!(
+                    "ERROR: password_hash for user '{}' is not an argon2 or bcrypt hash.",
+                    user.username
+                );
+                eprintln!("  Generate one with:");
+                eprintln!("    monkeyarch hash-password");
+                eprintln!();
+                std::process::exit(1);
+            }
+        }
+        tracing::info!(
+            "Authentication enabled: session login {}",
+            if config.auth.session_login {
+                "on"
+            } else {
+                "off"
+            }
+        );
+        for user in &config.auth.users {
+            tracing::info!("User '{}': {:?}", user.username, user.role);
+        }
+    } else {
+        tracing::warn!("Authentication disabled: anyone on the network has full access");
+    }
+
+    if config.allowed_networks.is_empty() {
+        tracing::info!("IP allow-list: disabled");
+    } else {
+        tracing::info!("IP allow-list: {:?}", config.allowed_networks);
+    }
+
     if let Some(ref static_dir) = config.static_directory {
         if static_dir.exists() {
             tracing::info!("Static files: {:?} (external)", static_dir);
         } else {
             eprintln!();
-            eprintln!("WARNING: static_directory does not exist: {}", static_dir.display());
+            eprintln!(
+                "WARNING: static_directory does not exist: {}",
+                static_dir.display()
+            );
             eprintln!("         Falling back to embedded assets.");
             eprintln!();
         }
@@ -95,25 +176,163 @@ async fn main() {
         tracing::info!("Static files: embedded");
     }
 
+    // Leftovers from uploads cut short by a crash or power loss. Runs in
+    // the background since a large SD card can take a while to walk; temp
+    // files of uploads started meanwhile are newer and left alone.
+    let sweep_root = config.root_directory.clone();
+    let started = SystemTime::now();
+    tokio::task::spawn_blocking(
+        move || match fsutil::sweep_temp_files(&sweep_root, started) {
+            Ok(0) => {}
+            Ok(n) => tracing::info!("Removed {} stale upload temp file(s)", n),
+            Err(e) => tracing::warn!("Temp file sweep failed: {}", e),
+        },
+    );
+
+    if config.trash.enabled && config.trash.retention_days > 0 {
+        tracing::info!("Trash: items kept {} days", config.trash.retention_days);
+        let trash_root = config.root_directory.clone();
+        let retention = Duration::from_secs(config.trash.retention_days * 24 * 60 * 60);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                match trash::expire(&trash_root, retention).await {
+                    Ok(0) => {}
+                    Ok(n) => tracing::info!("Emptied {} expired trash item(s)", n),
+                    Err(e) => tracing::warn!("Trash cleanup failed: {}", e),
+                }
+            }
+        });
+    } else if config.trash.enabled {
+        tracing::info!("Trash: items kept until purged");
+    } else {
+        tracing::info!("Trash: disabled, deletes are permanent");
+    }
+
+    // Resumable uploads the client gave up on
+    let uploads = Arc::new(TusStore::default());
+    if config.tus.expire_after > 0 {
+        let tus_root = config.root_directory.clone();
+        let tus_uploads = uploads.clone();
+        let max_idle = Duration::from_secs(config.tus.expire_after);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                match tus::expire(&tus_root, &tus_uploads, max_idle).await {
+                    Ok(0) => {}
+                    Ok(n) => tracing::info!("Discarded {} abandoned resumable upload(s)", n),
+                    Err(e) => tracing::warn!("Resumable upload cleanup failed: {}", e),
+                }
+            }
+        });
+    }
+
+    // Archives of jobs from before a restart can no longer be downloaded
+    let _ = std::fs::remove_dir_all(jobs::output_dir(&config.root_directory));
+
+    // Live change notifications; the watcher stops when dropped
+    let events = Arc::new(EventBus::default());
+    let _watcher = if config.events.enabled {
+        match events::watch(&canonical_root, events.clone()) {
+            Ok(watcher) => {
+                tracing::info!("Live updates: watching for changes");
+                Some(watcher)
+            }
+            Err(e) => {
+                tracing::warn!("Live updates unavailable: {}", e);
+                None
+            }
+        }
+    } else {
+        tracing::info!("Live updates: disabled");
+        None
+    };
+
+    let shutdown_events = events.clone();
     let state = AppState {
         config: Arc::new(config.clone()),
+        sessions: Arc::new(SessionStore::default()),
+        uploads,
+        jobs: Arc::new(JobStore::new(
+            config.jobs.workers,
+            Duration::from_secs(config.jobs.result_ttl),
+        )),
+        events,
+        dav_locks: Arc::new(LockStore::default()),
     };
 
-    // Build router
-    let app = Router::new()
-        // API routes
+    // Resumable uploads (tus 1.0)
+    let tus = Router::new()
+        .route(
+            "/api/tus",
+            post(handlers::tus::create_upload).options(handlers::tus::options),
+        )
+        .route(
+            "/api/tus/",
+            post(handlers::tus::create_upload).options(handlers::tus::options),
+        )
+        .route(
+            "/api/tus/:id",
+            head(handlers::tus::upload_status)
+                .patch(handlers::tus::upload_chunk)
+                .delete(handlers::tus::delete_upload),
+        )
+        .layer(middleware::from_fn(handlers::tus::protocol));
+
+    // API routes (authenticated)
+    let api = Router::new()
         .route("/api/list", get(handlers::list::list_directory))
         .route("/api/file", get(handlers::serve::serve_file))
+        .route(
+            "/api/archive",
+            get(handlers::archive::download_archive).post(handlers::archive::download_selection),
+        )
         .route("/api/upload", post(handlers::upload::upload_file))
         .route("/api/move", post(handlers::move_file::move_file))
+        .route("/api/copy", post(handlers::copy::copy_path))
         .route("/api/mkdir", post(handlers::mkdir::create_directory))
         .route("/api/delete", post(handlers::delete::delete_path))
+        .route("/api/batch", post(handlers::batch::run_batch))
+        .route(
+            "/api/jobs",
+            get(handlers::jobs::list_jobs).post(handlers::jobs::create_job),
+        )
+        .route("/api/jobs/:id", get(handlers::jobs::job_status))
+        .route("/api/jobs/:id/cancel", post(handlers::jobs::cancel_job))
+        .route(
+            "/api/jobs/:id/download",
+            get(handlers::jobs::download_output),
+        )
+        .route("/api/trash", get(handlers::trash::list_trash))
+        .route("/api/trash/restore", post(handlers::trash::restore))
+        .route("/api/trash/purge", post(handlers::trash::purge))
+        .route("/api/events", get(handlers::events::stream_events))
+        .route("/api/ws", get(handlers::ws::websocket))
+        // WebDAV
+        .route("/dav", any(handlers::dav::handle))
+        .route("/dav/", any(handlers::dav::handle))
+        .route("/dav/*path", any(handlers::dav::handle))
+        .merge(tus)
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_auth,
+        ));
+
+    // Build router
+    let app = Router::new()
+        // Session login (public)
+        .route("/api/login", post(handlers::session::login))
+        .route("/api/logout", post(handlers::session::logout))
+        .merge(api)
         // Static files (catch-all)
         .fallback(static_files::serve_static)
         // Shared state
-        .with_state(state)
+        .with_state(state.clone())
         // Middleware
         .layer(RequestBodyLimitLayer::new(config.max_upload_size as usize))
+        .layer(middleware::from_fn_with_state(state, ip_filter::filter_ip))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::default().include_headers(false)),
@@ -126,10 +345,39 @@ async fn main() {
 
     tracing::info!("Server listening on http://{}", addr);
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .expect("Server error");
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        shutdown_events.close();
+    })
+    .await
+    .expect("Server error");
+}
+
+fn hash_password_command() {
+    eprint!("Password: ");
+    let mut password = String::new();
+    if std::io::stdin().read_line(&mut password).is_err() {
+        eprintln!("Failed to read password");
+        std::process::exit(1);
+    }
+
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        eprintln!("Password must not be empty");
+        std::process::exit(1);
+    }
+
+    match auth::hash_password(password) {
+        Ok(hash) => println!("{}", hash),
+        Err(e) => {
+            eprintln!("Failed to hash password: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
 async fn shutdown_signal() {
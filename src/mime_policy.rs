@@ -0,0 +1,393 @@
+//! Upload type policy: which files may be uploaded where.
+//!
+//! A file passes in two steps. Its name and claimed `Content-Type` are
+//! checked against the allow-lists up front, then (with `sniff` on) its
+//! first bytes must be a recognized format that the policy also allows.
+//! Content that isn't recognized is only accepted if its claimed type is
+//! one we have no signature for and is listed exactly, e.g. `text/plain`;
+//! a `type/*` wildcard covers only the formats we can recognize.
+//!
+//! Downloads take their `Content-Type` from the file name, so both the
+//! claimed and the sniffed type must also agree with the extension: a PNG
+//! named `x.html` would otherwise be served as a web page.
+
+use std::path::Path;
+
+use axum::body::Bytes;
+
+use crate::config::UploadPolicy;
+use crate::error::AppError;
+use crate::security::normalize_relative;
+
+/// Bytes needed to recognize every known signature.
+pub const SNIFF_LEN: usize = 16;
+
+/// Every type `sniff` can return.
+const RECOGNIZED: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/bmp",
+    "image/tiff",
+    "image/heic",
+    "image/avif",
+    "audio/flac",
+    "audio/ogg",
+    "audio/mpeg",
+];
+
+/// Identifies a file format from its first bytes.
+pub fn sniff(head: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, sig: &[u8]| head.get(offset..offset + sig.len()) == Some(sig);
+
+    if at(0, b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if at(0, b"\xFF\xD8\xFF") {
+        Some("image/jpeg")
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        Some("image/gif")
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        Some("image/webp")
+    } else if at(0, b"BM") && head.len() >= 14 {
+        Some("image/bmp")
+    } else if at(0, b"II*\0") || at(0, b"MM\0*") {
+        Some("image/tiff")
+    } else if at(4, b"ftypheic") || at(4, b"ftypheix") || at(4, b"ftypmif1") {
+        Some("image/heic")
+    } else if at(4, b"ftypavif") {
+        Some("image/avif")
+    } else if at(0, b"fLaC") {
+        Some("audio/flac")
+    } else if at(0, b"OggS") {
+        Some("audio/ogg")
+    } else if at(0, b"ID3") || is_mpeg_frame(head) {
+        Some("audio/mpeg")
+    } else {
+        None
+    }
+}
+
+/// MPEG audio frame header: 11-bit sync, valid version and layer.
+fn is_mpeg_frame(head: &[u8]) -> bool {
+    match head {
+        [0xFF, b, ..] => b & 0xE0 == 0xE0 && (b >> 3) & 0x03 != 0x01 && (b >> 1) & 0x03 != 0,
+        _ => false,
+    }
+}
+
+/// Lowercases, drops parameters and maps common aliases.
+fn canonical(mime: &str) -> String {
+    let mime = mime.split(';').next().unwrap_or("").trim().to_lowercase();
+    match mime.as_str() {
+        "audio/mp3" | "audio/mpeg3" | "audio/x-mpeg" | "audio/x-mp3" => "audio/mpeg".into(),
+        "image/jpg" | "image/pjpeg" => "image/jpeg".into(),
+        "audio/x-flac" => "audio/flac".into(),
+        "application/ogg" => "audio/ogg".into(),
+        _ => mime,
+    }
+}
+
+/// Whether the extension of `filename` suggests `mime` (canonical). Names
+/// without a known extension are served as binary and agree with anything.
+fn name_agrees(filename: &str, mime: &str) -> bool {
+    let mut guesses = mime_guess::from_path(filename).iter().peekable();
+    guesses.peek().is_none() || guesses.any(|guess| canonical(guess.essence_str()) == mime)
+}
+
+fn mime_matches(pattern: &str, mime: &str) -> bool {
+    let pattern = pattern.trim();
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(family) => mime
+            .strip_prefix(family.to_lowercase().as_str())
+            .is_some_and(|rest| rest.starts_with('/')),
+        None => canonical(pattern) == mime,
+    }
+}
+
+/// The allow-lists in effect for uploads into `dir`.
+struct Rules<'a> {
+    mime_types: &'a [String],
+    extensions: &'a [String],
+}
+
+impl<'a> Rules<'a> {
+    fn for_dir(policy: &'a UploadPolicy, dir: &Path) -> Self {
+        let dir = normalize_relative(&dir.to_string_lossy());
+
+        // Most specific override first; each list is taken from the most
+        // specific override that sets it
+        let mut matching: Vec<_> = policy
+            .overrides
+            .iter()
+            .map(|o| (normalize_relative(&o.path), o))
+            .filter(|(path, _)| dir.starts_with(path))
+            .collect();
+        matching.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+
+        Self {
+            mime_types: matching
+                .iter()
+                .find_map(|(_, o)| o.mime_types.as_deref())
+                .unwrap_or(&policy.mime_types),
+            extensions: matching
+                .iter()
+                .find_map(|(_, o)| o.extensions.as_deref())
+                .unwrap_or(&policy.extensions),
+        }
+    }
+
+    fn allows(&self, mime: &str) -> bool {
+        self.mime_types.iter().any(|p| mime_matches(p, mime))
+    }
+
+    fn lists_exactly(&self, mime: &str) -> bool {
+        self.mime_types
+            .iter()
+            .any(|p| !p.ends_with("/*") && canonical(p) == mime)
+    }
+}
+
+/// Checks the file name and claimed type before any content arrives.
+pub fn check_declared(
+    policy: &UploadPolicy,
+    dir: &Path,
+    filename: &str,
+    claimed: &str,
+) -> Result<(), AppError> {
+    let rules = Rules::for_dir(policy, dir);
+
+    if !rules.extensions.is_empty() {
+        let ext = Path::new(filename)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let allowed = rules
+            .extensions
+            .iter()
+            .any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(&ext));
+        if !allowed {
+            return Err(AppError::UnsupportedMediaType(format!(
+                "file extension of '{}' not allowed here",
+                filename
+            )));
+        }
+    }
+
+    let mime = canonical(claimed);
+    if !rules.allows(&mime) {
+        return Err(AppError::UnsupportedMediaType(format!(
+            "type '{}' not allowed here",
+            claimed
+        )));
+    }
+
+    if !name_agrees(filename, &mime) {
+        return Err(AppError::UnsupportedMediaType(format!(
+            "'{}' does not match its type {}",
+            filename, claimed
+        )));
+    }
+
+    Ok(())
+}
+
+/// Checks the first bytes of a file (up to `SNIFF_LEN`).
+pub fn check_content(
+    policy: &UploadPolicy,
+    dir: &Path,
+    filename: &str,
+    claimed: &str,
+    head: &[u8],
+) -> Result<(), AppError> {
+    if !policy.sniff {
+        return Ok(());
+    }
+
+    let rules = Rules::for_dir(policy, dir);
+    let claimed = canonical(claimed);
+
+    match sniff(head) {
+        Some(actual) if !rules.allows(actual) => Err(AppError::UnsupportedMediaType(format!(
+            "content is {}, which is not allowed here",
+            actual
+        ))),
+        Some(actual) if !name_agrees(filename, actual) => Err(AppError::UnsupportedMediaType(
+            format!("content is {}, which does not match '{}'", actual, filename),
+        )),
+        Some(_) => Ok(()),
+        None if !RECOGNIZED.contains(&claimed.as_str()) && rules.lists_exactly(&claimed) => Ok(()),
+        None => Err(AppError::UnsupportedMediaType(format!(
+            "content does not look like {}",
+            claimed
+        ))),
+    }
+}
+
+/// Holds back the start of a streamed upload until its content is checked.
+pub struct ContentCheck<'a> {
+    policy: &'a UploadPolicy,
+    dir: &'a Path,
+    filename: &'a str,
+    claimed: &'a str,
+    head: Option<Vec<u8>>,
+}
+
+impl<'a> ContentCheck<'a> {
+    pub fn new(
+        policy: &'a UploadPolicy,
+        dir: &'a Path,
+        filename: &'a str,
+        claimed: &'a str,
+    ) -> Self {
+        Self {
+            policy,
+            dir,
+            filename,
+            claimed,
+            head: Some(Vec::with_capacity(SNIFF_LEN)),
+        }
+    }
+
+    /// Returns the bytes that may be written now.
+    pub fn feed(&mut self, chunk: Bytes) -> Result<Bytes, AppError> {
+        let Some(head) = self.head.as_mut() else {
+            return Ok(chunk);
+        };
+
+        head.extend_from_slice(&chunk);
+        if head.len() < SNIFF_LEN {
+            return Ok(Bytes::new());
+        }
+        self.finish()
+    }
+
+    /// Checks whatever arrived, for files shorter than `SNIFF_LEN`.
+    /// Returns the held-back bytes.
+    pub fn finish(&mut self) -> Result<Bytes, AppError> {
+        let Some(head) = self.head.take() else {
+            return Ok(Bytes::new());
+        };
+        check_content(self.policy, self.dir, self.filename, self.claimed, &head)?;
+        Ok(Bytes::from(head))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PolicyOverride;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const ELF: &[u8] = b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0";
+
+    fn policy() -> UploadPolicy {
+        let list = |l: &[&str]| Some(l.iter().map(|s| s.to_string()).collect());
+        UploadPolicy {
+            overrides: vec![
+                PolicyOverride {
+                    path: "podcasts".into(),
+                    mime_types: list(&["audio/*"]),
+                    extensions: list(&["mp3", ".ogg"]),
+                },
+                PolicyOverride {
+                    path: "podcasts/notes".into(),
+                    mime_types: list(&["text/plain"]),
+                    extensions: None,
+                },
+            ],
+            ..UploadPolicy::default()
+        }
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(PNG), Some("image/png"));
+        assert_eq!(sniff(b"\xFF\xD8\xFF\xE0\0\x10JFIF"), Some("image/jpeg"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"ID3\x04\0\0\0\0\0\0"), Some("audio/mpeg"));
+        assert_eq!(sniff(b"\xFF\xFB\x90\x64"), Some("audio/mpeg"));
+        assert_eq!(sniff(b"fLaC\0\0\0\x22"), Some("audio/flac"));
+        assert_eq!(sniff(b"OggS\0\x02"), Some("audio/ogg"));
+        assert_eq!(sniff(b"\0\0\0\x1cftypheic"), Some("image/heic"));
+        assert_eq!(sniff(ELF), None);
+        assert_eq!(sniff(b"MZ\x90\0"), None);
+        assert_eq!(sniff(b""), None);
+
+        for sample in [PNG, b"GIF89a", b"II*\0", b"\0\0\0\x1cftypavif"] {
+            assert!(RECOGNIZED.contains(&sniff(sample).unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_declared() {
+        let policy = policy();
+        let root = Path::new("");
+
+        assert!(check_declared(&policy, root, "a.png", "image/png").is_ok());
+        assert!(check_declared(&policy, root, "a.mp3", "audio/MP3").is_ok());
+        assert!(check_declared(&policy, root, "a.ogg", "audio/ogg").is_err());
+
+        // Override replaces both lists, extension case doesn't matter
+        let podcasts = Path::new("podcasts/ep1");
+        assert!(check_declared(&policy, podcasts, "a.OGG", "audio/ogg").is_ok());
+        assert!(check_declared(&policy, podcasts, "a.png", "image/png").is_err());
+        assert!(check_declared(&policy, podcasts, "a.flac", "audio/flac").is_err());
+
+        // Nested override inherits the extensions it doesn't set
+        let notes = Path::new("podcasts/notes");
+        assert!(check_declared(&policy, notes, "a.txt", "text/plain").is_err());
+
+        // The name must agree with the type it will be served as
+        assert!(check_declared(&policy, root, "a.html", "image/png").is_err());
+        assert!(check_declared(&policy, root, "a.jpg", "image/png").is_err());
+        assert!(check_declared(&policy, root, "a.JPG", "image/pjpeg").is_ok());
+        assert!(check_declared(&policy, root, "photo", "image/png").is_ok());
+    }
+
+    #[test]
+    fn test_content() {
+        let policy = policy();
+        let root = Path::new("");
+
+        assert!(check_content(&policy, root, "a.png", "image/png", PNG).is_ok());
+        // A renamed executable is caught whatever it claims to be
+        assert!(check_content(&policy, root, "a.png", "image/png", ELF).is_err());
+        assert!(check_content(&policy, root, "a.bmp", "image/bmp", ELF).is_err());
+        assert!(check_content(&policy, root, "a.mp3", "audio/mpeg", ELF).is_err());
+        // Real content decides, not the claim
+        let podcasts = Path::new("podcasts");
+        assert!(check_content(&policy, podcasts, "a.mp3", "audio/mpeg", PNG).is_err());
+        // ...and it must match the name it will be served under
+        assert!(check_content(&policy, root, "a.jpg", "image/jpeg", PNG).is_err());
+        assert!(check_content(&policy, root, "a.html", "image/png", PNG).is_err());
+        // Unrecognized content needs an exact listing
+        let notes = Path::new("podcasts/notes");
+        assert!(check_content(&policy, notes, "a.txt", "text/plain", b"hi").is_ok());
+
+        let off = UploadPolicy {
+            sniff: false,
+            ..policy
+        };
+        assert!(check_content(&off, root, "a.png", "image/png", ELF).is_ok());
+    }
+
+    #[test]
+    fn test_content_check_holds_back_head() {
+        let policy = UploadPolicy::default();
+        let mut check = ContentCheck::new(&policy, Path::new(""), "a.png", "image/png");
+
+        assert!(check
+            .feed(Bytes::from_static(&PNG[..4]))
+            .unwrap()
+            .is_empty());
+        assert_eq!(check.feed(Bytes::from_static(&PNG[4..])).unwrap(), PNG);
+        assert_eq!(check.feed(Bytes::from_static(b"rest")).unwrap(), "rest");
+        assert!(check.finish().unwrap().is_empty());
+
+        let mut check = ContentCheck::new(&policy, Path::new(""), "a.png", "image/png");
+        assert!(check.feed(Bytes::from_static(b"MZ")).unwrap().is_empty());
+        assert!(check.finish().is_err());
+    }
+}
@@ -1,5 +1,7 @@
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::archive::ArchiveFormat;
 
 // === List Directory ===
 
@@ -33,6 +35,37 @@ pub enum EntryType {
     Directory,
 }
 
+// === Get File ===
+
+#[derive(Debug, Deserialize)]
+pub struct FileQuery {
+    #[serde(default)]
+    pub path: String,
+    /// `download=1` sends `Content-Disposition: attachment`.
+    #[serde(default, deserialize_with = "flag")]
+    pub download: bool,
+}
+
+// === Archive Download ===
+
+#[derive(Debug, Deserialize)]
+pub struct ArchiveQuery {
+    #[serde(default)]
+    pub path: String,
+    #[serde(default)]
+    pub format: ArchiveFormat,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ArchiveRequest {
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub format: ArchiveFormat,
+    /// Download file name without extension. Default: `archive`.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
 // === Upload ===
 
 #[derive(Debug, Deserialize)]
@@ -41,6 +74,103 @@ pub struct UploadQuery {
     pub path: String,
     #[serde(default)]
     pub overwrite: bool,
+    #[serde(default)]
+    pub conflict: Option<OnConflict>,
+    #[serde(default)]
+    pub on_error: OnError,
+}
+
+impl UploadQuery {
+    pub fn on_conflict(&self) -> OnConflict {
+        OnConflict::resolve(self.conflict, self.overwrite)
+    }
+}
+
+/// What to do when the destination name is already taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    /// 409 Conflict.
+    #[default]
+    Fail,
+    Overwrite,
+    /// Pick a free `name (1).ext`-style name.
+    Rename,
+}
+
+impl OnConflict {
+    /// `conflict` wins over the older `overwrite` flag.
+    pub fn resolve(conflict: Option<OnConflict>, overwrite: bool) -> Self {
+        match conflict {
+            Some(conflict) => conflict,
+            None if overwrite => OnConflict::Overwrite,
+            None => OnConflict::Fail,
+        }
+    }
+}
+
+/// What a multi-file upload does when one file fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnError {
+    /// Keep the files already stored, skip the rest.
+    #[default]
+    Stop,
+    /// Keep going with the remaining files.
+    Continue,
+    /// Store nothing unless every file succeeds.
+    Rollback,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadResponse {
+    pub status: &'static str,
+    /// First failure, also found in `files`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub files: Vec<UploadResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadResult {
+    pub name: String,
+    pub status: UploadStatus,
+    pub bytes: u64,
+    /// Final name, when `conflict=rename` had to pick another one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub saved_as: Option<String>,
+    /// Hex digests of the stored file; `md5` only when the client sent one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl UploadResult {
+    pub fn new(name: String, status: UploadStatus, bytes: u64, error: Option<String>) -> Self {
+        Self {
+            name,
+            status,
+            bytes,
+            saved_as: None,
+            sha256: None,
+            md5: None,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadStatus {
+    Ok,
+    Failed,
+    /// Not attempted after an earlier failure.
+    Skipped,
+    /// Received, then discarded because another file failed.
+    RolledBack,
 }
 
 // === Move/Rename ===
@@ -51,6 +181,35 @@ pub struct MoveRequest {
     pub to: String,
     #[serde(default)]
     pub overwrite: bool,
+    #[serde(default)]
+    pub conflict: Option<OnConflict>,
+}
+
+impl MoveRequest {
+    pub fn on_conflict(&self) -> OnConflict {
+        OnConflict::resolve(self.conflict, self.overwrite)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MoveResponse {
+    pub status: &'static str,
+    /// Final destination, which differs from `to` after `conflict=rename`.
+    pub to: String,
+}
+
+// === Copy ===
+
+#[derive(Debug, Deserialize)]
+pub struct CopyRequest {
+    pub from: String,
+    pub to: String,
+    /// Replace existing files; an existing directory is merged into.
+    #[serde(default)]
+    pub overwrite: bool,
+    /// Required to copy a directory.
+    #[serde(default)]
+    pub recursive: bool,
 }
 
 // === Create Directory ===
@@ -67,6 +226,259 @@ pub struct DeleteRequest {
     pub path: String,
     #[serde(default)]
     pub recursive: bool,
+    /// Skip the trash, e.g. for items it can't take.
+    #[serde(default)]
+    pub permanent: bool,
+}
+
+// === Trash ===
+
+#[derive(Debug, Serialize)]
+pub struct TrashListResponse {
+    pub items: Vec<TrashEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrashEntry {
+    pub id: String,
+    /// Original location.
+    pub path: String,
+    #[serde(rename = "type")]
+    pub entry_type: EntryType,
+    pub size: u64,
+    pub deleted: DateTime<Utc>,
+    pub deleted_by: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RestoreRequest {
+    pub id: String,
+    #[serde(default)]
+    pub conflict: Option<OnConflict>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RestoreResponse {
+    pub status: &'static str,
+    /// Where the item was restored to; differs from the original
+    /// location after `conflict=rename`.
+    pub path: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PurgeRequest {
+    /// Item to remove for good. Default: everything the user can see.
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+// === Batch ===
+
+/// One operation, with the same fields as its own endpoint.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum BatchOperation {
+    Move(MoveRequest),
+    Copy(CopyRequest),
+    Delete(DeleteRequest),
+    Mkdir(MkdirRequest),
+}
+
+impl BatchOperation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BatchOperation::Move(_) => "move",
+            BatchOperation::Copy(_) => "copy",
+            BatchOperation::Delete(_) => "delete",
+            BatchOperation::Mkdir(_) => "mkdir",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    /// Run in order.
+    pub operations: Vec<BatchOperation>,
+    #[serde(default)]
+    pub on_error: BatchOnError,
+}
+
+/// Unlike uploads, a batch can't be rolled back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchOnError {
+    /// Skip the remaining operations.
+    #[default]
+    Stop,
+    /// Carry on with the remaining operations.
+    Continue,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    pub status: &'static str,
+    /// First failure, also found in `results`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub results: Vec<BatchResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResult {
+    pub op: &'static str,
+    pub status: BatchStatus,
+    /// Final destination of a move, as returned by `/api/move`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchStatus {
+    Ok,
+    Failed,
+    /// Not attempted after an earlier failure.
+    Skipped,
+}
+
+// === Jobs ===
+
+/// An operation to run in the background, with the same fields as its
+/// own endpoint. `archive` takes the body of `POST /api/archive`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum JobRequest {
+    Move(MoveRequest),
+    Copy(CopyRequest),
+    Delete(DeleteRequest),
+    Archive(ArchiveRequest),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Waiting for a free worker.
+    #[default]
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobResponse {
+    pub id: String,
+    pub op: &'static str,
+    pub status: JobStatus,
+    pub created: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished: Option<DateTime<Utc>>,
+    pub progress: JobProgress,
+    /// What the operation's endpoint would have answered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    /// URL of the finished archive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobProgress {
+    pub bytes: u64,
+    /// Files and folders done.
+    pub items: u64,
+    /// Known up front for copies.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_items: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobListResponse {
+    pub jobs: Vec<JobResponse>,
+}
+
+// === Live Updates ===
+
+/// A change below the root, as pushed by `GET /api/events`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChangeEvent {
+    #[serde(rename = "type")]
+    pub kind: ChangeKind,
+    pub path: String,
+    /// New location of a renamed or moved item.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Deleted,
+    Renamed,
+}
+
+/// Progress of one file of a multipart upload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UploadProgress {
+    pub id: String,
+    /// Destination, including the final file name.
+    pub path: String,
+    pub username: String,
+    pub bytes: u64,
+    pub status: UploadState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadState {
+    Uploading,
+    /// Fully received; written unless a later file of a rollback batch fails.
+    Received,
+    Failed,
+}
+
+// === WebSocket ===
+
+/// Sent by the client over `/api/ws`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum WsRequest {
+    /// Start receiving changes and uploads in these folders and below.
+    Subscribe { paths: Vec<String> },
+    Unsubscribe { paths: Vec<String> },
+}
+
+/// Sent by the server over `/api/ws`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum WsMessage {
+    Change { change: ChangeEvent },
+    Upload(UploadProgress),
+    Job(JobResponse),
+    /// Changes were missed; re-list what is on screen.
+    Resync,
+    /// Current subscriptions, after every request.
+    Subscribed { paths: Vec<String> },
+    Error { error: String },
+}
+
+// === Session Login ===
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
 }
 
 // === Common Responses ===
@@ -81,3 +493,12 @@ impl StatusResponse {
         Self { status: "ok" }
     }
 }
+
+/// Query string flag accepting `1`/`true`/`yes`/`on`.
+fn flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Ok(matches!(
+        value.to_ascii_lowercase().as_str(),
+        "1" | "true" | "yes" | "on"
+    ))
+}
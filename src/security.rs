@@ -2,6 +2,21 @@ use std::path::{Path, PathBuf};
 
 use crate::error::AppError;
 
+/// Directory inside the root holding server state (upload staging etc.).
+pub const INTERNAL_DIR: &str = ".monkeyarch";
+
+/// Returns true for names reserved for server state and temporary files.
+/// These never show up in listings or archives and can't be addressed.
+pub fn is_internal_name(name: &str) -> bool {
+    name == INTERNAL_DIR || name.starts_with(".monkeyarch-")
+}
+
+/// Returns true if any component of `path` is an internal name.
+pub fn has_internal_component(path: &Path) -> bool {
+    path.components()
+        .any(|c| is_internal_name(&c.as_os_str().to_string_lossy()))
+}
+
 /// Validates and resolves a user-provided path against the root jail.
 /// Returns the canonical absolute path if valid.
 ///
@@ -10,12 +25,17 @@ use crate::error::AppError;
 /// - Rejects path traversal (../)
 /// - Canonicalizes paths before comparison
 /// - Verifies result stays within root directory
+/// - Hides internal server files (`INTERNAL_DIR`, temp files)
 pub fn validate_path(root: &Path, user_path: &str) -> Result<PathBuf, AppError> {
     // Reject null bytes
     if user_path.contains('\0') {
         return Err(AppError::BadRequest("invalid path: null byte".to_string()));
     }
 
+    if has_internal_component(Path::new(user_path)) {
+        return Err(AppError::NotFound(format!("not found: {}", user_path)));
+    }
+
     // Normalize: strip leading slashes, handle empty
     let normalized = user_path.trim_start_matches('/');
     let normalized = if normalized.is_empty() {
@@ -67,13 +87,35 @@ pub fn validate_path(root: &Path, user_path: &str) -> Result<PathBuf, AppError>
     };
 
     // Final jail check: canonical path must start with canonical root
-    if !canonical_target.starts_with(&canonical_root) {
+    let Ok(rel) = canonical_target.strip_prefix(&canonical_root) else {
         return Err(AppError::Forbidden);
+    };
+
+    // Also catches symlinks pointing into internal directories
+    if has_internal_component(rel) {
+        return Err(AppError::NotFound(format!("not found: {}", user_path)));
     }
 
     Ok(canonical_target)
 }
 
+/// Resolves `.` and `..` in a user-provided path without touching the
+/// filesystem. Components that would climb above the root are dropped;
+/// `validate_path` still rejects such paths.
+pub fn normalize_relative(user_path: &str) -> PathBuf {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in user_path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.iter().collect()
+}
+
 /// Validates path exists AND is a directory.
 pub fn validate_directory(root: &Path, user_path: &str) -> Result<PathBuf, AppError> {
     let path = validate_path(root, user_path)?;
@@ -123,6 +165,28 @@ pub fn validate_filename(filename: &str) -> Result<&str, AppError> {
     Ok(filename)
 }
 
+/// Validates a `/`-separated relative path from a folder upload
+/// (e.g. `Album/CD1/01.mp3`) and returns its segments. Every segment must
+/// pass `validate_filename` and none may be an internal name.
+pub fn validate_relative_path(rel: &str) -> Result<Vec<&str>, AppError> {
+    let segments: Vec<&str> = rel.split('/').filter(|s| !s.is_empty()).collect();
+    if segments.is_empty() {
+        return Err(AppError::BadRequest("empty relative path".to_string()));
+    }
+
+    for segment in &segments {
+        validate_filename(segment)?;
+        if is_internal_name(segment) {
+            return Err(AppError::BadRequest(format!(
+                "reserved name in path: {}",
+                segment
+            )));
+        }
+    }
+
+    Ok(segments)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -210,6 +274,47 @@ mod tests {
         assert!(matches!(result, Err(AppError::BadRequest(_))));
     }
 
+    #[test]
+    fn test_internal_paths_hidden() {
+        let tmp = setup();
+        fs::create_dir(tmp.path().join(INTERNAL_DIR)).unwrap();
+        std::os::unix::fs::symlink(tmp.path().join(INTERNAL_DIR), tmp.path().join("link"))
+            .unwrap();
+
+        for path in [".monkeyarch", ".monkeyarch/tus/x", "link", "subdir/.monkeyarch-tmp-1"] {
+            assert!(
+                matches!(validate_path(tmp.path(), path), Err(AppError::NotFound(_))),
+                "{} should be hidden",
+                path
+            );
+        }
+        assert!(validate_path(tmp.path(), ".monkeyarchive").is_ok());
+    }
+
+    #[test]
+    fn test_normalize_relative() {
+        assert_eq!(normalize_relative(""), PathBuf::new());
+        assert_eq!(normalize_relative("/music/./rock/"), PathBuf::from("music/rock"));
+        assert_eq!(
+            normalize_relative("music/../photos/private"),
+            PathBuf::from("photos/private")
+        );
+    }
+
+    #[test]
+    fn test_validate_relative_path() {
+        assert_eq!(
+            validate_relative_path("Album/CD1/01.mp3").unwrap(),
+            vec!["Album", "CD1", "01.mp3"]
+        );
+        assert_eq!(validate_relative_path("/a//b.mp3").unwrap(), vec!["a", "b.mp3"]);
+        assert!(validate_relative_path("Album/../../etc/passwd").is_err());
+        assert!(validate_relative_path("Album/./a.mp3").is_err());
+        assert!(validate_relative_path("a\\..\\b.mp3").is_err());
+        assert!(validate_relative_path(".monkeyarch/tus/x").is_err());
+        assert!(validate_relative_path("").is_err());
+    }
+
     #[test]
     fn test_validate_filename() {
         assert!(validate_filename("test.txt").is_ok());
@@ -0,0 +1,241 @@
+//! Disk space: free-space checks and per-directory quotas.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use nix::sys::statvfs::statvfs;
+
+use crate::config::{Config, Quota};
+use crate::error::AppError;
+use crate::security::normalize_relative;
+
+/// Space left for writing into one directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Budget {
+    /// Free space on the filesystem, as seen by unprivileged users.
+    pub free: u64,
+    /// Tightest quota covering the directory: its path and what's left.
+    pub quota: Option<(String, u64)>,
+}
+
+impl Budget {
+    pub fn available(&self) -> u64 {
+        match &self.quota {
+            Some((_, left)) => self.free.min(*left),
+            None => self.free,
+        }
+    }
+
+    pub fn check(&self, needed: u64) -> Result<(), AppError> {
+        if needed <= self.available() {
+            return Ok(());
+        }
+
+        let message = match &self.quota {
+            Some((path, left)) if *left < self.free => {
+                format!("quota for '/{}' exceeded ({} bytes left)", path, left)
+            }
+            _ => format!("not enough free space ({} bytes left)", self.free),
+        };
+        tracing::warn!("Rejected write of {} bytes: {}", needed, message);
+        Err(AppError::InsufficientStorage(message))
+    }
+
+    /// Checks the quota alone, for moves that don't take up new disk space.
+    pub fn check_quota(&self, needed: u64) -> Result<(), AppError> {
+        Budget {
+            free: u64::MAX,
+            quota: self.quota.clone(),
+        }
+        .check(needed)
+    }
+}
+
+/// Quota usage shared by the writes of one request, so each quota's
+/// subtree is walked once rather than once per file. Bytes written are
+/// added with [`Usage::record`] as they land.
+#[derive(Debug, Default)]
+pub struct Usage {
+    quotas: HashMap<String, u64>,
+}
+
+impl Usage {
+    pub fn record(&mut self, config: &Config, dir_rel: &Path, bytes: u64) {
+        for quota in matching(&config.quotas, dir_rel) {
+            if let Some(used) = self.quotas.get_mut(&quota.path) {
+                *used += bytes;
+            }
+        }
+    }
+}
+
+/// Computes the budget for writing into `dir` (canonical) / `dir_rel`.
+/// Only quotas covering the directory cost a walk of their subtree.
+pub async fn budget(config: &Config, dir: &Path, dir_rel: &Path) -> Result<Budget, AppError> {
+    budget_with(config, dir, dir_rel, &mut Usage::default()).await
+}
+
+/// Like [`budget`], reusing the quota usage already walked for `usage`.
+pub async fn budget_with(
+    config: &Config,
+    dir: &Path,
+    dir_rel: &Path,
+    usage: &mut Usage,
+) -> Result<Budget, AppError> {
+    let root = config.root_directory.clone();
+    let dir = dir.to_path_buf();
+    let quotas: Vec<Quota> = matching(&config.quotas, dir_rel).cloned().collect();
+    let unknown: Vec<String> = quotas
+        .iter()
+        .filter(|q| !usage.quotas.contains_key(&q.path))
+        .map(|q| q.path.clone())
+        .collect();
+
+    let (free, walked) = tokio::task::spawn_blocking(move || -> io::Result<_> {
+        let walked = unknown
+            .into_iter()
+            .map(|path| {
+                let used = dir_usage(&root.join(normalize_relative(&path)))?;
+                Ok((path, used))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok((free_space(&dir)?, walked))
+    })
+    .await
+    .map_err(|e| AppError::Internal(format!("space check failed: {}", e)))??;
+    usage.quotas.extend(walked);
+
+    let mut budget = Budget { free, quota: None };
+    for quota in quotas {
+        let left = quota.max_size.saturating_sub(usage.quotas[&quota.path]);
+        if budget.quota.as_ref().is_none_or(|(_, l)| left < *l) {
+            budget.quota = Some((quota.path.trim_matches('/').to_string(), left));
+        }
+    }
+
+    Ok(budget)
+}
+
+/// Whether moving `from_rel` to `to_rel` brings it under a quota it was
+/// not already counted against.
+pub fn enters_quota(quotas: &[Quota], from_rel: &Path, to_rel: &Path) -> bool {
+    matching(quotas, to_rel).any(|q| !from_rel.starts_with(normalize_relative(&q.path)))
+}
+
+fn matching<'a>(quotas: &'a [Quota], dir_rel: &'a Path) -> impl Iterator<Item = &'a Quota> {
+    quotas
+        .iter()
+        .filter(move |q| dir_rel.starts_with(normalize_relative(&q.path)))
+}
+
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    let stat = statvfs(path)?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/// Total size of the files below `dir`. Symlinks are not followed and a
+/// missing directory uses nothing.
+pub fn dir_usage(dir: &Path) -> io::Result<u64> {
+    let mut total = 0;
+    let mut pending: Vec<PathBuf> = vec![dir.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        for entry in entries {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                total += entry.metadata()?.len();
+            }
+        }
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_dir_usage() {
+        let tmp = TempDir::new().unwrap();
+        fs::create_dir_all(tmp.path().join("a/b")).unwrap();
+        fs::write(tmp.path().join("a/one"), [0u8; 10]).unwrap();
+        fs::write(tmp.path().join("a/b/two"), [0u8; 5]).unwrap();
+        std::os::unix::fs::symlink(tmp.path().join("a/one"), tmp.path().join("link")).unwrap();
+
+        assert_eq!(dir_usage(tmp.path()).unwrap(), 15);
+        assert_eq!(dir_usage(&tmp.path().join("a/b")).unwrap(), 5);
+        assert_eq!(dir_usage(&tmp.path().join("missing")).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_matching_quotas() {
+        let quota = |path: &str| Quota {
+            path: path.to_string(),
+            max_size: 1,
+        };
+        let quotas = vec![
+            quota(""),
+            quota("music"),
+            quota("/music/kids/"),
+            quota("photos"),
+        ];
+        let paths = |rel: &str| -> Vec<String> {
+            matching(&quotas, Path::new(rel))
+                .map(|q| q.path.clone())
+                .collect()
+        };
+
+        assert_eq!(paths("music/kids/songs"), vec!["", "music", "/music/kids/"]);
+        assert_eq!(paths("musicals"), vec![""]);
+    }
+
+    #[test]
+    fn test_budget_check() {
+        let budget = Budget {
+            free: 100,
+            quota: Some(("music".into(), 40)),
+        };
+        assert_eq!(budget.available(), 40);
+        assert!(budget.check(40).is_ok());
+        assert!(matches!(
+            budget.check(41),
+            Err(AppError::InsufficientStorage(msg)) if msg.contains("quota")
+        ));
+
+        let budget = Budget {
+            free: 10,
+            quota: Some(("".into(), 40)),
+        };
+        assert!(matches!(
+            budget.check(11),
+            Err(AppError::InsufficientStorage(msg)) if msg.contains("free space")
+        ));
+    }
+
+    #[test]
+    fn test_enters_quota() {
+        let quotas = vec![Quota {
+            path: "music".to_string(),
+            max_size: 1,
+        }];
+        let enters = |from: &str, to: &str| enters_quota(&quotas, Path::new(from), Path::new(to));
+
+        assert!(enters("inbox/song.mp3", "music/song.mp3"));
+        assert!(!enters("music/a/song.mp3", "music/b/song.mp3"));
+        assert!(!enters("music/song.mp3", "inbox/song.mp3"));
+        assert!(!enters("inbox/song.mp3", "photos/song.mp3"));
+    }
+}
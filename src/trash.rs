@@ -0,0 +1,288 @@
+//! Recycle bin.
+//!
+//! Deleted files and folders are moved into `<root>/.monkeyarch/trash` as
+//! `<id>.item`, with `<id>.info` holding the JSON metadata: where the item
+//! came from and when it was deleted. Like the tus staging area it is
+//! inside the root jail but never listed or downloadable.
+//!
+//! Items on another filesystem mounted below the root (a USB stick or SD
+//! card) stay on it, in `<mount>/.monkeyarch/trash`, so deleting them is
+//! still a rename. Their info files are kept with all the others.
+
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+use crate::security::{normalize_relative, INTERNAL_DIR};
+use crate::storage;
+use crate::util;
+
+/// Metadata stored alongside a trashed item.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashInfo {
+    pub id: String,
+    /// Original location, relative to the root.
+    pub path: String,
+    pub is_dir: bool,
+    /// Total size in bytes, for a folder everything in it.
+    pub size: u64,
+    pub deleted: DateTime<Utc>,
+    pub username: String,
+    /// Root-relative mount point whose trash holds the item, when it was
+    /// deleted from another filesystem.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<String>,
+}
+
+pub fn trash_dir(root: &Path) -> PathBuf {
+    root.join(INTERNAL_DIR).join("trash")
+}
+
+/// Path of an item's info file. The id comes from the client, so only ids
+/// we could have generated are accepted.
+pub fn info_path(root: &Path, id: &str) -> Result<PathBuf, AppError> {
+    if id.len() != 32 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(AppError::NotFound(format!("trash item not found: {}", id)));
+    }
+    Ok(trash_dir(root).join(format!("{}.info", id)))
+}
+
+/// Path of the item itself, in the trash of the filesystem it came from.
+pub fn item_path(root: &Path, info: &TrashInfo) -> PathBuf {
+    let dir = match &info.volume {
+        Some(volume) => trash_dir(&root.join(normalize_relative(volume))),
+        None => trash_dir(root),
+    };
+    dir.join(format!("{}.item", info.id))
+}
+
+/// Moves `path` (canonical, `rel` relative to the root) into the trash.
+/// Items on another filesystem below the root go into that filesystem's
+/// trash; where that isn't possible the delete fails rather than copying
+/// the item over.
+pub async fn move_to_trash(
+    root: &Path,
+    path: &Path,
+    rel: &Path,
+    username: &str,
+) -> Result<TrashInfo, AppError> {
+    let meta = tokio::fs::symlink_metadata(path).await?;
+    let size = if meta.is_dir() {
+        let dir = path.to_path_buf();
+        tokio::task::spawn_blocking(move || storage::dir_usage(&dir))
+            .await
+            .map_err(|e| AppError::Internal(format!("trash failed: {}", e)))??
+    } else {
+        meta.len()
+    };
+
+    let not_trashable = |reason: String| {
+        AppError::Conflict(format!(
+            "cannot move '{}' to the trash ({}); delete it with \"permanent\": true instead",
+            rel.display(),
+            reason
+        ))
+    };
+
+    let volume = volume_of(root, path, meta.dev()).await?;
+    if volume.as_deref() == Some(path) {
+        return Err(not_trashable("it is a mount point".to_string()));
+    }
+    let volume_rel = match &volume {
+        Some(volume) => Some(volume.strip_prefix(root).map_err(|_| AppError::Forbidden)?),
+        None => None,
+    };
+
+    let info = TrashInfo {
+        id: util::random_id(),
+        path: rel.to_string_lossy().to_string(),
+        is_dir: meta.is_dir(),
+        size,
+        deleted: Utc::now(),
+        username: username.to_string(),
+        volume: volume_rel.map(|v| v.to_string_lossy().to_string()),
+    };
+
+    let info_path = info_path(root, &info.id)?;
+    let item_path = item_path(root, &info);
+    tokio::fs::create_dir_all(trash_dir(root)).await?;
+    if let Some(dir) = item_path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|e| not_trashable(e.to_string()))?;
+    }
+
+    // Written first: an info file without its item is skipped and expires,
+    // an item without its info could never be restored
+    tokio::fs::write(&info_path, serde_json::to_vec(&info).unwrap()).await?;
+
+    if let Err(e) = tokio::fs::rename(path, &item_path).await {
+        let _ = tokio::fs::remove_file(&info_path).await;
+        return Err(not_trashable(e.to_string()));
+    }
+
+    Ok(info)
+}
+
+/// The top folder below `root` of the filesystem `dev` that `path` is on,
+/// or `None` for the root's own filesystem.
+async fn volume_of(root: &Path, path: &Path, dev: u64) -> io::Result<Option<PathBuf>> {
+    if tokio::fs::metadata(root).await?.dev() == dev {
+        return Ok(None);
+    }
+
+    let mut top = path;
+    for dir in path.ancestors().skip(1) {
+        if dir == root || !dir.starts_with(root) || tokio::fs::metadata(dir).await?.dev() != dev {
+            break;
+        }
+        top = dir;
+    }
+    Ok(Some(top.to_path_buf()))
+}
+
+/// All trashed items, newest first.
+pub async fn list(root: &Path) -> io::Result<Vec<TrashInfo>> {
+    let mut items = Vec::new();
+    let mut dir = match tokio::fs::read_dir(trash_dir(root)).await {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(items),
+        Err(e) => return Err(e),
+    };
+
+    while let Some(entry) = dir.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "info") {
+            continue;
+        }
+        match tokio::fs::read(&path)
+            .await
+            .map(|d| serde_json::from_slice(&d))
+        {
+            Ok(Ok(info)) => items.push(info),
+            _ => tracing::warn!("Skipping unreadable trash info {}", path.display()),
+        }
+    }
+
+    items.sort_by_key(|info: &TrashInfo| std::cmp::Reverse(info.deleted));
+    Ok(items)
+}
+
+/// Loads one item's metadata.
+pub async fn load(root: &Path, id: &str) -> Result<(TrashInfo, PathBuf, PathBuf), AppError> {
+    let not_found = || AppError::NotFound(format!("trash item not found: {}", id));
+
+    let info_path = info_path(root, id)?;
+    let data = tokio::fs::read(&info_path).await.map_err(|_| not_found())?;
+    let info: TrashInfo = serde_json::from_slice(&data)
+        .map_err(|e| AppError::Internal(format!("corrupt trash info {}: {}", id, e)))?;
+    let item_path = item_path(root, &info);
+
+    Ok((info, info_path, item_path))
+}
+
+/// Removes an item for good.
+pub async fn purge(root: &Path, info: &TrashInfo) -> io::Result<()> {
+    let info_path = info_path(root, &info.id).map_err(io::Error::other)?;
+    let item_path = item_path(root, info);
+
+    let removed = if info.is_dir {
+        tokio::fs::remove_dir_all(&item_path).await
+    } else {
+        tokio::fs::remove_file(&item_path).await
+    };
+    match removed {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    tokio::fs::remove_file(&info_path).await
+}
+
+/// Purges items deleted longer than `retention` ago. Returns how many.
+pub async fn expire(root: &Path, retention: Duration) -> io::Result<usize> {
+    let Ok(retention) = chrono::Duration::from_std(retention) else {
+        return Ok(0);
+    };
+    let cutoff = Utc::now() - retention;
+
+    let mut count = 0;
+    for info in list(root).await? {
+        if info.deleted < cutoff {
+            purge(root, &info).await?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_paths() {
+        let root = Path::new("/media");
+        let id = util::random_id();
+        assert_eq!(
+            info_path(root, &id).unwrap(),
+            PathBuf::from(format!("/media/.monkeyarch/trash/{}.info", id))
+        );
+        assert!(info_path(root, "../../etc/passwd").is_err());
+        assert!(info_path(root, &"g".repeat(32)).is_err());
+
+        let mut info = TrashInfo {
+            id: id.clone(),
+            path: "usb/song.mp3".to_string(),
+            is_dir: false,
+            size: 0,
+            deleted: Utc::now(),
+            username: "pi".to_string(),
+            volume: None,
+        };
+        assert_eq!(
+            item_path(root, &info),
+            PathBuf::from(format!("/media/.monkeyarch/trash/{}.item", id))
+        );
+        info.volume = Some("usb".to_string());
+        assert_eq!(
+            item_path(root, &info),
+            PathBuf::from(format!("/media/usb/.monkeyarch/trash/{}.item", id))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trash_list_and_expire() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("album/cd1")).unwrap();
+        fs::write(root.join("album/cd1/01.mp3"), [0u8; 10]).unwrap();
+        fs::write(root.join("song.mp3"), [0u8; 3]).unwrap();
+
+        let album = move_to_trash(root, &root.join("album"), Path::new("album"), "pi")
+            .await
+            .unwrap();
+        let song = move_to_trash(root, &root.join("song.mp3"), Path::new("song.mp3"), "pi")
+            .await
+            .unwrap();
+        assert!(!root.join("album").exists());
+        assert_eq!(album.size, 10);
+        assert!(album.is_dir);
+
+        let items = list(root).await.unwrap();
+        let ids: Vec<&str> = items.iter().map(|i| i.id.as_str()).collect();
+        assert_eq!(ids, vec![song.id.as_str(), album.id.as_str()]);
+
+        assert_eq!(expire(root, Duration::from_secs(3600)).await.unwrap(), 0);
+        assert_eq!(expire(root, Duration::ZERO).await.unwrap(), 2);
+        assert!(list(root).await.unwrap().is_empty());
+        assert_eq!(fs::read_dir(trash_dir(root)).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,238 @@
+//! Staging area for resumable uploads (tus 1.0).
+//!
+//! Each upload is a pair of files in `<root>/.monkeyarch/tus`: `<id>.info`
+//! holds the JSON metadata, `<id>.part` the bytes received so far. The
+//! offset is simply the length of the part file, so nothing else needs to
+//! be kept in sync and uploads survive a restart.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::error::AppError;
+use crate::models::OnConflict;
+use crate::security::INTERNAL_DIR;
+
+pub const TUS_VERSION: &str = "1.0.0";
+pub const TUS_EXTENSIONS: &str = "creation,termination";
+
+/// Metadata stored alongside a partial upload.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadInfo {
+    pub id: String,
+    pub username: String,
+    /// Destination directory, relative to the root.
+    pub dir: String,
+    pub filename: String,
+    pub mime: String,
+    pub length: u64,
+    #[serde(default)]
+    pub conflict: OnConflict,
+}
+
+/// Tracks which uploads are currently receiving a PATCH, so two requests
+/// can't append to the same part file at once.
+#[derive(Default)]
+pub struct TusStore {
+    busy: Mutex<HashSet<String>>,
+}
+
+/// Releases an upload's lock when dropped.
+pub struct UploadLock<'a> {
+    store: &'a TusStore,
+    id: String,
+}
+
+impl Drop for UploadLock<'_> {
+    fn drop(&mut self) {
+        self.store.busy.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl TusStore {
+    pub fn lock(&self, id: &str) -> Result<UploadLock<'_>, AppError> {
+        if !self.busy.lock().unwrap().insert(id.to_string()) {
+            return Err(AppError::Conflict("upload is busy".to_string()));
+        }
+        Ok(UploadLock {
+            store: self,
+            id: id.to_string(),
+        })
+    }
+}
+
+pub fn staging_dir(root: &Path) -> PathBuf {
+    root.join(INTERNAL_DIR).join("tus")
+}
+
+/// Paths of the info and part files. The id comes from the URL, so only
+/// ids we could have generated are accepted.
+pub fn upload_paths(root: &Path, id: &str) -> Result<(PathBuf, PathBuf), AppError> {
+    if id.len() != 32 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(AppError::NotFound(format!("upload not found: {}", id)));
+    }
+    let dir = staging_dir(root);
+    Ok((
+        dir.join(format!("{}.info", id)),
+        dir.join(format!("{}.part", id)),
+    ))
+}
+
+/// Discards uploads whose files haven't changed for `max_idle`, including
+/// halves whose partner is missing after a crash. Uploads receiving a
+/// PATCH are left alone. Returns how many were removed.
+pub async fn expire(root: &Path, store: &TusStore, max_idle: Duration) -> io::Result<usize> {
+    let dir = staging_dir(root);
+    let mut entries = match tokio::fs::read_dir(&dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut ids = HashSet::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some((id, "info" | "part")) = name.rsplit_once('.') {
+            ids.insert(id.to_string());
+        }
+    }
+
+    let mut count = 0;
+    for id in ids {
+        let Ok((info_path, part_path)) = upload_paths(root, &id) else {
+            continue;
+        };
+        let Ok(_lock) = store.lock(&id) else {
+            continue;
+        };
+
+        let mut last_change = None;
+        for path in [&info_path, &part_path] {
+            if let Ok(modified) = tokio::fs::metadata(path).await.and_then(|m| m.modified()) {
+                last_change = last_change.max(Some(modified));
+            }
+        }
+        let idle = last_change
+            .and_then(|t| SystemTime::now().duration_since(t).ok())
+            .unwrap_or_default();
+        if idle < max_idle {
+            continue;
+        }
+
+        for path in [&info_path, &part_path] {
+            match tokio::fs::remove_file(path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Parses `Upload-Metadata`: comma-separated `key base64value` pairs,
+/// where the value may be omitted.
+pub fn parse_metadata(header: &str) -> Result<HashMap<String, String>, AppError> {
+    let invalid = || AppError::BadRequest("invalid Upload-Metadata".to_string());
+    let mut metadata = HashMap::new();
+
+    for pair in header.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let mut parts = pair.splitn(2, ' ');
+        let key = parts.next().unwrap_or_default();
+        let value = match parts.next() {
+            Some(encoded) => {
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(encoded.trim())
+                    .map_err(|_| invalid())?;
+                String::from_utf8(bytes).map_err(|_| invalid())?
+            }
+            None => String::new(),
+        };
+        if key.is_empty() || metadata.insert(key.to_string(), value).is_some() {
+            return Err(invalid());
+        }
+    }
+
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::random_id;
+
+    #[test]
+    fn test_parse_metadata() {
+        let metadata =
+            parse_metadata("filename c29uZy5tcDM=, filetype YXVkaW8vbXBlZw==,overwrite").unwrap();
+        assert_eq!(metadata["filename"], "song.mp3");
+        assert_eq!(metadata["filetype"], "audio/mpeg");
+        assert_eq!(metadata["overwrite"], "");
+
+        assert!(parse_metadata("").unwrap().is_empty());
+        assert!(parse_metadata("filename !!!").is_err());
+        assert!(parse_metadata("a YQ==,a YQ==").is_err());
+    }
+
+    #[test]
+    fn test_upload_paths() {
+        let root = Path::new("/media");
+        let id = random_id();
+        let (info, part) = upload_paths(root, &id).unwrap();
+        assert_eq!(
+            info,
+            root.join(".monkeyarch/tus").join(format!("{}.info", id))
+        );
+        assert_eq!(part.extension().unwrap(), "part");
+
+        assert!(upload_paths(root, "../../etc/passwd").is_err());
+        assert!(upload_paths(root, &"g".repeat(32)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expire() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let root = tmp.path();
+        std::fs::create_dir_all(staging_dir(root)).unwrap();
+        let store = TusStore::default();
+
+        let create = |parts: &[&str]| {
+            let id = random_id();
+            for ext in parts {
+                std::fs::write(staging_dir(root).join(format!("{}.{}", id, ext)), "").unwrap();
+            }
+            id
+        };
+        let idle = create(&["info", "part"]);
+        let busy = create(&["info", "part"]);
+        let orphan = create(&["part"]);
+        let _lock = store.lock(&busy).unwrap();
+
+        assert_eq!(
+            expire(root, &store, Duration::from_secs(60)).await.unwrap(),
+            0
+        );
+
+        assert_eq!(expire(root, &store, Duration::ZERO).await.unwrap(), 2);
+        assert!(!upload_paths(root, &idle).unwrap().0.exists());
+        assert!(!upload_paths(root, &idle).unwrap().1.exists());
+        assert!(!upload_paths(root, &orphan).unwrap().1.exists());
+        assert!(upload_paths(root, &busy).unwrap().0.exists());
+    }
+
+    #[test]
+    fn test_lock() {
+        let store = TusStore::default();
+        let lock = store.lock("a").unwrap();
+        assert!(store.lock("a").is_err());
+        assert!(store.lock("b").is_ok());
+        drop(lock);
+        assert!(store.lock("a").is_ok());
+    }
+}
@@ -0,0 +1,16 @@
+//! Small helpers shared by unrelated modules.
+
+use rand::RngCore;
+
+/// A random 128-bit id as 32 hex digits, for names and tokens clients
+/// must not be able to guess (uploads, trash items, jobs, locks).
+pub fn random_id() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// Lowercase hex, two digits per byte.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -12,6 +12,16 @@ const mkdirDialog = document.getElementById('mkdir-dialog');
 const renameDialog = document.getElementById('rename-dialog');
 const deleteDialog = document.getElementById('delete-dialog');
 
+// Send the user to the login page when the session is missing or expired.
+// With plain Basic Auth the server sends WWW-Authenticate and the browser prompts instead.
+function redirectIfUnauthorized(status, hasChallenge) {
+    if (status === 401 && !hasChallenge) {
+        window.location.href = '/login.html';
+        return true;
+    }
+    return false;
+}
+
 // API helper
 async function api(endpoint, options = {}) {
     const response = await fetch(endpoint, {
@@ -22,6 +32,10 @@ async function api(endpoint, options = {}) {
         },
     });
 
+    if (redirectIfUnauthorized(response.status, response.headers.has('WWW-Authenticate'))) {
+        return new Promise(() => {});
+    }
+
     const data = await response.json();
 
     if (!response.ok) {
@@ -161,11 +175,19 @@ async function listDirectory(path) {
 // Upload files
 async function uploadFiles(files) {
     const formData = new FormData();
+    let isFolder = false;
     for (const file of files) {
+        // Folder uploads recreate the folder structure on the server
+        if (file.webkitRelativePath) {
+            formData.append('relative_path', file.webkitRelativePath);
+            isFolder = true;
+        }
         formData.append('file', file);
     }
 
     const overwrite = document.getElementById('upload-overwrite').checked;
+    // A folder often holds a few files that can't be uploaded; keep the rest
+    const onError = isFolder ? 'continue' : 'stop';
     const progress = document.getElementById('upload-progress');
 
     return new Promise((resolve, reject) => {
@@ -178,12 +200,18 @@ async function uploadFiles(files) {
         });
 
         xhr.onload = () => {
+            if (redirectIfUnauthorized(xhr.status, xhr.getResponseHeader('WWW-Authenticate') !== null)) {
+                return;
+            }
             if (xhr.status === 200) {
                 resolve();
             } else {
                 try {
                     const err = JSON.parse(xhr.responseText);
-                    reject(new Error(err.error || 'Upload failed'));
+                    const failed = (err.files || [])
+                        .filter((f) => f.status === 'failed')
+                        .map((f) => `${f.name}: ${f.error}`);
+                    reject(new Error(failed.length ? failed.join('; ') : (err.error || 'Upload failed')));
                 } catch {
                     reject(new Error('Upload failed'));
                 }
@@ -192,7 +220,7 @@ async function uploadFiles(files) {
 
         xhr.onerror = () => reject(new Error('Network error'));
 
-        xhr.open('POST', `/api/upload?path=${encodeURIComponent(currentPath)}&overwrite=${overwrite}`);
+        xhr.open('POST', `/api/upload?path=${encodeURIComponent(currentPath)}&overwrite=${overwrite}&on_error=${onError}`);
         xhr.send(formData);
     });
 }
@@ -243,14 +271,40 @@ function openDeleteDialog(name, type) {
     deleteDialog.showModal();
 }
 
+// Live updates: re-list when something changes in the current folder
+function parentOf(path) {
+    const i = path.lastIndexOf('/');
+    return i === -1 ? '' : path.slice(0, i);
+}
+
+function watchChanges() {
+    if (!window.EventSource) return;
+    const events = new EventSource('/api/events');
+    let pending = null;
+    const refresh = () => {
+        clearTimeout(pending);
+        pending = setTimeout(() => listDirectory(currentPath), 300);
+    };
+    events.onmessage = (e) => {
+        const change = JSON.parse(e.data);
+        const paths = change.to ? [change.path, change.to] : [change.path];
+        if (paths.some(p => parentOf(p) === currentPath)) {
+            refresh();
+        }
+    };
+    events.addEventListener('resync', refresh);
+}
+
 // Event listeners
 document.addEventListener('DOMContentLoaded', () => {
     // Initial load
     listDirectory('');
+    watchChanges();
 
     // Upload button
     document.getElementById('btn-upload').addEventListener('click', () => {
         document.getElementById('file-input').value = '';
+        document.getElementById('folder-input').value = '';
         document.getElementById('upload-progress').value = 0;
         document.getElementById('upload-overwrite').checked = false;
         uploadDialog.showModal();
@@ -259,7 +313,8 @@ document.addEventListener('DOMContentLoaded', () => {
     // Upload form
     document.getElementById('upload-form').addEventListener('submit', async (e) => {
         e.preventDefault();
-        const files = document.getElementById('file-input').files;
+        const picked = document.getElementById('file-input').files;
+        const files = picked.length > 0 ? picked : document.getElementById('folder-input').files;
         if (files.length === 0) return;
 
         try {
@@ -269,6 +324,8 @@ document.addEventListener('DOMContentLoaded', () => {
             listDirectory(currentPath);
         } catch (err) {
             showToast(err.message, true);
+            // Files before the failing one may have been stored
+            listDirectory(currentPath);
         }
     });
 